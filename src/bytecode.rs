@@ -0,0 +1,1814 @@
+// A compiler from `Node` to bytecode and a stack machine that runs it, a
+// faster alternative to walking the tree with `evaluator::eval`.
+//
+// Variables are resolved while compiling: arguments, `let` bindings and local
+// `define`s are numbered slots of the current frame, variables of enclosing
+// lambdas are copied into a closure when it is created (the point where
+// `evaluator` clones its environment, so both see the same values), and
+// everything else is a slot in the machine's global table. As in
+// `evaluator`, globals are shared, so a lambda sees `define`s made after it
+// was created and can call itself through a global.
+//
+// Special forms are recognised by name unless a variable shadows them.
+// Procedures other than closures are called by `evaluator::apply`; `+`, `car`
+// and the other primitives registered by `rlisp::init` get opcodes of their
+// own unless the program redefines them.
+//
+// A continuation made by `call/cc` is a copy of the machine's stacks, so
+// calling it brings back the local variables as they were, but not globals.
+// `catch` and `call/ec` push a handler, which an error that is a `throw` or
+// an escape makes the machine go back to. `unwind-protect` pushes one for
+// any error, which runs the cleanup and then goes on with the error, and a
+// continuation leaving it runs the cleanup the same way before it jumps.
+// `with-exception-handler` pushes one that is called where an exception is
+// raised, on top of the stacks as they are, and `guard` one that is escaped
+// to like `call/ec`'s.
+//
+// Macros are defined and expanded while compiling, so a `defmacro` takes
+// effect wherever it is, and only sees the globals of `env`.
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+use node::*;
+use env::Env;
+use symbol::Symbol;
+use error::EvalError;
+use evaluator::{self, EvalResult, Step, Catcher};
+use primitives;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(usize),
+    Local(usize),
+    Free(usize),
+    Global(usize),
+    // Pops into a slot, for `let`.
+    SetLocal(usize),
+    // `define` leaves the value on the stack, as it is also its result.
+    DefineLocal(usize),
+    DefineGlobal(usize),
+    // Pops the top value and replaces the one below it unless it is `()`,
+    // which is how `primitives::prim_progn` picks its result.
+    Keep,
+    Jump(usize),
+    JumpIfFalse(usize),
+    // The condition of an `if` without an else clause was false.
+    NoElse,
+    Dup,
+    Pop,
+    Swap,
+    // Pushes whether the top value is in the list constant, for `case`.
+    Memv(usize),
+    Closure(usize),
+    // Pushes the running closure, which is how a named `let` calls itself.
+    Itself,
+    Call(usize),
+    TailCall(usize),
+    Return,
+    Add(usize),
+    Sub(usize),
+    Mul(usize),
+    Div(usize),
+    NumEq(usize),
+    Cons,
+    // Pops a tail and a list and pushes a copy of the list ending in the
+    // tail, for `,@`.
+    Append,
+    Car,
+    Cdr,
+    IsNull,
+    // Pops a tag and handles `throw`s to it by jumping to the address, until
+    // the following `Uncatch`.
+    Catch(usize),
+    Uncatch,
+    // Handles any error by jumping to the cleanup at the address, until the
+    // following `Unprotect`. `Wind` pops the `before` of a `dynamic-wind`
+    // first, for a continuation going back into it to call.
+    Protect(usize),
+    Wind(usize),
+    Unprotect,
+    // Goes on with the error or the continuation the cleanup was run for, if
+    // any.
+    Reraise,
+    // Pops a continuation, and pushes the handler of the next
+    // `dynamic-wind` it is in that the machine isn't.
+    Enter,
+    // Pops a procedure and installs it as an exception handler, until the
+    // following `Uncatch`.
+    Handle,
+    // Handles what is raised by jumping to the address with it, until the
+    // following `Uncatch`.
+    Guard(usize),
+    // Pops what to raise, and pushes the handler to call with it, and a
+    // handler over it until the following `Uncatch`.
+    RaiseContinuable,
+    // Pops what the handler of a `raise` was called with.
+    HandlerReturned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Local(usize),
+    Free(usize),
+    Itself,
+}
+
+// The code of one lambda, or of a whole program.
+#[derive(Debug)]
+pub struct Proto {
+    pub code: Vec<Op>,
+    pub consts: Vec<Node>,
+    pub protos: Vec<Rc<Proto>>,
+    pub arity: usize,
+    // Names of the frame's slots and of the closure's captured variables,
+    // for reporting ones read before they are defined.
+    pub locals: Vec<String>,
+    pub free_names: Vec<String>,
+    pub captures: Vec<Capture>,
+}
+
+pub struct Closure {
+    proto: Rc<Proto>,
+    free: Vec<Option<Node>>,
+}
+
+#[derive(Clone)]
+struct Frame {
+    closure: Rc<Closure>,
+    pc: usize,
+    locals: usize,
+    stack: usize,
+}
+
+// Where `Machine::run` was when `call/cc` was called.
+pub struct Continuation {
+    closure: Rc<Closure>,
+    pc: usize,
+    base: usize,
+    stack_base: usize,
+    stack: Vec<Node>,
+    locals: Vec<Option<Node>>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    pending: Vec<Exit>,
+}
+
+// How the body of an `unwind-protect` whose cleanup is running was left.
+#[derive(Clone)]
+enum Exit {
+    Return,
+    Error(EvalError),
+    // By calling the continuation with the value.
+    Jump(Rc<Continuation>, Node),
+}
+
+// Where to go on with the value of a `throw` or an escape, and how much of
+// the stacks to keep.
+#[derive(Clone)]
+struct Handler {
+    kind: Kind,
+    closure: Rc<Closure>,
+    pc: usize,
+    base: usize,
+    stack_base: usize,
+    stack: usize,
+    locals: usize,
+    frames: usize,
+}
+
+#[derive(Clone)]
+enum Kind {
+    Catch(Catcher),
+    // The cleanup of an `unwind-protect` or a `dynamic-wind`, which every
+    // error goes to, with a number no other has and the `before` of a
+    // `dynamic-wind`.
+    Protect(usize, Option<Node>),
+    // These two are never gone back to. While a handler runs, the ones from
+    // the index up are left out.
+    Handler(Node),
+    Mask(usize),
+}
+
+// Where something raised goes.
+enum Target {
+    Handler(usize, Node),
+    Guard(usize),
+}
+
+pub struct Machine {
+    // What the primitives it calls are given.
+    env: Env<Node>,
+    globals: Vec<Option<Node>>,
+    global_names: Vec<String>,
+    global_index: HashMap<String, usize>,
+    // Globals some compiled program `define`s, whose primitive opcodes
+    // can't be used any more.
+    defined: HashSet<Symbol>,
+    stack: Vec<Node>,
+    locals: Vec<Option<Node>>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    // Why the cleanup of each `unwind-protect` running is.
+    pending: Vec<Exit>,
+    // Whether the error `exec` returned is one a cleanup was run for, which
+    // handlers have already seen.
+    reraised: bool,
+    // What a procedure called by `call/ec` returns to, which drops the
+    // handler of its escape procedure.
+    uncatch: Rc<Closure>,
+    // Does `dynamic-wind`, and calls the `before` of one a continuation goes
+    // back into.
+    wind: Rc<Closure>,
+    enter: Rc<Closure>,
+    // Do `with-exception-handler` and `raise-continuable`, and call the
+    // handler of anything else raised.
+    handle: Rc<Closure>,
+    raise_continuable: Rc<Closure>,
+    raised: Rc<Closure>,
+}
+
+impl Machine {
+    // Globals not defined by a program are looked up in `env`, which should
+    // have been set up by `rlisp::init`.
+    pub fn new(env: Env<Node>) -> Machine {
+        Machine {
+            env: env,
+            globals: Vec::new(),
+            global_names: Vec::new(),
+            global_index: HashMap::new(),
+            defined: HashSet::new(),
+            stack: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            pending: Vec::new(),
+            reraised: false,
+            uncatch: builtin(&[], vec![Op::Uncatch, Op::Return]),
+            wind: builtin(&["before", "thunk", "after"],
+                          vec![Op::Local(0),
+                               Op::Call(0),
+                               Op::Pop,
+                               Op::Local(0),
+                               Op::Wind(8),
+                               Op::Local(1),
+                               Op::Call(0),
+                               Op::Unprotect,
+                               Op::Local(2),
+                               Op::Call(0),
+                               Op::Pop,
+                               Op::Reraise,
+                               Op::Return]),
+            enter: builtin(&["before", "k", "v"],
+                           vec![Op::Local(0),
+                                Op::Call(0),
+                                Op::Pop,
+                                Op::Local(1),
+                                Op::Enter,
+                                Op::Local(1),
+                                Op::Local(2),
+                                Op::TailCall(1)]),
+            handle: builtin(&["handler", "thunk"],
+                            vec![Op::Local(0),
+                                 Op::Handle,
+                                 Op::Local(1),
+                                 Op::Call(0),
+                                 Op::Uncatch,
+                                 Op::Return]),
+            raise_continuable: builtin(&["obj"],
+                                       vec![Op::Local(0),
+                                            Op::RaiseContinuable,
+                                            Op::Call(1),
+                                            Op::Uncatch,
+                                            Op::Return]),
+            raised: builtin(&["handler", "obj"],
+                            vec![Op::Local(0),
+                                 Op::Local(1),
+                                 Op::Call(1),
+                                 Op::Pop,
+                                 Op::Local(1),
+                                 Op::HandlerReturned]),
+        }
+    }
+
+    pub fn eval(&mut self, ast: &Node) -> EvalResult<Node> {
+        let proto = try!(self.compile(ast));
+        self.run(&proto)
+    }
+
+    // A program that defines a special form is rejected as a whole, where
+    // `evaluator` only fails once the `define` runs.
+    pub fn compile(&mut self, ast: &Node) -> EvalResult<Rc<Proto>> {
+        let defined = &mut HashSet::new();
+        collect_defines(ast, defined);
+        if let Some(name) = defined.iter().find(|n| self.is_special(n)) {
+            return Err(EvalError::SpecialFormRebind(name.to_string()));
+        }
+        self.defined.extend(defined.drain());
+        let mut compiler = Compiler {
+            machine: self,
+            funcs: vec![Function::new(0)],
+        };
+        try!(compiler.compile(ast, true));
+        compiler.emit(Op::Return);
+        Ok(Rc::new(compiler.funcs.pop().unwrap().finish()))
+    }
+
+    fn is_special(&self, name: &str) -> bool {
+        !self.defined.contains(&Symbol::from(name)) &&
+        self.env.find(name).as_ref().map_or(false, evaluator::is_special_form)
+    }
+
+    fn is_macro(&self, name: &str) -> bool {
+        !self.defined.contains(&Symbol::from(name)) &&
+        self.env.find(name).as_ref().map_or(false, evaluator::is_macro)
+    }
+
+    fn find_macro(&self, name: &str) -> Option<Prim> {
+        if self.defined.contains(&Symbol::from(name)) {
+            return None;
+        }
+        match self.env.find(name).as_ref() {
+            Some(&Node::Prim(ref mac @ Prim::Macro(..))) => Some(mac.clone()),
+            _ => None,
+        }
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        if let Some(&i) = self.global_index.get(name) {
+            return i;
+        }
+        let i = self.globals.len();
+        self.globals.push(self.env.find(name));
+        self.global_names.push(name.to_string());
+        self.global_index.insert(name.to_string(), i);
+        i
+    }
+
+    pub fn run(&mut self, proto: &Rc<Proto>) -> EvalResult<Node> {
+        self.stack.clear();
+        self.locals.clear();
+        self.frames.clear();
+        self.handlers.clear();
+        self.pending.clear();
+        self.reraised = false;
+        let closure = Rc::new(Closure {
+            proto: proto.clone(),
+            free: Vec::new(),
+        });
+        self.locals.resize(proto.locals.len(), None);
+        let mut ret = self.exec(closure, 0, 0, 0);
+        loop {
+            let mut e = match ret {
+                Err(e) => e,
+                ok => return ok,
+            };
+            // A handler is called before anything is unwound, and only then.
+            let raised = if mem::replace(&mut self.reraised, false) {
+                None
+            } else {
+                evaluator::raised_object(&e)
+            };
+            if let Some(v) = raised {
+                match self.raise_target() {
+                    Some(Target::Handler(at, h)) => {
+                        let mask = self.marker(Kind::Mask(at));
+                        self.handlers.push(mask);
+                        let base = self.locals.len();
+                        self.locals.push(Some(h));
+                        self.locals.push(Some(v));
+                        let stack_base = self.stack.len();
+                        ret = self.exec(self.raised.clone(), 0, base, stack_base);
+                        continue;
+                    }
+                    Some(Target::Guard(id)) => {
+                        e = EvalError::Escape(id, evaluator::guarded(v, false))
+                    }
+                    None => (),
+                }
+            }
+            let mut caught = None;
+            while let Some(h) = self.handlers.pop() {
+                let v = match h.kind {
+                    Kind::Catch(ref c) => c.catches(&e),
+                    Kind::Protect(..) => Some(Node::Nil),
+                    Kind::Handler(_) | Kind::Mask(_) => None,
+                };
+                if let Some(v) = v {
+                    caught = Some((h, v));
+                    break;
+                }
+            }
+            let (h, v) = match caught {
+                Some(c) => c,
+                None => return Err(e),
+            };
+            if let Kind::Protect(..) = h.kind {
+                self.pending.push(Exit::Error(e));
+            }
+            self.stack.truncate(h.stack);
+            self.locals.truncate(h.locals);
+            self.frames.truncate(h.frames);
+            self.stack.push(v);
+            ret = self.exec(h.closure, h.pc, h.base, h.stack_base);
+        }
+    }
+
+    fn exec(&mut self,
+            mut closure: Rc<Closure>,
+            mut pc: usize,
+            mut base: usize,
+            mut stack_base: usize)
+            -> EvalResult<Node> {
+        loop {
+            let op = closure.proto.code[pc];
+            pc += 1;
+            match op {
+                Op::Const(i) => self.stack.push(closure.proto.consts[i].clone()),
+                Op::Local(i) => {
+                    match self.locals[base + i] {
+                        Some(ref v) => self.stack.push(v.clone()),
+                        None => {
+                            return Err(EvalError::UnknowSymbol(closure.proto.locals[i].clone()))
+                        }
+                    }
+                }
+                Op::Free(i) => {
+                    match closure.free[i] {
+                        Some(ref v) => self.stack.push(v.clone()),
+                        None => {
+                            return Err(EvalError::UnknowSymbol(closure.proto.free_names[i]
+                                .clone()))
+                        }
+                    }
+                }
+                Op::Global(i) => {
+                    match self.globals[i] {
+                        Some(ref v) => self.stack.push(v.clone()),
+                        None => return Err(EvalError::UnknowSymbol(self.global_names[i].clone())),
+                    }
+                }
+                Op::SetLocal(i) => self.locals[base + i] = self.stack.pop(),
+                Op::DefineLocal(i) => self.locals[base + i] = self.stack.last().cloned(),
+                Op::DefineGlobal(i) => self.globals[i] = self.stack.last().cloned(),
+                Op::Keep => {
+                    let v = self.stack.pop().unwrap();
+                    if v != Node::Nil {
+                        *self.stack.last_mut().unwrap() = v;
+                    }
+                }
+                Op::Jump(to) => pc = to,
+                Op::JumpIfFalse(to) => {
+                    if let Some(Node::Bool(Bool::False)) = self.stack.pop() {
+                        pc = to;
+                    }
+                }
+                Op::NoElse => return Err(EvalError::WrongTypeArg),
+                Op::Dup => {
+                    let v = self.stack.last().unwrap().clone();
+                    self.stack.push(v);
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Swap => {
+                    let n = self.stack.len();
+                    self.stack.swap(n - 1, n - 2);
+                }
+                Op::Memv(i) => {
+                    let found = {
+                        let key = self.stack.last().unwrap();
+                        let mut datums = &closure.proto.consts[i];
+                        let mut found = false;
+                        while let Node::Cell(ref d, ref rest) = *datums {
+                            if **d == *key {
+                                found = true;
+                                break;
+                            }
+                            datums = rest;
+                        }
+                        found
+                    };
+                    self.stack.push(rbool(found));
+                }
+                Op::Closure(i) => {
+                    let proto = closure.proto.protos[i].clone();
+                    let free = proto.captures
+                        .iter()
+                        .map(|c| match *c {
+                            Capture::Local(s) => self.locals[base + s].clone(),
+                            Capture::Free(s) => closure.free[s].clone(),
+                            Capture::Itself => Some(Node::Prim(Prim::Closure(closure.clone()))),
+                        })
+                        .collect();
+                    let c = Closure {
+                        proto: proto,
+                        free: free,
+                    };
+                    self.stack.push(Node::Prim(Prim::Closure(Rc::new(c))));
+                }
+                Op::Itself => self.stack.push(Node::Prim(Prim::Closure(closure.clone()))),
+                Op::Call(n) | Op::TailCall(n) => {
+                    let at = self.stack.len() - n - 1;
+                    let mut catcher = None;
+                    // A `Prim::Control` like `call/cc` can hand back another
+                    // procedure to call in its place.
+                    let callee = loop {
+                        let fun = self.stack[at].clone();
+                        match fun {
+                            Node::Prim(Prim::Closure(c)) => break Some(c),
+                            Node::Prim(Prim::Control(ref f)) => {
+                                let args = self.pop_list(at + 1);
+                                self.stack.pop();
+                                match try!(f(&mut self.env, &args)) {
+                                    Step::Apply(g, args) => {
+                                        self.stack.push(g);
+                                        self.stack.extend(node_to_vec(args));
+                                    }
+                                    Step::CallCC(g) => {
+                                        let k = Continuation {
+                                            closure: closure.clone(),
+                                            pc: pc,
+                                            base: base,
+                                            stack_base: stack_base,
+                                            stack: self.stack.clone(),
+                                            locals: self.locals.clone(),
+                                            frames: self.frames.clone(),
+                                            handlers: self.handlers.clone(),
+                                            pending: self.pending.clone(),
+                                        };
+                                        self.stack.push(g);
+                                        self.stack.push(Node::Prim(Prim::Resume(Rc::new(k))));
+                                    }
+                                    Step::Wind(before, thunk, after) => {
+                                        let wind = self.wind.clone();
+                                        self.stack.push(Node::Prim(Prim::Closure(wind)));
+                                        self.stack.extend(vec![before, thunk, after]);
+                                    }
+                                    Step::Handle(handler, thunk) => {
+                                        let handle = self.handle.clone();
+                                        self.stack.push(Node::Prim(Prim::Closure(handle)));
+                                        self.stack.extend(vec![handler, thunk]);
+                                    }
+                                    Step::RaiseContinuable(v) => {
+                                        let raise = self.raise_continuable.clone();
+                                        self.stack.push(Node::Prim(Prim::Closure(raise)));
+                                        self.stack.push(v);
+                                    }
+                                    Step::Catch(c, next) => {
+                                        match *next {
+                                            Step::Apply(g @ Node::Prim(Prim::Closure(_)), args) => {
+                                                self.stack.push(g);
+                                                self.stack.extend(node_to_vec(args));
+                                                catcher = Some(c);
+                                            }
+                                            step => {
+                                                let step = Step::Catch(c, Box::new(step));
+                                                let v = try!(evaluator::run(&mut self.env, step));
+                                                self.stack.push(v);
+                                                break None;
+                                            }
+                                        }
+                                    }
+                                    step => {
+                                        let v = try!(evaluator::run(&mut self.env, step));
+                                        self.stack.push(v);
+                                        break None;
+                                    }
+                                }
+                            }
+                            Node::Prim(Prim::Resume(k)) => {
+                                if self.stack.len() != at + 2 {
+                                    return Err(EvalError::InvalidArgNumber);
+                                }
+                                let v = self.stack.pop().unwrap();
+                                self.stack.pop();
+                                let to = self.jump(k, v);
+                                closure = to.0;
+                                pc = to.1;
+                                base = to.2;
+                                stack_base = to.3;
+                                break None;
+                            }
+                            _ => {
+                                try!(self.apply_other(at));
+                                break None;
+                            }
+                        }
+                    };
+                    let callee = match callee {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let n = self.stack.len() - at - 1;
+                    if callee.proto.arity != n {
+                        return Err(EvalError::InvalidArgNumber);
+                    }
+
+                    if let Op::TailCall(_) = op {
+                        // Reuses the frame, so loops written as tail calls run
+                        // in constant space.
+                        self.locals.truncate(base);
+                    } else {
+                        self.frames.push(Frame {
+                            closure: closure,
+                            pc: pc,
+                            locals: base,
+                            stack: stack_base,
+                        });
+                        base = self.locals.len();
+                        stack_base = at;
+                    }
+                    if let Some(c) = catcher {
+                        // The callee returns to `uncatch`, which returns to
+                        // the caller.
+                        self.handlers.push(Handler {
+                            kind: Kind::Catch(c),
+                            closure: self.uncatch.clone(),
+                            pc: 1,
+                            base: base,
+                            stack_base: stack_base,
+                            stack: stack_base,
+                            locals: base,
+                            frames: self.frames.len(),
+                        });
+                        self.frames.push(Frame {
+                            closure: self.uncatch.clone(),
+                            pc: 0,
+                            locals: base,
+                            stack: stack_base,
+                        });
+                    }
+                    self.locals.extend(self.stack.drain(at + 1..).map(Some));
+                    self.locals.resize(base + callee.proto.locals.len(), None);
+                    self.stack.truncate(stack_base);
+                    closure = callee;
+                    pc = 0;
+                }
+                Op::Return => {
+                    let v = self.stack.pop().unwrap();
+                    self.stack.truncate(stack_base);
+                    self.locals.truncate(base);
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            closure = frame.closure;
+                            pc = frame.pc;
+                            base = frame.locals;
+                            stack_base = frame.stack;
+                            self.stack.push(v);
+                        }
+                        None => return Ok(v),
+                    }
+                }
+                Op::Add(n) => {
+                    let v = try!(self.fold_left(n, 0, add, primitives::prim_add));
+                    self.stack.push(v);
+                }
+                Op::Mul(n) => {
+                    let v = try!(self.fold_left(n, 1, mul, primitives::prim_mul));
+                    self.stack.push(v);
+                }
+                Op::Sub(n) => {
+                    let v = try!(self.fold_left(n, 0, sub, primitives::prim_sub));
+                    self.stack.push(v);
+                }
+                Op::Div(n) => {
+                    let v = try!(self.fold_left(n, 1, div, primitives::prim_div));
+                    self.stack.push(v);
+                }
+                Op::NumEq(n) => {
+                    let at = self.stack.len() - n;
+                    let v = if n > 0 && self.stack[at..].iter().all(is_int) {
+                        let ret = self.stack[at..].windows(2).all(|w| w[0] == w[1]);
+                        self.stack.truncate(at);
+                        rbool(ret)
+                    } else {
+                        try!(self.call_primitive(n, primitives::prim_eq))
+                    };
+                    self.stack.push(v);
+                }
+                Op::Cons => {
+                    let cdr = self.stack.pop().unwrap();
+                    let car = self.stack.pop().unwrap();
+                    self.stack.push(rcell(car, cdr));
+                }
+                Op::Append => {
+                    let tail = self.stack.pop().unwrap();
+                    let list = self.stack.pop().unwrap();
+                    self.stack.push(try!(rappend(&list, tail)));
+                }
+                Op::Car => {
+                    let v = try!(rcar(&self.stack.pop().unwrap()));
+                    self.stack.push(v);
+                }
+                Op::Cdr => {
+                    let v = try!(rcdr(&self.stack.pop().unwrap()));
+                    self.stack.push(v);
+                }
+                Op::IsNull => {
+                    let v = self.stack.pop().unwrap() == Node::Nil;
+                    self.stack.push(rbool(v));
+                }
+                Op::Catch(to) => {
+                    let tag = self.stack.pop().unwrap();
+                    self.handlers.push(Handler {
+                        kind: Kind::Catch(Catcher::Tag(tag)),
+                        closure: closure.clone(),
+                        pc: to,
+                        base: base,
+                        stack_base: stack_base,
+                        stack: self.stack.len(),
+                        locals: self.locals.len(),
+                        frames: self.frames.len(),
+                    });
+                }
+                Op::Uncatch => {
+                    self.handlers.pop();
+                }
+                Op::Protect(to) | Op::Wind(to) => {
+                    let before = match op {
+                        Op::Wind(_) => self.stack.pop(),
+                        _ => None,
+                    };
+                    self.handlers.push(Handler {
+                        kind: Kind::Protect(evaluator::escape_id(), before),
+                        closure: closure.clone(),
+                        pc: to,
+                        base: base,
+                        stack_base: stack_base,
+                        stack: self.stack.len(),
+                        locals: self.locals.len(),
+                        frames: self.frames.len(),
+                    });
+                }
+                Op::Unprotect => {
+                    self.handlers.pop();
+                    self.pending.push(Exit::Return);
+                }
+                Op::Reraise => {
+                    match self.pending.pop().unwrap() {
+                        Exit::Return => (),
+                        Exit::Error(e) => {
+                            self.reraised = true;
+                            return Err(e);
+                        }
+                        Exit::Jump(k, v) => {
+                            let to = self.jump(k, v);
+                            closure = to.0;
+                            pc = to.1;
+                            base = to.2;
+                            stack_base = to.3;
+                        }
+                    }
+                }
+                Op::Enter => {
+                    if let Some(Node::Prim(Prim::Resume(k))) = self.stack.pop() {
+                        let (from, to) = (protects(&self.handlers), protects(&k.handlers));
+                        let common = common_protects(&from, &to);
+                        self.handlers.push(k.handlers[to[common].0].clone());
+                    }
+                }
+                Op::Handle => {
+                    let handler = self.stack.pop().unwrap();
+                    let h = self.marker(Kind::Handler(handler));
+                    self.handlers.push(h);
+                }
+                Op::Guard(to) => {
+                    self.handlers.push(Handler {
+                        kind: Kind::Catch(Catcher::Guard(evaluator::escape_id())),
+                        closure: closure.clone(),
+                        pc: to,
+                        base: base,
+                        stack_base: stack_base,
+                        stack: self.stack.len(),
+                        locals: self.locals.len(),
+                        frames: self.frames.len(),
+                    });
+                }
+                Op::RaiseContinuable => {
+                    let v = self.stack.pop().unwrap();
+                    match self.raise_target() {
+                        Some(Target::Handler(at, h)) => {
+                            let mask = self.marker(Kind::Mask(at));
+                            self.handlers.push(mask);
+                            self.stack.push(h);
+                            self.stack.push(v);
+                        }
+                        Some(Target::Guard(id)) => {
+                            return Err(EvalError::Escape(id, evaluator::guarded(v, true)))
+                        }
+                        None => return Err(evaluator::unhandled(v)),
+                    }
+                }
+                Op::HandlerReturned => {
+                    return Err(EvalError::HandlerReturned(self.stack.pop().unwrap()))
+                }
+            }
+        }
+    }
+
+    // Where to go on from `k` with `v`, as `evaluator::jump` does: the
+    // cleanup of the innermost `unwind-protect` or `dynamic-wind` being left,
+    // which goes on with the jump afterwards, then `enter` for each
+    // `dynamic-wind` being gone back into, and then `k`.
+    fn jump(&mut self, k: Rc<Continuation>, v: Node) -> (Rc<Closure>, usize, usize, usize) {
+        let (from, to) = (protects(&self.handlers), protects(&k.handlers));
+        let common = common_protects(&from, &to);
+        if let Some(&(at, _)) = from[common..].last() {
+            let h = self.handlers[at].clone();
+            self.handlers.truncate(at);
+            self.pending.push(Exit::Jump(k, v));
+            self.stack.truncate(h.stack);
+            self.locals.truncate(h.locals);
+            self.frames.truncate(h.frames);
+            self.stack.push(Node::Nil);
+            return (h.closure, h.pc, h.base, h.stack_base);
+        }
+        for &(at, _) in to[common..].iter() {
+            if let Kind::Protect(_, Some(ref before)) = k.handlers[at].kind {
+                let h = &k.handlers[at];
+                self.stack.clone_from(&k.stack);
+                self.stack.truncate(h.stack);
+                self.locals.clone_from(&k.locals);
+                self.locals.truncate(h.locals);
+                self.frames.clone_from(&k.frames);
+                self.frames.truncate(h.frames);
+                self.handlers = k.handlers[..at].to_vec();
+                self.pending.clone_from(&k.pending);
+                let base = self.locals.len();
+                self.locals.push(Some(before.clone()));
+                self.locals.push(Some(Node::Prim(Prim::Resume(k.clone()))));
+                self.locals.push(Some(v));
+                return (self.enter.clone(), 0, base, self.stack.len());
+            }
+        }
+        self.stack.clone_from(&k.stack);
+        self.locals.clone_from(&k.locals);
+        self.frames.clone_from(&k.frames);
+        self.handlers.clone_from(&k.handlers);
+        self.pending.clone_from(&k.pending);
+        self.stack.push(v);
+        (k.closure.clone(), k.pc, k.base, k.stack_base)
+    }
+
+    // The innermost handler that isn't running or `guard`, as
+    // `evaluator::raise` looks for it.
+    fn raise_target(&self) -> Option<Target> {
+        let mut at = self.handlers.len();
+        while at > 0 {
+            at -= 1;
+            match self.handlers[at].kind {
+                Kind::Handler(ref h) => return Some(Target::Handler(at, h.clone())),
+                Kind::Catch(Catcher::Guard(id)) => return Some(Target::Guard(id)),
+                Kind::Mask(n) => at = n,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    // A handler that is only looked at.
+    fn marker(&self, kind: Kind) -> Handler {
+        Handler {
+            kind: kind,
+            closure: self.uncatch.clone(),
+            pc: 0,
+            base: 0,
+            stack_base: 0,
+            stack: self.stack.len(),
+            locals: self.locals.len(),
+            frames: self.frames.len(),
+        }
+    }
+
+    // Anything else is applied by `evaluator`.
+    fn apply_other(&mut self, at: usize) -> EvalResult<()> {
+        let args = self.pop_list(at + 1);
+        let fun = self.stack.pop().unwrap();
+        let v = try!(evaluator::apply(&mut self.env, &fun, &args));
+        self.stack.push(v);
+        Ok(())
+    }
+
+    fn pop_list(&mut self, at: usize) -> Node {
+        self.stack.drain(at..).rev().fold(Node::Nil, |list, v| rcell(v, list))
+    }
+
+    fn call_primitive(&mut self,
+                      n: usize,
+                      f: fn(&mut Env<Node>, &Node) -> EvalResult<Node>)
+                      -> EvalResult<Node> {
+        let at = self.stack.len() - n;
+        let args = self.pop_list(at);
+        f(&mut self.env, &args)
+    }
+
+    // Arithmetic folds from the left like `primitives`, which decides where
+    // an overflow is detected, and with one argument starts from `init`.
+    // Arguments that aren't all integers go to the primitive itself.
+    fn fold_left(&mut self,
+                 n: usize,
+                 init: i32,
+                 op: fn(i32, i32) -> EvalResult<i32>,
+                 f: fn(&mut Env<Node>, &Node) -> EvalResult<Node>)
+                 -> EvalResult<Node> {
+        let at = self.stack.len() - n;
+        if n == 0 || !self.stack[at..].iter().all(is_int) {
+            return self.call_primitive(n, f);
+        }
+        let mut args = self.stack.drain(at..).map(|v| if let Node::Int(v) = v { v } else { 0 });
+        let mut acc = if n == 1 { init } else { args.next().unwrap() };
+        for v in args {
+            acc = try!(op(acc, v));
+        }
+        Ok(Node::Int(acc))
+    }
+}
+
+// Where the handlers of `unwind-protect`s and `dynamic-wind`s are, and
+// their numbers.
+fn protects(handlers: &[Handler]) -> Vec<(usize, usize)> {
+    handlers.iter()
+        .enumerate()
+        .filter_map(|(at, h)| match h.kind {
+            Kind::Protect(id, _) => Some((at, id)),
+            _ => None,
+        })
+        .collect()
+}
+
+// How many of them two lists from `protects` start with alike.
+fn common_protects(a: &[(usize, usize)], b: &[(usize, usize)]) -> usize {
+    a.iter().zip(b.iter()).take_while(|&(a, b)| a.1 == b.1).count()
+}
+
+// A closure the machine calls itself.
+fn builtin(locals: &[&str], code: Vec<Op>) -> Rc<Closure> {
+    Rc::new(Closure {
+        proto: Rc::new(Proto {
+            code: code,
+            consts: Vec::new(),
+            protos: Vec::new(),
+            arity: locals.len(),
+            locals: locals.iter().map(|l| l.to_string()).collect(),
+            free_names: Vec::new(),
+            captures: Vec::new(),
+        }),
+        free: Vec::new(),
+    })
+}
+
+fn is_int(v: &Node) -> bool {
+    if let Node::Int(_) = *v { true } else { false }
+}
+
+fn add(l: i32, r: i32) -> EvalResult<i32> {
+    l.checked_add(r).ok_or(EvalError::Overflow)
+}
+
+fn sub(l: i32, r: i32) -> EvalResult<i32> {
+    l.checked_sub(r).ok_or(EvalError::Overflow)
+}
+
+fn mul(l: i32, r: i32) -> EvalResult<i32> {
+    l.checked_mul(r).ok_or(EvalError::Overflow)
+}
+
+fn div(l: i32, r: i32) -> EvalResult<i32> {
+    if r == 0 {
+        return Err(EvalError::DivisionByZero);
+    }
+    l.checked_div(r).ok_or(EvalError::Overflow)
+}
+
+// Whether anything in `template` is evaluated.
+fn unquotes(template: &Node, depth: usize) -> bool {
+    match quote_form(template) {
+        Some((name, x)) if name.starts_with("unquote") => depth == 0 || unquotes(x, depth - 1),
+        Some(("quasiquote", x)) => unquotes(x, depth + 1),
+        _ => {
+            match *template {
+                Node::Cell(ref car, ref cdr) => unquotes(car, depth) || unquotes(cdr, depth),
+                _ => false,
+            }
+        }
+    }
+}
+
+fn collect_defines(ast: &Node, defined: &mut HashSet<Symbol>) {
+    if let Node::Cell(ref car, ref cdr) = *ast {
+        if let (&Node::Sym(ref f), &Node::Cell(ref name, _)) = (&**car, &**cdr) {
+            if let (true, &Node::Sym(ref name)) = (f == "define", &**name) {
+                defined.insert(*name);
+            }
+        }
+        collect_defines(car, defined);
+        collect_defines(cdr, defined);
+    }
+}
+
+struct Function {
+    code: Vec<Op>,
+    consts: Vec<Node>,
+    protos: Vec<Rc<Proto>>,
+    arity: usize,
+    locals: Vec<String>,
+    free_names: Vec<String>,
+    captures: Vec<Capture>,
+    // Bindings visible in each nested `let`, innermost last. A lambda starts
+    // with one for its parameters; the program starts with none, so its
+    // `define`s are global.
+    blocks: Vec<Vec<(String, usize)>>,
+    // The name of a named `let`, which refers to the closure itself.
+    name: Option<String>,
+}
+
+impl Function {
+    fn new(arity: usize) -> Function {
+        Function {
+            code: Vec::new(),
+            consts: Vec::new(),
+            protos: Vec::new(),
+            arity: arity,
+            locals: Vec::new(),
+            free_names: Vec::new(),
+            captures: Vec::new(),
+            blocks: Vec::new(),
+            name: None,
+        }
+    }
+
+    fn finish(self) -> Proto {
+        Proto {
+            code: self.code,
+            consts: self.consts,
+            protos: self.protos,
+            arity: self.arity,
+            locals: self.locals,
+            free_names: self.free_names,
+            captures: self.captures,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.blocks
+            .iter()
+            .rev()
+            .filter_map(|b| b.iter().rev().find(|&&(ref n, _)| n == name))
+            .map(|&(_, slot)| slot)
+            .next()
+    }
+
+    // Binds `name` to a new slot in the innermost block. A name bound twice
+    // in a block refers to the later binding, as `Env::register` overwrites.
+    fn bind(&mut self, name: &str) -> usize {
+        let slot = self.slot(name);
+        self.blocks.last_mut().unwrap().push((name.to_string(), slot));
+        slot
+    }
+
+    // A slot no variable refers to, named for error messages.
+    fn slot(&mut self, name: &str) -> usize {
+        let slot = self.locals.len();
+        self.locals.push(name.to_string());
+        slot
+    }
+
+    // `define` reuses the slot of a variable already bound in the block.
+    fn define(&mut self, name: &str) -> usize {
+        let existing = self.blocks
+            .last()
+            .and_then(|b| b.iter().rev().find(|&&(ref n, _)| n == name))
+            .map(|&(_, slot)| slot);
+        match existing {
+            Some(slot) => slot,
+            None => self.bind(name),
+        }
+    }
+}
+
+enum Var {
+    Local(usize),
+    Free(usize),
+    Itself,
+    Global(usize),
+}
+
+struct Compiler<'a> {
+    machine: &'a mut Machine,
+    // The lambdas being compiled, innermost last.
+    funcs: Vec<Function>,
+}
+
+fn list_to_vec(mut node: &Node) -> EvalResult<Vec<&Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push(&**car);
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Ok(ret),
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+// Like `primitives::let_bindings`, each binding must be `(name value)`.
+fn let_bindings<'a>(form: &str, node: &'a Node) -> EvalResult<Vec<(&'a str, &'a Node)>> {
+    let invalid = || EvalError::InvalidSyntax(form.to_string());
+    let mut ret = Vec::new();
+    for b in try!(list_to_vec(node).map_err(|_| invalid())) {
+        let parts = try!(list_to_vec(b).map_err(|_| invalid()));
+        match (parts.len(), parts.first().map(|p| sym_to_str(p))) {
+            (2, Some(Ok(name))) => ret.push((name, parts[1])),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(ret)
+}
+
+impl<'a> Compiler<'a> {
+    fn func(&mut self) -> &mut Function {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let f = self.func();
+        f.code.push(op);
+        f.code.len() - 1
+    }
+
+    fn patch(&mut self, at: usize, to: usize) {
+        let f = self.func();
+        f.code[at] = match f.code[at] {
+            Op::Jump(_) => Op::Jump(to),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(to),
+            Op::Catch(_) => Op::Catch(to),
+            Op::Protect(_) => Op::Protect(to),
+            Op::Guard(_) => Op::Guard(to),
+            op => op,
+        };
+    }
+
+    fn here(&mut self) -> usize {
+        self.func().code.len()
+    }
+
+    fn constant(&mut self, v: Node) {
+        let i = self.constant_index(v);
+        self.emit(Op::Const(i));
+    }
+
+    fn constant_index(&mut self, v: Node) -> usize {
+        let f = self.func();
+        f.consts.push(v);
+        f.consts.len() - 1
+    }
+
+    fn is_lexical(&self, name: &str) -> bool {
+        self.funcs
+            .iter()
+            .any(|f| {
+                f.lookup(name).is_some() || f.free_names.iter().any(|n| n == name) ||
+                f.name.as_ref().map_or(false, |n| n == name)
+            })
+    }
+
+    fn resolve(&mut self, name: &str) -> Var {
+        let depth = self.funcs.len() - 1;
+        match self.resolve_in(depth, name) {
+            Some(v) => v,
+            None => Var::Global(self.machine.global(name)),
+        }
+    }
+
+    // Variables of enclosing lambdas are captured by every lambda in between.
+    fn resolve_in(&mut self, depth: usize, name: &str) -> Option<Var> {
+        if let Some(slot) = self.funcs[depth].lookup(name) {
+            return Some(Var::Local(slot));
+        }
+        if let Some(i) = self.funcs[depth].free_names.iter().position(|n| n == name) {
+            return Some(Var::Free(i));
+        }
+        if self.funcs[depth].name.as_ref().map_or(false, |n| n == name) {
+            return Some(Var::Itself);
+        }
+        if depth == 0 {
+            return None;
+        }
+        let capture = match self.resolve_in(depth - 1, name) {
+            Some(Var::Local(slot)) => Capture::Local(slot),
+            Some(Var::Free(i)) => Capture::Free(i),
+            Some(Var::Itself) => Capture::Itself,
+            _ => return None,
+        };
+        let f = &mut self.funcs[depth];
+        f.free_names.push(name.to_string());
+        f.captures.push(capture);
+        Some(Var::Free(f.captures.len() - 1))
+    }
+
+    fn compile(&mut self, ast: &Node, tail: bool) -> EvalResult<()> {
+        match *ast {
+            Node::Int(_) | Node::Bool(_) | Node::Nil => self.constant(ast.clone()),
+            Node::Sym(ref name) => {
+                let op = match self.resolve(name) {
+                    Var::Local(slot) => Op::Local(slot),
+                    Var::Free(i) => Op::Free(i),
+                    Var::Itself => Op::Itself,
+                    Var::Global(_) if self.machine.is_special(name) => {
+                        return Err(EvalError::SpecialFormValue(name.to_string()))
+                    }
+                    Var::Global(_) if self.machine.is_macro(name) => {
+                        return Err(EvalError::MacroValue(name.to_string()))
+                    }
+                    Var::Global(i) => Op::Global(i),
+                };
+                self.emit(op);
+            }
+            Node::Cell(ref car, ref cdr) => {
+                if let Node::Sym(ref name) = **car {
+                    if !self.is_lexical(name) && !self.machine.defined.contains(name) {
+                        if let Some(ret) = self.compile_special(name, cdr, tail) {
+                            return ret;
+                        }
+                        if let Some(mac) = self.machine.find_macro(name) {
+                            let expansion = try!(evaluator::expand_macro(&mac, cdr));
+                            return self.compile(&expansion, tail);
+                        }
+                    }
+                }
+                try!(self.compile(car, false));
+                let args = try!(list_to_vec(cdr).map_err(|_| EvalError::E));
+                for a in args.iter() {
+                    try!(self.compile(a, false));
+                }
+                if tail {
+                    self.emit(Op::TailCall(args.len()));
+                    self.emit(Op::Return);
+                } else {
+                    self.emit(Op::Call(args.len()));
+                }
+            }
+            _ => return Err(EvalError::E),
+        }
+        Ok(())
+    }
+
+    // Returns `None` when `name` is not a special form or a primitive with
+    // an opcode, so the form is compiled as a call.
+    fn compile_special(&mut self, name: &str, rest: &Node, tail: bool) -> Option<EvalResult<()>> {
+        let ret = match name {
+            "quote" => rcar(rest).map(|v| self.constant(v)),
+            "quasiquote" => car_ref(rest).and_then(|t| self.compile_quasiquote(t, 0)),
+            "defmacro" => {
+                let form = rcell(rsym("defmacro"), rest.clone());
+                evaluator::eval(&mut self.machine.env, &form).map(|v| self.constant(v))
+            }
+            "if" => self.compile_if(rest, tail),
+            "define" => self.compile_define(rest),
+            "progn" => self.compile_progn(rest, tail),
+            "lambda" => self.compile_lambda(rest),
+            "let" => self.compile_let(rest, tail),
+            "let*" => self.compile_let_star(rest, tail),
+            "do" => self.compile_do(rest, tail),
+            "while" => self.compile_while(rest),
+            "and" => self.compile_and(rest, tail),
+            "or" => self.compile_or(rest, tail),
+            "when" => self.compile_when(rest, tail, true),
+            "unless" => self.compile_when(rest, tail, false),
+            "cond" => self.compile_cond(rest, tail),
+            "case" => self.compile_case(rest, tail),
+            "catch" => self.compile_catch(rest),
+            "unwind-protect" => self.compile_unwind_protect(rest),
+            "guard" => self.compile_guard(rest, tail),
+            _ => {
+                let args = match list_to_vec(rest) {
+                    Ok(args) => args,
+                    Err(_) => return None,
+                };
+                let op = match (name, args.len()) {
+                    ("+", n) => Op::Add(n),
+                    ("-", n) => Op::Sub(n),
+                    ("*", n) => Op::Mul(n),
+                    ("/", n) => Op::Div(n),
+                    ("=", n) => Op::NumEq(n),
+                    ("cons", 2) => Op::Cons,
+                    ("car", 1) => Op::Car,
+                    ("cdr", 1) => Op::Cdr,
+                    ("null?", 1) => Op::IsNull,
+                    _ => return None,
+                };
+                let mut ret = Ok(());
+                for a in args.iter() {
+                    ret = self.compile(a, false);
+                    if ret.is_err() {
+                        break;
+                    }
+                }
+                ret.map(|_| {
+                    self.emit(op);
+                })
+            }
+        };
+        Some(ret)
+    }
+
+    // The same as `primitives::prim_quasiquote`, with the parts that don't
+    // unquote anything made constants.
+    fn compile_quasiquote(&mut self, template: &Node, depth: usize) -> EvalResult<()> {
+        if !unquotes(template, depth) {
+            self.constant(template.clone());
+            return Ok(());
+        }
+        match quote_form(template) {
+            Some(("unquote", x)) if depth == 0 => return self.compile(x, false),
+            Some(("unquote-splicing", _)) if depth == 0 => return Err(EvalError::WrongTypeArg),
+            Some((name, x)) if name != "quote" => {
+                let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+                self.constant(rsym(name));
+                try!(self.compile_quasiquote(x, depth));
+                self.constant(Node::Nil);
+                self.emit(Op::Cons);
+                self.emit(Op::Cons);
+                return Ok(());
+            }
+            _ => (),
+        }
+        let (car, cdr) = match *template {
+            Node::Cell(ref car, ref cdr) => (car, cdr),
+            _ => unreachable!(),
+        };
+        if let (Some(("unquote-splicing", x)), 0) = (quote_form(car), depth) {
+            try!(self.compile(x, false));
+            try!(self.compile_quasiquote(cdr, depth));
+            self.emit(Op::Append);
+            return Ok(());
+        }
+        try!(self.compile_quasiquote(car, depth));
+        try!(self.compile_quasiquote(cdr, depth));
+        self.emit(Op::Cons);
+        Ok(())
+    }
+
+    fn compile_if(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        try!(self.compile(try!(car_ref(rest)), false));
+        let jump_else = self.emit(Op::JumpIfFalse(0));
+        try!(self.compile(try!(cdr_ref(rest).and_then(car_ref)), tail));
+        let jump_end = self.emit(Op::Jump(0));
+        let else_at = self.here();
+        self.patch(jump_else, else_at);
+        match cdr_ref(rest).and_then(cdr_ref).and_then(car_ref) {
+            Ok(v) => try!(self.compile(v, tail)),
+            Err(_) => {
+                self.emit(Op::NoElse);
+            }
+        }
+        let end = self.here();
+        self.patch(jump_end, end);
+        Ok(())
+    }
+
+    // A call of a closure that can call itself by `name`, so a loop written
+    // as a tail call of it runs in constant space.
+    fn compile_named_let(&mut self, name: &str, rest: &Node, tail: bool) -> EvalResult<()> {
+        let bindings = try!(car_ref(rest)
+            .map_err(|_| EvalError::InvalidSyntax("let".to_string()))
+            .and_then(|b| let_bindings("let", b)));
+        let params: Vec<&str> = bindings.iter().map(|&(n, _)| n).collect();
+        try!(self.compile_function(&params, try!(cdr_ref(rest)), Some(name)));
+        for &(_, value) in bindings.iter() {
+            try!(self.compile(value, false));
+        }
+        if tail {
+            self.emit(Op::TailCall(bindings.len()));
+            self.emit(Op::Return);
+        } else {
+            self.emit(Op::Call(bindings.len()));
+        }
+        Ok(())
+    }
+
+    // Runs in a block of the variables, which are updated in place.
+    fn compile_do(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        let mut names = Vec::new();
+        let mut steps = Vec::new();
+        for spec in try!(car_ref(rest).and_then(list_to_vec)) {
+            let spec = try!(list_to_vec(spec));
+            if spec.len() != 2 && spec.len() != 3 {
+                return Err(EvalError::WrongTypeArg);
+            }
+            names.push(try!(sym_to_str(spec[0])));
+            try!(self.compile(spec[1], false));
+            steps.push(spec.get(2).cloned());
+        }
+        let (test, result) = match *try!(cdr_ref(rest).and_then(car_ref)) {
+            Node::Cell(ref test, ref result) => (test, result),
+            _ => return Err(EvalError::WrongTypeArg),
+        };
+        let body = try!(cdr_ref(rest).and_then(cdr_ref));
+
+        self.func().blocks.push(Vec::new());
+        let slots: Vec<usize> = names.iter().map(|n| self.func().bind(n)).collect();
+        for &slot in slots.iter().rev() {
+            self.emit(Op::SetLocal(slot));
+        }
+        let ret = self.compile_do_loop(&slots, &steps, (test, result), body, tail);
+        self.func().blocks.pop();
+        ret
+    }
+
+    fn compile_do_loop(&mut self,
+                       slots: &[usize],
+                       steps: &[Option<&Node>],
+                       end: (&Node, &Node),
+                       body: &Node,
+                       tail: bool)
+                       -> EvalResult<()> {
+        let top = self.here();
+        try!(self.compile(end.0, false));
+        let jump_body = self.emit(Op::JumpIfFalse(0));
+        try!(self.compile_progn(end.1, tail));
+        let jump_end = self.emit(Op::Jump(0));
+        let body_at = self.here();
+        self.patch(jump_body, body_at);
+        try!(self.compile_progn(body, false));
+        self.emit(Op::Pop);
+        let mut stepped = Vec::new();
+        for (&slot, step) in slots.iter().zip(steps.iter()) {
+            if let Some(step) = *step {
+                try!(self.compile(step, false));
+                stepped.push(slot);
+            }
+        }
+        for &slot in stepped.iter().rev() {
+            self.emit(Op::SetLocal(slot));
+        }
+        self.emit(Op::Jump(top));
+        let end = self.here();
+        self.patch(jump_end, end);
+        Ok(())
+    }
+
+    fn compile_while(&mut self, rest: &Node) -> EvalResult<()> {
+        let top = self.here();
+        try!(self.compile(try!(car_ref(rest)), false));
+        let jump_end = self.emit(Op::JumpIfFalse(0));
+        try!(self.compile_progn(try!(cdr_ref(rest)), false));
+        self.emit(Op::Pop);
+        self.emit(Op::Jump(top));
+        let end = self.here();
+        self.patch(jump_end, end);
+        self.constant(Node::Nil);
+        Ok(())
+    }
+
+    // The body isn't a tail, as the handler has to be dropped after it.
+    fn compile_catch(&mut self, rest: &Node) -> EvalResult<()> {
+        try!(self.compile(try!(car_ref(rest)), false));
+        let catch = self.emit(Op::Catch(0));
+        try!(self.compile_progn(try!(cdr_ref(rest)), false));
+        self.emit(Op::Uncatch);
+        let end = self.here();
+        self.patch(catch, end);
+        Ok(())
+    }
+
+    // The body value is left on the stack while the cleanup runs, or `()`
+    // after an error.
+    fn compile_unwind_protect(&mut self, rest: &Node) -> EvalResult<()> {
+        let protect = self.emit(Op::Protect(0));
+        try!(self.compile(try!(car_ref(rest)), false));
+        self.emit(Op::Unprotect);
+        let cleanup = self.here();
+        self.patch(protect, cleanup);
+        try!(self.compile_progn(try!(cdr_ref(rest)), false));
+        self.emit(Op::Pop);
+        self.emit(Op::Reraise);
+        Ok(())
+    }
+
+    // Like `catch`, with the clauses as the code the handler jumps to, in a
+    // block of the variable. The handler is given what `evaluator::guarded`
+    // makes, and keeps the procedure raising it again in a slot of its own.
+    fn compile_guard(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        let (var, clauses) = match *try!(car_ref(rest)) {
+            Node::Cell(ref var, ref clauses) => (try!(sym_to_str(var)), clauses),
+            _ => return Err(EvalError::WrongTypeArg),
+        };
+        let guard = self.emit(Op::Guard(0));
+        try!(self.compile_progn(try!(cdr_ref(rest)), false));
+        self.emit(Op::Uncatch);
+        let jump_end = self.emit(Op::Jump(0));
+        let handler = self.here();
+        self.patch(guard, handler);
+        self.func().blocks.push(Vec::new());
+        let again = self.func().slot("guard");
+        let slot = self.func().bind(var);
+        self.emit(Op::Dup);
+        self.emit(Op::Cdr);
+        self.emit(Op::SetLocal(again));
+        self.emit(Op::Car);
+        self.emit(Op::SetLocal(slot));
+        let ret = self.compile_clauses(clauses, tail, Some(again));
+        self.func().blocks.pop();
+        try!(ret);
+        let end = self.here();
+        self.patch(jump_end, end);
+        Ok(())
+    }
+
+    fn compile_and(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        let forms = try!(list_to_vec(rest));
+        let last = match forms.split_last() {
+            Some((last, _)) => *last,
+            None => {
+                self.constant(rbool(true));
+                return Ok(());
+            }
+        };
+        let mut jumps = Vec::new();
+        for f in forms[..forms.len() - 1].iter() {
+            try!(self.compile(f, false));
+            jumps.push(self.emit(Op::JumpIfFalse(0)));
+        }
+        try!(self.compile(last, tail));
+        if jumps.is_empty() {
+            return Ok(());
+        }
+        let jump_end = self.emit(Op::Jump(0));
+        let false_at = self.here();
+        for j in jumps {
+            self.patch(j, false_at);
+        }
+        self.constant(rbool(false));
+        let end = self.here();
+        self.patch(jump_end, end);
+        Ok(())
+    }
+
+    fn compile_or(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        let forms = try!(list_to_vec(rest));
+        let last = match forms.split_last() {
+            Some((last, _)) => *last,
+            None => {
+                self.constant(rbool(false));
+                return Ok(());
+            }
+        };
+        let mut jumps = Vec::new();
+        for f in forms[..forms.len() - 1].iter() {
+            try!(self.compile(f, false));
+            self.emit(Op::Dup);
+            let jump_next = self.emit(Op::JumpIfFalse(0));
+            jumps.push(self.emit(Op::Jump(0)));
+            let next = self.here();
+            self.patch(jump_next, next);
+            self.emit(Op::Pop);
+        }
+        try!(self.compile(last, tail));
+        let end = self.here();
+        for j in jumps {
+            self.patch(j, end);
+        }
+        Ok(())
+    }
+
+    // `unless` is `when` with the branches swapped.
+    fn compile_when(&mut self, rest: &Node, tail: bool, when: bool) -> EvalResult<()> {
+        try!(self.compile(try!(car_ref(rest)), false));
+        let jump_else = self.emit(Op::JumpIfFalse(0));
+        if when {
+            try!(self.compile_progn(try!(cdr_ref(rest)), tail));
+        } else {
+            self.constant(Node::Nil);
+        }
+        let jump_end = self.emit(Op::Jump(0));
+        let else_at = self.here();
+        self.patch(jump_else, else_at);
+        if when {
+            self.constant(Node::Nil);
+        } else {
+            try!(self.compile_progn(try!(cdr_ref(rest)), tail));
+        }
+        let end = self.here();
+        self.patch(jump_end, end);
+        Ok(())
+    }
+
+    fn compile_cond(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        self.compile_clauses(rest, tail, None)
+    }
+
+    // The clauses of a `cond`, or of a `guard` that calls the procedure in
+    // `reraise` when none applies.
+    fn compile_clauses(&mut self,
+                       rest: &Node,
+                       tail: bool,
+                       reraise: Option<usize>)
+                       -> EvalResult<()> {
+        let mut jumps = Vec::new();
+        let mut has_else = false;
+        for clause in try!(list_to_vec(rest)) {
+            let (test, body) = match *clause {
+                Node::Cell(ref test, ref body) => (test, body),
+                _ => return Err(EvalError::WrongTypeArg),
+            };
+            if is_sym(test, "else") {
+                self.constant(rbool(true));
+                try!(self.compile_clause_body(body, tail));
+                has_else = true;
+                break;
+            }
+            try!(self.compile(test, false));
+            self.emit(Op::Dup);
+            let jump_next = self.emit(Op::JumpIfFalse(0));
+            try!(self.compile_clause_body(body, tail));
+            jumps.push(self.emit(Op::Jump(0)));
+            let next = self.here();
+            self.patch(jump_next, next);
+            self.emit(Op::Pop);
+        }
+        match (has_else, reraise) {
+            (true, _) => (),
+            (false, Some(slot)) => {
+                self.emit(Op::Local(slot));
+                self.emit(if tail { Op::TailCall(0) } else { Op::Call(0) });
+            }
+            (false, None) => self.constant(Node::Nil),
+        }
+        let end = self.here();
+        for j in jumps {
+            self.patch(j, end);
+        }
+        Ok(())
+    }
+
+    // The key stays on the stack until a clause applies.
+    fn compile_case(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        try!(self.compile(try!(car_ref(rest)), false));
+        let mut jumps = Vec::new();
+        let mut has_else = false;
+        for clause in try!(cdr_ref(rest).and_then(list_to_vec)) {
+            let (datums, body) = match *clause {
+                Node::Cell(ref datums, ref body) => (datums, body),
+                _ => return Err(EvalError::WrongTypeArg),
+            };
+            if is_sym(datums, "else") {
+                try!(self.compile_clause_body(body, tail));
+                has_else = true;
+                break;
+            }
+            try!(list_to_vec(datums));
+            let i = self.constant_index((**datums).clone());
+            self.emit(Op::Memv(i));
+            let jump_next = self.emit(Op::JumpIfFalse(0));
+            try!(self.compile_clause_body(body, tail));
+            jumps.push(self.emit(Op::Jump(0)));
+            let next = self.here();
+            self.patch(jump_next, next);
+        }
+        if !has_else {
+            self.emit(Op::Pop);
+            self.constant(Node::Nil);
+        }
+        let end = self.here();
+        for j in jumps {
+            self.patch(j, end);
+        }
+        Ok(())
+    }
+
+    // Like `primitives::clause_body`, with the value of the test or the key
+    // on the stack.
+    fn compile_clause_body(&mut self, body: &Node, tail: bool) -> EvalResult<()> {
+        match *body {
+            Node::Nil => (),
+            Node::Cell(ref arrow, ref rest) if is_sym(arrow, "=>") => {
+                let receiver = match **rest {
+                    Node::Cell(ref f, ref end) if **end == Node::Nil => f,
+                    _ => return Err(EvalError::WrongTypeArg),
+                };
+                try!(self.compile(receiver, false));
+                self.emit(Op::Swap);
+                if tail {
+                    self.emit(Op::TailCall(1));
+                    self.emit(Op::Return);
+                } else {
+                    self.emit(Op::Call(1));
+                }
+            }
+            _ => {
+                self.emit(Op::Pop);
+                try!(self.compile_progn(body, tail));
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_define(&mut self, rest: &Node) -> EvalResult<()> {
+        let name = match *rest {
+            Node::Cell(ref car, _) => try!(sym_to_str(car).map_err(|_| EvalError::E)),
+            _ => return Err(EvalError::E),
+        };
+        try!(self.compile(try!(cdr_ref(rest).and_then(car_ref)), false));
+        let op = if self.func().blocks.is_empty() {
+            Op::DefineGlobal(self.machine.global(name))
+        } else {
+            Op::DefineLocal(self.func().define(name))
+        };
+        self.emit(op);
+        Ok(())
+    }
+
+    // Only a single form is in tail position, since a later `()` makes
+    // `progn` return an earlier value.
+    fn compile_progn(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        let forms = try!(list_to_vec(rest));
+        if forms.is_empty() {
+            self.constant(Node::Nil);
+            return Ok(());
+        }
+        let tail = tail && forms.len() == 1;
+        try!(self.compile(forms[0], tail));
+        for f in forms[1..].iter() {
+            try!(self.compile(f, false));
+            self.emit(Op::Keep);
+        }
+        Ok(())
+    }
+
+    fn compile_lambda(&mut self, rest: &Node) -> EvalResult<()> {
+        let mut params = Vec::new();
+        for p in try!(list_to_vec(try!(car_ref(rest)))) {
+            params.push(try!(sym_to_str(p)));
+        }
+        self.compile_function(&params, try!(cdr_ref(rest)), None)
+    }
+
+    fn compile_function(&mut self,
+                        params: &[&str],
+                        body: &Node,
+                        name: Option<&str>)
+                        -> EvalResult<()> {
+        let mut f = Function::new(params.len());
+        f.blocks.push(Vec::new());
+        f.name = name.map(|n| n.to_string());
+        self.funcs.push(f);
+        for p in params.iter() {
+            self.func().bind(p);
+        }
+        let ret = self.compile_progn(body, true);
+        self.emit(Op::Return);
+        let proto = Rc::new(self.funcs.pop().unwrap().finish());
+        try!(ret);
+
+        let i = {
+            let f = self.func();
+            f.protos.push(proto);
+            f.protos.len() - 1
+        };
+        self.emit(Op::Closure(i));
+        Ok(())
+    }
+
+    fn compile_let(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        if let Node::Sym(ref name) = *try!(car_ref(rest)) {
+            return self.compile_named_let(name, try!(cdr_ref(rest)), tail);
+        }
+        let bindings = try!(car_ref(rest).and_then(|b| let_bindings("let", b)));
+        let body = try!(cdr_ref(rest));
+        for &(_, value) in bindings.iter() {
+            try!(self.compile(value, false));
+        }
+
+        self.func().blocks.push(Vec::new());
+        let slots: Vec<usize> = bindings.iter().map(|&(n, _)| self.func().bind(n)).collect();
+        for &slot in slots.iter().rev() {
+            self.emit(Op::SetLocal(slot));
+        }
+        let ret = self.compile_progn(body, tail);
+        self.func().blocks.pop();
+        ret
+    }
+
+    fn compile_let_star(&mut self, rest: &Node, tail: bool) -> EvalResult<()> {
+        let bindings = try!(car_ref(rest).and_then(|b| let_bindings("let*", b)));
+        self.func().blocks.push(Vec::new());
+        let ret = self.compile_let_star_body(&bindings, try!(cdr_ref(rest)), tail);
+        self.func().blocks.pop();
+        ret
+    }
+
+    fn compile_let_star_body(&mut self,
+                             bindings: &[(&str, &Node)],
+                             body: &Node,
+                             tail: bool)
+                             -> EvalResult<()> {
+        for &(name, value) in bindings {
+            try!(self.compile(value, false));
+            let slot = self.func().bind(name);
+            self.emit(Op::SetLocal(slot));
+        }
+        self.compile_progn(body, tail)
+    }
+}
@@ -0,0 +1,411 @@
+// Lowers the subset of the language `codegen::VM` compiles into C99, for
+// building compiled programs without LLVM. The output includes "rlisp.h"
+// (`RUNTIME_HEADER`), which holds the whole runtime:
+//
+//   $ cc -std=c99 out.c -o out
+//
+// Lambdas are inlined at every application, as `codegen` does, and each
+// intermediate value gets its own local so evaluation order is explicit.
+
+use std::cell::{Cell, RefCell};
+use node::*;
+use env::{Env, Entry};
+use error::{CompileError, CompileResult};
+
+pub const RUNTIME_HEADER: &'static str = include_str!("rlisp.h");
+
+// Literals that fit in a fixnum on any target can be tagged by the C
+// compiler; bigger ones are checked at runtime.
+const PORTABLE_FIXNUM_BITS: u32 = 30;
+
+#[derive(Clone)]
+enum Value {
+    Var(String),
+    Lambda(Env<Value>, Node, Node),
+}
+
+struct Gen {
+    globals: RefCell<Vec<String>>,
+    body: RefCell<String>,
+    count: Cell<usize>,
+}
+
+fn car(node: &Node) -> CompileResult<&Node> {
+    car_ref(node).map_err(|_| CompileError::WrongTypeArg(node.clone()))
+}
+
+fn cdr(node: &Node) -> CompileResult<&Node> {
+    cdr_ref(node).map_err(|_| CompileError::WrongTypeArg(node.clone()))
+}
+
+fn sym(node: &Node) -> CompileResult<&str> {
+    sym_to_str(node).map_err(|_| CompileError::WrongTypeArg(node.clone()))
+}
+
+fn list_to_vec(mut node: &Node) -> CompileResult<Vec<Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push((**car).clone());
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Ok(ret),
+        _ => Err(CompileError::WrongTypeArg(node.clone())),
+    }
+}
+
+fn var(v: Value, node: &Node) -> CompileResult<String> {
+    match v {
+        Value::Var(name) => Ok(name),
+        Value::Lambda(_, _, _) => Err(CompileError::NotSupported(node.clone())),
+    }
+}
+
+// Lisp names may contain characters C identifiers can't.
+fn mangle(name: &str) -> String {
+    name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect()
+}
+
+// A C string literal holding `s`. Anything but printable ASCII is an octal
+// escape, and so is `?`, which could start a trigraph.
+fn string_literal(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            b'?' => out.push_str("\\?"),
+            0x20...0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn literal(v: i32) -> String {
+    let limit = 1 << (PORTABLE_FIXNUM_BITS - 1);
+    if v >= -limit && v < limit {
+        format!("RL_FIX({})", v)
+    } else {
+        format!("rl_make_fixnum({}LL)", v)
+    }
+}
+
+// Returns a C translation unit whose `main` prints the value of `node`.
+pub fn compile(node: &Node) -> CompileResult<String> {
+    let gen = Gen {
+        globals: RefCell::new(Vec::new()),
+        body: RefCell::new(String::new()),
+        count: Cell::new(0),
+    };
+    let env = &mut Env::new();
+    try!(gen.pre_gen(node, env));
+    let v = try!(gen.codegen(node, env));
+    let ret = try!(var(v, node));
+
+    let mut out = String::from("/* Generated by rlisp. */\n#include \"rlisp.h\"\n\n");
+    for g in gen.globals.borrow().iter() {
+        out.push_str(&format!("static rl_word {} = RL_NIL;\n", g));
+    }
+    if !gen.globals.borrow().is_empty() {
+        out.push('\n');
+    }
+    out.push_str("static rl_word rlisp_main(void)\n{\n");
+    out.push_str(&gen.body.borrow());
+    out.push_str(&format!("    return {};\n}}\n\n", ret));
+    out.push_str("int main(void)\n{\n    rl_print(rlisp_main());\n    return 0;\n}\n");
+    Ok(out)
+}
+
+impl Gen {
+    fn fresh(&self, prefix: &str, name: &str) -> String {
+        self.count.set(self.count.get() + 1);
+        format!("{}{}_{}", prefix, self.count.get(), mangle(name))
+    }
+
+    // Emits `rl_word <fresh> = <expr>;` and returns the new local.
+    fn emit_local(&self, name: &str, expr: &str) -> String {
+        let v = self.fresh("v", name);
+        self.body.borrow_mut().push_str(&format!("    rl_word {} = {};\n", v, expr));
+        v
+    }
+
+    fn emit_temp(&self, expr: &str) -> Value {
+        self.count.set(self.count.get() + 1);
+        let t = format!("t{}", self.count.get());
+        self.body.borrow_mut().push_str(&format!("    rl_word {} = {};\n", t, expr));
+        Value::Var(t)
+    }
+
+    // Every `define` makes a file scope variable, so later forms see it
+    // whatever scope it appears in, like `codegen::VM::pre_gen` does.
+    fn pre_gen(&self, ast: &Node, env: &mut Env<Value>) -> CompileResult<()> {
+        if let Node::Cell(ref car, ref cdr) = *ast {
+            match sym_to_str(car) {
+                Ok("define") => {
+                    let name = try!(sym(try!(self::car(cdr))));
+                    if env.find(name).is_none() {
+                        let g = self.fresh("g", name);
+                        self.globals.borrow_mut().push(g.clone());
+                        env.register(name, Value::Var(g));
+                    }
+                    try!(self.pre_gen(cdr, env));
+                }
+                _ => {
+                    try!(self.pre_gen(car, env));
+                    try!(self.pre_gen(cdr, env));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn codegen(&self, ast: &Node, env: &mut Env<Value>) -> CompileResult<Value> {
+        match *ast {
+            Node::Int(v) => Ok(Value::Var(literal(v))),
+            Node::Nil => Ok(Value::Var("RL_NIL".to_string())),
+            Node::Bool(Bool::True) => Ok(Value::Var("RL_TRUE".to_string())),
+            Node::Bool(Bool::False) => Ok(Value::Var("RL_FALSE".to_string())),
+            Node::Cell(ref car, ref cdr) => {
+                match **car {
+                    Node::Sym(ref n) => self.apply_fun(env, n, cdr),
+                    Node::Cell(_, _) => {
+                        match try!(self.codegen(car, env)) {
+                            Value::Lambda(ref new_env, ref args, ref body) => {
+                                let new_env = &mut new_env.clone().enter();
+                                self.codegen_lambda(new_env, args, body, cdr, env)
+                            }
+                            _ => Err(CompileError::NotSupported(ast.clone())),
+                        }
+                    }
+                    _ => Err(CompileError::WrongTypeArg(ast.clone())),
+                }
+            }
+            // Copied, so a later `define` can't change a value already read.
+            Node::Sym(ref name) => {
+                match env.find(name).as_ref() {
+                    Some(&Value::Var(ref v)) => Ok(self.emit_temp(v)),
+                    Some(&Value::Lambda(_, _, _)) => Err(CompileError::NotSupported(ast.clone())),
+                    None => Err(CompileError::UnknowSymbol(ast.clone())),
+                }
+            }
+            _ => Err(CompileError::NotSupported(ast.clone())),
+        }
+    }
+
+    fn apply_fun(&self, env: &mut Env<Value>, name: &str, rest: &Node) -> CompileResult<Value> {
+        match name {
+            "+" | "-" | "*" | "/" => self.codegen_arith(name, rest, env),
+            "quote" => {
+                match *rest {
+                    Node::Cell(ref v, ref r) if **r == Node::Nil => {
+                        let expr = try!(self.codegen_quote(v));
+                        Ok(self.emit_temp(&expr))
+                    }
+                    _ => Err(CompileError::InvalidArgNumber(rcell(rsym(name), rest.clone()))),
+                }
+            }
+            "cons" | "car" | "cdr" | "null?" => self.codegen_runtime_call(name, rest, env),
+            "define" => {
+                let c = try!(sym(try!(car(rest))));
+                let val_node = try!(car(try!(cdr(rest))));
+                let val = try!(self.codegen(val_node, env));
+                match env.entry(c) {
+                    Entry::Occupied(mut o) => {
+                        match (o.get(), val) {
+                            (_, lambda @ Value::Lambda(_, _, _)) => {
+                                o.insert(lambda.clone());
+                                Ok(lambda)
+                            }
+                            (Value::Var(g), Value::Var(v)) => {
+                                self.body.borrow_mut().push_str(&format!("    {} = {};\n", g, v));
+                                Ok(self.emit_temp(&g))
+                            }
+                            (Value::Lambda(_, _, _), _) => {
+                                Err(CompileError::NotSupported(rcell(rsym(name), rest.clone())))
+                            }
+                        }
+                    }
+                    Entry::Vacant(_) => {
+                        Err(CompileError::NotSupported(rcell(rsym(name), rest.clone())))
+                    }
+                }
+            }
+            "progn" => {
+                let vec = try!(list_to_vec(rest));
+                if vec.is_empty() {
+                    return Err(CompileError::InvalidArgNumber(rcell(rsym(name), rest.clone())));
+                }
+                env.push_local_scope();
+                let mut ret = Ok(Value::Var("RL_NIL".to_string()));
+                for (i, v) in vec.iter().enumerate() {
+                    ret = self.codegen(v, env);
+                    match ret {
+                        Err(_) => break,
+                        // Keeps -Wunused-variable quiet about discarded values.
+                        Ok(Value::Var(ref t)) if i + 1 < vec.len() && t.starts_with('t') => {
+                            self.body.borrow_mut().push_str(&format!("    (void){};\n", t))
+                        }
+                        _ => {}
+                    }
+                }
+                env.pop_local_scope();
+                ret
+            }
+            "lambda" => {
+                env.push_local_scope();
+                let lam = Value::Lambda(env.capture(),
+                                        try!(car(rest)).clone(),
+                                        try!(cdr(rest)).clone());
+                env.pop_local_scope();
+                Ok(lam)
+            }
+            // Each value is compiled after the previous variable is bound, as
+            // `let*` needs.
+            "let" | "let*" => {
+                env.push_local_scope();
+                let ret = self.codegen_let(env, rest);
+                env.pop_local_scope();
+                ret
+            }
+            _ => {
+                match env.find(name).as_ref() {
+                    Some(&Value::Lambda(ref new_env, ref args, ref body)) => {
+                        let new_env = &mut new_env.clone().enter();
+                        self.codegen_lambda(new_env, args, body, rest, &mut env.clone())
+                    }
+                    Some(_) => Err(CompileError::NotSupported(rcell(rsym(name), rest.clone()))),
+                    None => Err(CompileError::UnknowSymbol(rsym(name))),
+                }
+            }
+        }
+    }
+
+    fn bind(&self, env: &mut Env<Value>, name: &str, v: Value) {
+        match v {
+            Value::Lambda(_, _, _) => env.register(name, v),
+            Value::Var(ref expr) => {
+                let local = self.emit_local(name, expr);
+                env.register(name, Value::Var(local));
+            }
+        }
+    }
+
+    fn codegen_let(&self, env: &mut Env<Value>, lst: &Node) -> CompileResult<Value> {
+        let args = try!(list_to_vec(try!(car(lst))));
+        let body = try!(cdr(lst));
+
+        for n in args.iter() {
+            let key = try!(sym(try!(car(n))));
+            let v = try!(self.codegen(try!(car(try!(cdr(n)))), env));
+            self.bind(env, key, v);
+        }
+
+        self.apply_fun(env, "progn", body)
+    }
+
+    fn codegen_lambda(&self,
+                      lambda_env: &mut Env<Value>,
+                      vargs: &Node,
+                      body: &Node,
+                      aargs: &Node,
+                      env: &mut Env<Value>)
+                      -> CompileResult<Value> {
+        let arg_values = try!(self.codegen_list(env, aargs));
+        let names = try!(list_to_vec(vargs));
+        if arg_values.len() != names.len() {
+            return Err(CompileError::InvalidArgNumber(aargs.clone()));
+        }
+
+        for (a, name) in arg_values.into_iter().zip(names.iter()) {
+            self.bind(lambda_env, try!(sym(name)), a);
+        }
+        self.apply_fun(lambda_env, "progn", body)
+    }
+
+    fn codegen_list(&self, env: &mut Env<Value>, n: &Node) -> CompileResult<Vec<Value>> {
+        let mut values = Vec::new();
+        for a in try!(list_to_vec(n)).iter() {
+            values.push(try!(self.codegen(a, env)));
+        }
+        Ok(values)
+    }
+
+    // Folds left like `primitives::do_sub`; unary forms fold from the
+    // identity as `codegen` does.
+    fn codegen_arith(&self,
+                     fname: &str,
+                     rest: &Node,
+                     env: &mut Env<Value>)
+                     -> CompileResult<Value> {
+        let mut args = Vec::new();
+        for a in try!(list_to_vec(rest)).iter() {
+            args.push(try!(var(try!(self.codegen(a, env)), a)));
+        }
+
+        let fun = match fname {
+            "+" => "rl_add",
+            "-" => "rl_sub",
+            "*" => "rl_mul",
+            _ => "rl_div",
+        };
+        let (init, rest_args) = match (fname, args.len()) {
+            ("+", 0) => return Ok(Value::Var(literal(0))),
+            ("*", 0) => return Ok(Value::Var(literal(1))),
+            (_, 0) => return Err(CompileError::InvalidArgNumber(rcell(rsym(fname), rnil()))),
+            ("+", 1) | ("-", 1) => (literal(0), &args[..]),
+            ("*", 1) | ("/", 1) => (literal(1), &args[..]),
+            _ => (args[0].clone(), &args[1..]),
+        };
+
+        let ret = rest_args.iter().fold(init, |lh, rh| {
+            match self.emit_temp(&format!("{}({}, {})", fun, lh, rh)) {
+                Value::Var(t) => t,
+                _ => unreachable!(),
+            }
+        });
+        Ok(Value::Var(ret))
+    }
+
+    fn codegen_runtime_call(&self,
+                            fname: &str,
+                            rest: &Node,
+                            env: &mut Env<Value>)
+                            -> CompileResult<Value> {
+        let (fun, arity) = match fname {
+            "cons" => ("rl_cons", 2),
+            "car" => ("rl_car", 1),
+            "cdr" => ("rl_cdr", 1),
+            _ => ("rl_is_null", 1),
+        };
+        let nodes = try!(list_to_vec(rest));
+        if nodes.len() != arity {
+            return Err(CompileError::InvalidArgNumber(rcell(rsym(fname), rest.clone())));
+        }
+        let mut args = Vec::new();
+        for a in nodes.iter() {
+            args.push(try!(var(try!(self.codegen(a, env)), a)));
+        }
+        Ok(self.emit_temp(&format!("{}({})", fun, args.join(", "))))
+    }
+
+    // Quoted data is rebuilt on every evaluation; nothing can mutate it.
+    fn codegen_quote(&self, node: &Node) -> CompileResult<String> {
+        match *node {
+            Node::Int(v) => Ok(literal(v)),
+            Node::Sym(ref s) => Ok(format!("rl_intern({})", string_literal(&s.to_string()))),
+            Node::Nil => Ok("RL_NIL".to_string()),
+            Node::Bool(Bool::True) => Ok("RL_TRUE".to_string()),
+            Node::Bool(Bool::False) => Ok("RL_FALSE".to_string()),
+            Node::Cell(ref car, ref cdr) => {
+                Ok(format!("rl_cons({}, {})",
+                           try!(self.codegen_quote(car)),
+                           try!(self.codegen_quote(cdr))))
+            }
+            _ => Err(CompileError::NotSupported(node.clone())),
+        }
+    }
+}
@@ -1,15 +1,28 @@
 extern crate llvm_sys as llvm;
-use std::collections::hash_map::Entry;
-use std::ffi::CString;
+use std::cell::RefCell;
+use std::cmp;
+use std::ffi::{CStr, CString};
+use std::mem;
 use std::ptr;
+use std::sync::{Once, ONCE_INIT};
 use self::llvm::prelude::*;
+use self::llvm::analysis::{LLVMVerifyModule, LLVMVerifierFailureAction};
+use self::llvm::execution_engine as ee;
+use self::llvm::target_machine as tm;
+use self::llvm::transforms::{ipo, scalar};
 
 use node::*;
-use env::Env;
+use env::{Env, Entry};
+use error::{CompileError, CompileResult};
+use runtime;
+
+macro_rules! cptr {
+    ($x: expr) => (CString::new($x).unwrap().as_ptr())
+}
 
 #[derive(Clone)]
 pub enum Value {
-    Int(LLVMValueRef),
+    Int(LLVMValueRef), // a tagged word, see `runtime`
     Function(LLVMValueRef), // to fix
     Lambda(Env<Value>, Node, Node), // env, args, body
 }
@@ -32,153 +45,638 @@ impl Value {
     }
 }
 
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+}
+
+impl OptLevel {
+    // Parses the `-O0`..`-O3` command line flags.
+    pub fn from_flag(flag: &str) -> Option<OptLevel> {
+        match flag {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            "-O2" => Some(OptLevel::O2),
+            "-O3" => Some(OptLevel::O3),
+            _ => None,
+        }
+    }
+}
+
+// What to generate code for. An empty `cpu` or `features` means the
+// triple's generic CPU and default features.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Target {
+    pub triple: String,
+    pub cpu: String,
+    pub features: String,
+}
+
+impl Target {
+    pub fn new<S: Into<String>>(triple: S) -> Target {
+        Target {
+            triple: triple.into(),
+            cpu: String::new(),
+            features: String::new(),
+        }
+    }
+
+    pub fn native() -> Target {
+        unsafe {
+            let triple = tm::LLVMGetDefaultTargetTriple();
+            let ret = Target::new(CStr::from_ptr(triple).to_string_lossy().into_owned());
+            llvm::core::LLVMDisposeMessage(triple);
+            ret
+        }
+    }
+
+    // Parses the `--target=`, `--cpu=` and `--features=` command line flags
+    // into `self`, returning whether `flag` was one of them.
+    pub fn parse_flag(&mut self, flag: &str) -> bool {
+        let (name, value) = match flag.find('=') {
+            Some(i) => (&flag[..i], flag[i + 1..].to_string()),
+            None => return false,
+        };
+        match name {
+            "--target" => self.triple = value,
+            "--cpu" => self.cpu = value,
+            "--features" => self.features = value,
+            _ => return false,
+        }
+        true
+    }
+
+    fn create_machine(&self, level: OptLevel) -> CompileResult<tm::LLVMTargetMachineRef> {
+        static INIT: Once = ONCE_INIT;
+        INIT.call_once(|| unsafe {
+            llvm::target::LLVM_InitializeAllTargetInfos();
+            llvm::target::LLVM_InitializeAllTargets();
+            llvm::target::LLVM_InitializeAllTargetMCs();
+            llvm::target::LLVM_InitializeAllAsmPrinters();
+        });
+
+        let level = match level {
+            OptLevel::O0 => tm::LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => tm::LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O2 => tm::LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            OptLevel::O3 => tm::LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        };
+        let mut target = ptr::null_mut();
+        let mut msg = ptr::null_mut();
+        unsafe {
+            let triple = CString::new(self.triple.as_str()).unwrap();
+            if tm::LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut msg) != 0 {
+                let s = CStr::from_ptr(msg).to_string_lossy().into_owned();
+                llvm::core::LLVMDisposeMessage(msg);
+                return Err(CompileError::InvalidTarget(s));
+            }
+            Ok(tm::LLVMCreateTargetMachine(target,
+                                           triple.as_ptr(),
+                                           cptr!(self.cpu.as_str()),
+                                           cptr!(self.features.as_str()),
+                                           level,
+                                           tm::LLVMRelocMode::LLVMRelocPIC,
+                                           tm::LLVMCodeModel::LLVMCodeModelDefault))
+        }
+    }
+}
+
 pub struct VM {
     context: LLVMContextRef,
     builder: LLVMBuilderRef,
     module: LLVMModuleRef,
     int_value_type: LLVMTypeRef,
-    prims: Env<String>,
+    fixnum_type: LLVMTypeRef,
+    opt_level: OptLevel,
+    target: Option<Target>,
+    // What earlier `compile` calls defined, and the functions they added.
+    env: RefCell<Env<Value>>,
+    globals: RefCell<Vec<LLVMValueRef>>,
+    toplevels: RefCell<Vec<LLVMValueRef>>,
 }
 
-macro_rules! cptr {
-    ($x: expr) => (CString::new($x).unwrap().as_ptr())
+fn car(node: &Node) -> CompileResult<&Node> {
+    car_ref(node).map_err(|_| CompileError::WrongTypeArg(node.clone()))
+}
+
+fn cdr(node: &Node) -> CompileResult<&Node> {
+    cdr_ref(node).map_err(|_| CompileError::WrongTypeArg(node.clone()))
+}
+
+fn sym(node: &Node) -> CompileResult<&str> {
+    sym_to_str(node).map_err(|_| CompileError::WrongTypeArg(node.clone()))
+}
+
+fn list_to_vec(mut node: &Node) -> CompileResult<Vec<Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push((**car).clone());
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Ok(ret),
+        _ => Err(CompileError::WrongTypeArg(node.clone())),
+    }
+}
+
+// Lambdas only exist at compile time, so they have no LLVM value to hand out.
+fn value_ref(v: &Value, node: &Node) -> CompileResult<LLVMValueRef> {
+    match *v {
+        Value::Lambda(_, _, _) => Err(CompileError::NotSupported(node.clone())),
+        _ => Ok(v.to_ref()),
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         let context = VM::create_context();
-        let e = &mut Env::new();
-        // alias
-        e.register("+", "prim_add".to_string());
-        e.register("-", "prim_sub".to_string());
-        e.register("*", "prim_mul".to_string());
-        e.register("/", "prim_div".to_string());
-
         VM {
             context: context,
             builder: VM::create_builder_in_context(context),
-            module: VM::create_module_with_name("rlisp"),
-            int_value_type: VM::int_type(context),
-            prims: e.clone(),
+            module: VM::create_module_with_name_in_context("rlisp", context),
+            int_value_type: VM::word_type(context),
+            fixnum_type: VM::int_type(context),
+            opt_level: OptLevel::O0,
+            target: None,
+            env: RefCell::new(Env::new()),
+            globals: RefCell::new(Vec::new()),
+            toplevels: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.opt_level = level;
+    }
+
+    // Sets the module's triple and data layout. Words are as wide as the
+    // target's pointers, and fixnums keep two bits of that for the tag, up
+    // to 32 bits. Must come before anything is compiled.
+    pub fn set_target(&mut self, target: &Target) -> CompileResult<()> {
+        if !self.toplevels.borrow().is_empty() {
+            return Err(CompileError::InvalidTarget("set after compiling".to_string()));
+        }
+        let machine = try!(target.create_machine(self.opt_level));
+        unsafe {
+            let layout = tm::LLVMCreateTargetDataLayout(machine);
+            let bits = llvm::target::LLVMPointerSize(layout) * 8;
+            llvm::core::LLVMSetTarget(self.module, cptr!(target.triple.as_str()));
+            llvm::target::LLVMSetModuleDataLayout(self.module, layout);
+            llvm::target::LLVMDisposeTargetData(layout);
+            tm::LLVMDisposeTargetMachine(machine);
+
+            self.int_value_type = llvm::core::LLVMIntTypeInContext(self.context, bits);
+            self.fixnum_type = llvm::core::LLVMIntTypeInContext(self.context,
+                                                                cmp::min(32, bits - 2));
         }
+        self.target = Some(target.clone());
+        Ok(())
+    }
+
+    // Writes an object file for the module as `run` left it, for the target
+    // given to `set_target` or else the host.
+    pub fn emit_object(&self, path: &str) -> CompileResult<()> {
+        let target = self.target.clone().unwrap_or_else(Target::native);
+        let machine = try!(target.create_machine(self.opt_level));
+        let mut msg = ptr::null_mut();
+        let path = CString::new(path).unwrap();
+        let failed = unsafe {
+            tm::LLVMTargetMachineEmitToFile(machine,
+                                            self.module,
+                                            path.as_ptr() as *mut _,
+                                            tm::LLVMCodeGenFileType::LLVMObjectFile,
+                                            &mut msg)
+        };
+        let ret = if failed != 0 {
+            let s = unsafe { CStr::from_ptr(msg).to_string_lossy().into_owned() };
+            unsafe { llvm::core::LLVMDisposeMessage(msg) };
+            Err(CompileError::InvalidTarget(s))
+        } else {
+            Ok(())
+        };
+        unsafe { tm::LLVMDisposeTargetMachine(machine) };
+        ret
+    }
+
+    // A `VM` is a compilation session: every `compile` adds a function to
+    // the same module, and definitions made by one are visible to the next.
+    // `run` and `jit` write out or execute the module as it stands, with
+    // `rlisp_main` running the compiled top-level forms in order.
+    pub fn run(&self, node: &Node) -> CompileResult<()> {
+        try!(self.compile(node));
+        try!(self.link());
+        self.dump();
+        Ok(())
     }
 
-    pub fn run(&self, node: &Node) {
-        let env = &mut Env::new();
-        self.init(env);
-        self.pre_gen(node, env);
-        self.llvm_ret(self.codegen(node, env).to_ref());
-        self.finalize();
+    // Like `run`, but executes the module in this process instead of
+    // writing it out. Runtime errors in the compiled code abort. Each call
+    // starts from fresh globals, so the top-level forms compiled before
+    // `node` run again, side effects and all, to define them.
+    pub fn jit(&self, node: &Node) -> CompileResult<Node> {
+        try!(self.compile(node));
+        try!(self.link());
+        self.execute()
     }
 
-    fn init(&self, env: &mut Env<Value>) {
-        self.register_symbols(env);
+    // Compiles `node` into a new function taking no arguments and returning
+    // its value. On failure the module and the session are left as they
+    // were.
+    pub fn compile(&self, node: &Node) -> CompileResult<LLVMValueRef> {
+        let last_function = unsafe { llvm::core::LLVMGetLastFunction(self.module) };
+        let last_global = unsafe { llvm::core::LLVMGetLastGlobal(self.module) };
+        let defined = self.globals.borrow().len();
 
-        // create main
-        self.create_fun_and_set_bb("main", self.int_value_type, &mut []);
+        let name = format!("rlisp_toplevel{}", self.toplevels.borrow().len());
+        let fun = self.create_fun_and_set_bb(&name, self.int_value_type, &mut []);
+        let env = &mut self.env.borrow().fork();
+        let ret = self.compile_toplevel(node, env);
+        match ret.and_then(|_| self.verify()) {
+            Ok(_) => {
+                *self.env.borrow_mut() = env.clone();
+                self.toplevels.borrow_mut().push(fun);
+                Ok(fun)
+            }
+            Err(e) => {
+                self.globals.borrow_mut().truncate(defined);
+                self.delete_functions_after(last_function);
+                self.delete_globals_after(last_global);
+                Err(e)
+            }
+        }
     }
 
-    fn pre_gen(&self, ast: &Node, env: &mut Env<Value>) {
+    pub fn find_function(&self, name: &str) -> Option<LLVMValueRef> {
+        let v = unsafe { llvm::core::LLVMGetNamedFunction(self.module, cptr!(name)) };
+        if v.is_null() { None } else { Some(v) }
+    }
+
+    fn compile_toplevel(&self, node: &Node, env: &mut Env<Value>) -> CompileResult<()> {
+        let frame = self.gc_enter();
+        try!(self.pre_gen(node, env));
+        let v = try!(self.codegen(node, env));
+        let v = try!(value_ref(&v, node));
+        self.gc_leave(frame);
+        self.llvm_ret(v);
+        Ok(())
+    }
+
+    fn link(&self) -> CompileResult<()> {
+        self.create_rlisp_main();
+        self.create_main();
+        try!(self.verify());
+        self.optimize();
+        Ok(())
+    }
+
+    fn execute(&self) -> CompileResult<Node> {
+        if let Some(ref t) = self.target {
+            if t.triple != Target::native().triple {
+                return Err(CompileError::ExecutionEngine(format!("cannot run {} code", t.triple)));
+            }
+        }
+
+        static INIT: Once = ONCE_INIT;
+        INIT.call_once(|| unsafe {
+            ee::LLVMLinkInMCJIT();
+            llvm::target::LLVM_InitializeNativeTarget();
+            llvm::target::LLVM_InitializeNativeAsmPrinter();
+        });
+
+        let mut engine = ptr::null_mut();
+        let mut msg = ptr::null_mut();
+        unsafe {
+            let mut options = mem::zeroed();
+            let size = mem::size_of::<ee::LLVMMCJITCompilerOptions>();
+            ee::LLVMInitializeMCJITCompilerOptions(&mut options, size);
+            if ee::LLVMCreateMCJITCompilerForModule(&mut engine,
+                                                    self.module,
+                                                    &mut options,
+                                                    size,
+                                                    &mut msg) != 0 {
+                let s = CStr::from_ptr(msg).to_string_lossy().into_owned();
+                llvm::core::LLVMDisposeMessage(msg);
+                return Err(CompileError::ExecutionEngine(s));
+            }
+
+            for (name, addr) in runtime::functions() {
+                if let Some(f) = self.find_function(name) {
+                    ee::LLVMAddGlobalMapping(engine, f, addr as *mut _);
+                }
+            }
+            let addr = ee::LLVMGetFunctionAddress(engine, cptr!("rlisp_main"));
+            let rlisp_main: extern "C" fn() -> runtime::Word = mem::transmute(addr as usize);
+            let v = runtime::to_node(rlisp_main());
+
+            // Take the module back so it outlives the engine.
+            let mut module = ptr::null_mut();
+            ee::LLVMRemoveModule(engine, self.module, &mut module, &mut msg);
+            ee::LLVMDisposeExecutionEngine(engine);
+            Ok(v)
+        }
+    }
+
+    fn verify(&self) -> CompileResult<()> {
+        let mut msg = ptr::null_mut();
+        let failed = unsafe {
+            LLVMVerifyModule(self.module,
+                             LLVMVerifierFailureAction::LLVMReturnStatusAction,
+                             &mut msg)
+        };
+        let ret = if failed != 0 {
+            let s = unsafe { CStr::from_ptr(msg).to_string_lossy().into_owned() };
+            Err(CompileError::InvalidModule(s))
+        } else {
+            Ok(())
+        };
+        if !msg.is_null() {
+            unsafe { llvm::core::LLVMDisposeMessage(msg) };
+        }
+        ret
+    }
+
+    // mem2reg comes first at every level above -O0 so allocas become SSA
+    // registers before the other passes look at them. Slots registered with
+    // the collector by `allocate_root` escape and stay in memory; the ones
+    // `allocate_variable` leaves unrooted are promoted.
+    fn optimize(&self) {
+        if self.opt_level == OptLevel::O0 {
+            return;
+        }
+
+        unsafe {
+            let pm = llvm::core::LLVMCreatePassManager();
+            scalar::LLVMAddPromoteMemoryToRegisterPass(pm);
+            scalar::LLVMAddInstructionCombiningPass(pm);
+            scalar::LLVMAddCFGSimplificationPass(pm);
+            if self.opt_level >= OptLevel::O2 {
+                ipo::LLVMAddFunctionInliningPass(pm);
+                scalar::LLVMAddReassociatePass(pm);
+                scalar::LLVMAddGVNPass(pm);
+                scalar::LLVMAddCFGSimplificationPass(pm);
+            }
+            if self.opt_level >= OptLevel::O3 {
+                scalar::LLVMAddAggressiveDCEPass(pm);
+                scalar::LLVMAddInstructionCombiningPass(pm);
+                scalar::LLVMAddCFGSimplificationPass(pm);
+            }
+            llvm::core::LLVMRunPassManager(pm, self.module);
+            llvm::core::LLVMDisposePassManager(pm);
+        }
+    }
+
+    // Rebuilt on every link to call all top-level functions so far, which is
+    // the whole program for `run` and what `jit` needs to set up globals. It
+    // roots the globals `define` stores into for as long as they run.
+    fn create_rlisp_main(&self) {
+        self.delete_function("rlisp_main");
+        self.create_fun_and_set_bb("rlisp_main", self.int_value_type, &mut []);
+        let frame = self.gc_enter();
+        for &g in self.globals.borrow().iter() {
+            self.gc_root(g);
+        }
+        let mut v = self.word_value(runtime::NIL);
+        for &f in self.toplevels.borrow().iter() {
+            v = self.build_call(f, &mut [], 0, "v");
+        }
+        self.gc_leave(frame);
+        self.llvm_ret(v);
+    }
+
+    fn delete_function(&self, name: &str) {
+        if let Some(f) = self.find_function(name) {
+            unsafe { llvm::core::LLVMDeleteFunction(f) };
+        }
+    }
+
+    // Functions and globals are appended to the module, so what a failed
+    // `compile` added is everything after what was last before it.
+    fn delete_functions_after(&self, last: LLVMValueRef) {
+        unsafe {
+            let mut f = if last.is_null() {
+                llvm::core::LLVMGetFirstFunction(self.module)
+            } else {
+                llvm::core::LLVMGetNextFunction(last)
+            };
+            while !f.is_null() {
+                let next = llvm::core::LLVMGetNextFunction(f);
+                llvm::core::LLVMDeleteFunction(f);
+                f = next;
+            }
+        }
+    }
+
+    // Quoted cells refer to the ones added before them, so the newest go
+    // first, and uses left in constant expressions are replaced.
+    fn delete_globals_after(&self, last: LLVMValueRef) {
+        unsafe {
+            let mut g = llvm::core::LLVMGetLastGlobal(self.module);
+            while !g.is_null() && g != last {
+                let previous = llvm::core::LLVMGetPreviousGlobal(g);
+                let undef = llvm::core::LLVMGetUndef(llvm::core::LLVMTypeOf(g));
+                llvm::core::LLVMReplaceAllUsesWith(g, undef);
+                llvm::core::LLVMDeleteGlobal(g);
+                g = previous;
+            }
+        }
+    }
+
+    // `main` prints the value computed by `rlisp_main`, so compiled programs
+    // must be linked with the functions in `runtime`.
+    fn create_main(&self) {
+        self.delete_function("main");
+        let int_ty = unsafe { llvm::core::LLVMInt32TypeInContext(self.context) };
+        let word = self.int_value_type;
+        let void = unsafe { llvm::core::LLVMVoidTypeInContext(self.context) };
+        let rlisp_main = self.declare_function("rlisp_main", word, &mut []);
+        let print = self.declare_function("rlisp_print", void, &mut [word]);
+        self.create_fun_and_set_bb("main", int_ty, &mut []);
+        let v = self.build_call(rlisp_main, &mut [], 0, "v");
+        self.build_call(print, &mut [v], 1, "");
+        self.llvm_ret(unsafe { llvm::core::LLVMConstInt(int_ty, 0, 0) });
+    }
+
+    fn pre_gen(&self, ast: &Node, env: &mut Env<Value>) -> CompileResult<()> {
         if let &Node::Cell(ref car, ref cdr) = ast {
             if rnil() == **cdr {
-                self.pre_gen(car, env);
-                return;
+                return self.pre_gen(car, env);
             }
 
             match sym_to_str(car) {
                 Ok(x) => {
                     match x.as_ref() {
-                        "define" => self.prim_define(&(**cdr), env),
-                        _ => self.pre_gen(cdr, env),
+                        "define" => try!(self.prim_define(&(**cdr), env)),
+                        _ => try!(self.pre_gen(cdr, env)),
                     }
                 }
                 Err(_) => {
-                    self.pre_gen(car, env);
-                    self.pre_gen(cdr, env);
+                    try!(self.pre_gen(car, env));
+                    try!(self.pre_gen(cdr, env));
                 }
             }
         }
+        Ok(())
     }
 
-    fn prim_define(&self, body: &Node, env: &mut Env<Value>) {
-        if let Node::Cell(ref ncar, ref ncdr) = *body {
-            let sym_name = sym_to_str(ncar).unwrap();
-            let val = self.codegen(&rcar(ncdr).unwrap().clone(), env);
-            let ptr = match env.entry(sym_name.as_ref()) {
-                Entry::Occupied(o) => o.get().clone(),
-                Entry::Vacant(v) => {
-                    match val {
-                        Value::Lambda(_, _, _) => {
-                            v.insert(val);
-                            return; // do not call store operator of llvm for lambda
-                        }
-                        _ => {
-                            let p = self.allocate_mem(sym_name.as_ref(), self.int_value_type);
-                            v.insert(val.create_from(p)).clone()
-                        }
+    fn prim_define(&self, body: &Node, env: &mut Env<Value>) -> CompileResult<()> {
+        let sym_name = try!(sym(try!(car(body))));
+        let val_node = try!(car(try!(cdr(body))));
+        let val = try!(self.codegen(val_node, env));
+        let ptr = match env.entry(sym_name) {
+            Entry::Occupied(o) => o.get(),
+            Entry::Vacant(v) => {
+                match val {
+                    Value::Lambda(_, _, _) => {
+                        v.insert(val);
+                        return Ok(()); // do not call store operator of llvm for lambda
+                    }
+                    _ => {
+                        let p = self.add_global(sym_name.as_ref());
+                        v.insert(val.create_from(p))
                     }
                 }
-            };
-            self.llmv_store(val.to_ref(), ptr.to_ref());
-        }
+            }
+        };
+        let val = try!(value_ref(&val, val_node));
+        self.llmv_store(val, try!(value_ref(&ptr, body)));
+        Ok(())
     }
 
-    fn register_symbols(&self, env: &mut Env<Value>) {
-        env.register("+", self.prim_arith("prim_add"));
-        env.register("-", self.prim_arith("prim_sub"));
-        env.register("*", self.prim_arith("prim_mul"));
-        env.register("/", self.prim_arith("prim_div"));
+    // The module's IR as it stands.
+    pub fn ir(&self) -> String {
+        unsafe {
+            let s = llvm::core::LLVMPrintModuleToString(self.module);
+            let ir = CStr::from_ptr(s).to_string_lossy().into_owned();
+            llvm::core::LLVMDisposeMessage(s);
+            ir
+        }
     }
 
-    fn finalize(&self) {
+    fn dump(&self) {
         unsafe {
             llvm::core::LLVMDumpModule(self.module);
             llvm::core::LLVMPrintModuleToFile(self.module, cptr!("out.ll"), ptr::null_mut());
-
-            llvm::core::LLVMDisposeBuilder(self.builder);
-            llvm::core::LLVMDisposeModule(self.module);
-            llvm::core::LLVMContextDispose(self.context);
         }
     }
 
-    fn prim_arith(&self, name: &str) -> Value {
-        let ty = self.int_value_type;
-        let arg_types = &mut [ty, ty];
-        let fun = self.create_fun_and_set_bb(name, ty, arg_types);
-        let lh = self.get_param_fun(&fun, 0);
-        let rh = self.get_param_fun(&fun, 1);
-        let v = match name {
-            "prim_add" => self.llvm_add(lh, rh),
-            "prim_sub" => self.llvm_sub(lh, rh),
-            "prim_mul" => self.llvm_mul(lh, rh),
-            "prim_div" => self.llvm_div(lh, rh),
-            _ => {
-                println!("{:?}", name);
-                panic!("not support arith")
-            }
+    // Arithmetic is checked the way `primitives::do_*` does and branches to
+    // a trap block instead of silently wrapping.
+    fn append_trap_block(&self, fun: LLVMValueRef) -> LLVMBasicBlockRef {
+        let current = unsafe { llvm::core::LLVMGetInsertBlock(self.builder) };
+        let bb = self.append_basic_block("trap", fun);
+        self.set_builder_position_at_end(bb);
+        let void = unsafe { llvm::core::LLVMVoidTypeInContext(self.context) };
+        let trap = self.declare_function("llvm.trap", void, &mut []);
+        self.build_call(trap, &mut [], 0, "");
+        unsafe { llvm::core::LLVMBuildUnreachable(self.builder) };
+        self.set_builder_position_at_end(current);
+        bb
+    }
+
+    fn llvm_checked_arith(&self,
+                          intrinsic: &str,
+                          lh: LLVMValueRef,
+                          rh: LLVMValueRef)
+                          -> LLVMValueRef {
+        let ty = self.fixnum_type;
+        let ret_ty = unsafe {
+            let bool_ty = llvm::core::LLVMInt1TypeInContext(self.context);
+            llvm::core::LLVMStructTypeInContext(self.context, [ty, bool_ty].as_mut_ptr(), 2, 0)
         };
-        self.llvm_ret(v);
-        Value::Function(fun)
+        let name = format!("{}.i{}", intrinsic, self.fixnum_bits());
+        let fun = self.declare_function(&name, ret_ty, &mut [ty, ty]);
+        let ret = self.build_call(fun, &mut [lh, rh], 2, "ret");
+        let v = self.llvm_extract_value(ret, 0, "v");
+        let overflow = self.llvm_extract_value(ret, 1, "overflow");
+        self.llvm_trap_if(overflow);
+        v
     }
 
-    fn llvm_ret(&self, ret: LLVMValueRef) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMBuildRet(self.builder, ret) }
+    // Traps on a zero divisor and on `INT_MIN / -1`, the only overflowing case.
+    fn llvm_checked_div(&self, lh: LLVMValueRef, rh: LLVMValueRef) -> LLVMValueRef {
+        let zero = self.llvm_icmp(llvm::LLVMIntPredicate::LLVMIntEQ, rh, self.fixnum_value(0));
+        self.llvm_trap_if(zero);
+
+        let min = self.fixnum_value(self.fixnum_min());
+        let minus_one = self.fixnum_value(-1);
+        let lh_min = self.llvm_icmp(llvm::LLVMIntPredicate::LLVMIntEQ, lh, min);
+        let rh_minus_one = self.llvm_icmp(llvm::LLVMIntPredicate::LLVMIntEQ, rh, minus_one);
+        let overflow = unsafe {
+            llvm::core::LLVMBuildAnd(self.builder, lh_min, rh_minus_one, cptr!("overflow"))
+        };
+        self.llvm_trap_if(overflow);
+
+        self.llvm_div(lh, rh)
+    }
+
+    fn fixnum_bits(&self) -> u32 {
+        unsafe { llvm::core::LLVMGetIntTypeWidth(self.fixnum_type) }
+    }
+
+    fn fixnum_min(&self) -> i32 {
+        i32::min_value() >> (32 - self.fixnum_bits())
+    }
+
+    // Fixnums are shifted up by two bits, so the arithmetic happens on the
+    // untagged value (`fixnum_type`) and the result is tagged again.
+    fn untag_fixnum(&self, v: LLVMValueRef) -> LLVMValueRef {
+        let tag = unsafe {
+            llvm::core::LLVMBuildAnd(self.builder,
+                                     v,
+                                     self.word_value(runtime::TAG_MASK),
+                                     cptr!("tag"))
+        };
+        let not_fixnum = self.llvm_icmp(llvm::LLVMIntPredicate::LLVMIntNE,
+                                        tag,
+                                        self.word_value(runtime::TAG_FIXNUM));
+        self.llvm_trap_if(not_fixnum);
+        unsafe {
+            let v = llvm::core::LLVMBuildAShr(self.builder, v, self.word_value(2), cptr!("v"));
+            llvm::core::LLVMBuildTrunc(self.builder, v, self.fixnum_type, cptr!("v"))
+        }
+    }
+
+    fn tag_fixnum(&self, v: LLVMValueRef) -> LLVMValueRef {
+        unsafe {
+            let v = llvm::core::LLVMBuildSExt(self.builder, v, self.int_value_type, cptr!("v"));
+            let v = llvm::core::LLVMBuildShl(self.builder, v, self.word_value(2), cptr!("v"));
+            llvm::core::LLVMBuildOr(self.builder,
+                                    v,
+                                    self.word_value(runtime::TAG_FIXNUM),
+                                    cptr!("v"))
+        }
+    }
+
+    fn llvm_trap_if(&self, cond: LLVMValueRef) {
+        let fun = unsafe {
+            llvm::core::LLVMGetBasicBlockParent(llvm::core::LLVMGetInsertBlock(self.builder))
+        };
+        let trap = self.append_trap_block(fun);
+        let cont = self.append_basic_block("cont", fun);
+        unsafe { llvm::core::LLVMBuildCondBr(self.builder, cond, trap, cont) };
+        self.set_builder_position_at_end(cont);
     }
 
-    fn llvm_add(&self, lh: LLVMValueRef, rh: LLVMValueRef) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMBuildAdd(self.builder, lh, rh, cptr!("v")) }
+    fn llvm_icmp(&self,
+                 op: llvm::LLVMIntPredicate,
+                 lh: LLVMValueRef,
+                 rh: LLVMValueRef)
+                 -> LLVMValueRef {
+        unsafe { llvm::core::LLVMBuildICmp(self.builder, op, lh, rh, cptr!("cmp")) }
     }
 
-    fn llvm_sub(&self, lh: LLVMValueRef, rh: LLVMValueRef) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMBuildSub(self.builder, lh, rh, cptr!("v")) }
+    fn llvm_extract_value(&self, agg: LLVMValueRef, i: u32, name: &str) -> LLVMValueRef {
+        unsafe { llvm::core::LLVMBuildExtractValue(self.builder, agg, i, cptr!(name)) }
     }
 
-    fn llvm_mul(&self, lh: LLVMValueRef, rh: LLVMValueRef) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMBuildMul(self.builder, lh, rh, cptr!("v")) }
+    fn llvm_ret(&self, ret: LLVMValueRef) -> LLVMValueRef {
+        unsafe { llvm::core::LLVMBuildRet(self.builder, ret) }
     }
 
     fn llvm_div(&self, lh: LLVMValueRef, rh: LLVMValueRef) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMBuildUDiv(self.builder, lh, rh, cptr!("v")) }
+        unsafe { llvm::core::LLVMBuildSDiv(self.builder, lh, rh, cptr!("v")) }
     }
 
     fn get_param_fun(&self, func: &LLVMValueRef, i: u32) -> LLVMValueRef {
@@ -214,130 +712,141 @@ impl VM {
         fun
     }
 
-    fn codegen(&self, ast: &Node, env: &mut Env<Value>) -> Value {
+    fn codegen(&self, ast: &Node, env: &mut Env<Value>) -> CompileResult<Value> {
         match *ast {
-            Node::Int(val) => Value::Int(self.int_value(val as u64)),
+            Node::Int(val) if val < self.fixnum_min() || val > !self.fixnum_min() => {
+                Err(CompileError::NotSupported(ast.clone()))
+            }
+            Node::Int(val) => Ok(Value::Int(self.word_value(runtime::fixnum(val)))),
+            Node::Nil => Ok(Value::Int(self.word_value(runtime::NIL))),
+            Node::Bool(Bool::True) => Ok(Value::Int(self.word_value(runtime::TRUE))),
+            Node::Bool(Bool::False) => Ok(Value::Int(self.word_value(runtime::FALSE))),
             Node::Cell(ref car, ref cdr) => {
                 match **car {
-                    Node::Sym(ref n) => {
-                        //
-                        self.apply_fun(env, n, cdr)
-                    }
+                    Node::Sym(ref n) => self.apply_fun(env, n, cdr),
                     Node::Cell(_, _) => {
-                        let lam = self.codegen(car, env);
-                        match lam {
+                        match try!(self.codegen(car, env)) {
                             Value::Lambda(ref new_env, ref args, ref body) => {
-                                self.codegen_lambda(&mut new_env.clone(),
+                                self.codegen_lambda(&mut new_env.clone().enter(),
                                                     &args,
                                                     &body,
                                                     cdr,
                                                     &mut env.clone())
                             }
-                            _ => unreachable!(),
+                            _ => Err(CompileError::NotSupported(ast.clone())),
                         }
                     }
-                    _ => {
-                        println!("{:?}", car);
-                        panic!("not suport")
-                    }
+                    _ => Err(CompileError::WrongTypeArg(ast.clone())),
                 }
             }
             Node::Sym(ref name) => {
-                let val = env.find(name).unwrap();
-                val.create_from(self.build_load(val.to_ref(), name))
-            }
-            ref a => {
-                println!("{:?}", a);
-                panic!("not support in codegen")
+                match env.find(name).as_ref() {
+                    Some(&Value::Lambda(_, _, _)) |
+                    Some(&Value::Function(_)) => Err(CompileError::NotSupported(ast.clone())),
+                    Some(val) => Ok(val.create_from(self.build_load(val.to_ref(), name))),
+                    None => Err(CompileError::UnknowSymbol(ast.clone())),
+                }
             }
+            _ => Err(CompileError::NotSupported(ast.clone())),
         }
     }
 
-    fn apply_fun(&self, env: &mut Env<Value>, name: &str, rest: &Node) -> Value {
+    fn apply_fun(&self, env: &mut Env<Value>, name: &str, rest: &Node) -> CompileResult<Value> {
         match name {
             "+" | "-" | "*" | "/" => self.codegen_arith(name, rest, env),
+            "quote" => {
+                match *rest {
+                    Node::Cell(ref v, ref r) if **r == Node::Nil => {
+                        Ok(Value::Int(try!(self.codegen_quote(v))))
+                    }
+                    _ => Err(CompileError::InvalidArgNumber(rcell(rsym(name), rest.clone()))),
+                }
+            }
+            "cons" | "car" | "cdr" | "null?" => self.codegen_runtime_call(name, rest, env),
             "define" => {
-                let c = car_ref(rest).and_then(|v| sym_to_str(v)).unwrap();
-                match env.clone().entry(c.as_ref()) {
+                let c = try!(sym(try!(car(rest))));
+                match env.clone().entry(c) {
                     Entry::Occupied(o) => {
                         match o.get() {
-                            &Value::Lambda(_, _, _) => Value::Int(self.int_value(10)), // tmp
+                            Value::Lambda(_, _, _) => {
+                                Ok(Value::Int(self.word_value(runtime::fixnum(10)))) // tmp
+                            }
                             _ => self.codegen(&Node::Sym(c.into()), env),
                         }
                     }
-                    Entry::Vacant(v) => panic!("not supoprt"),
+                    Entry::Vacant(_) => {
+                        Err(CompileError::NotSupported(rcell(rsym(name), rest.clone())))
+                    }
                 }
             }
             "progn" => {
+                let vec = try!(list_to_vec(rest));
+                if vec.is_empty() {
+                    return Err(CompileError::InvalidArgNumber(rcell(rsym(name), rest.clone())));
+                }
                 env.push_local_scope();
-                let mut vec = node_to_vec(rest.clone());
-                let vec2 = vec.split_off(1);
-                let mut ret = self.codegen(&vec[0], env);
-                for v in vec2 {
-                    ret = self.codegen(&v.clone(), env);
+                let mut ret = Ok(Value::Int(self.word_value(runtime::NIL)));
+                for v in vec.iter() {
+                    ret = self.codegen(v, env);
+                    if ret.is_err() {
+                        break;
+                    }
                 }
                 env.pop_local_scope();
                 ret
             }
             "lambda" => {
                 env.push_local_scope();
-                let ca = rcar(rest).unwrap();
-                let cd = rcdr(rest).unwrap();
-                let lam = Value::Lambda(env.clone(), ca, cd);
-                env.push_local_scope();
-                lam
+                let ca = try!(car(rest)).clone();
+                let cd = try!(cdr(rest)).clone();
+                let lam = Value::Lambda(env.capture(), ca, cd);
+                env.pop_local_scope();
+                Ok(lam)
             }
-            "let" => {
+            // Each value is compiled after the previous variable is bound, as
+            // `let*` needs.
+            "let" | "let*" => {
                 env.push_local_scope();
                 let ret = self.codegen_let(env, rest);
-                env.push_local_scope();
+                env.pop_local_scope();
                 ret
             }
             _ => {
-                match env.find(name) {
-                    Some(v) => {
-                        match *v {
-                            Value::Lambda(ref new_env, ref args, ref body) => {
-                                self.codegen_lambda(&mut new_env.clone(),
-                                                    &args,
-                                                    &body,
-                                                    rest,
-                                                    &mut env.clone())
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                    None => {
-                        println!("at apply fun {:?}", name);
-                        panic!("unknow");
+                match env.find(name).as_ref() {
+                    Some(&Value::Lambda(ref new_env, ref args, ref body)) => {
+                        self.codegen_lambda(&mut new_env.clone().enter(),
+                                            &args,
+                                            &body,
+                                            rest,
+                                            &mut env.clone())
                     }
+                    Some(_) => Err(CompileError::NotSupported(rcell(rsym(name), rest.clone()))),
+                    None => Err(CompileError::UnknowSymbol(rsym(name))),
                 }
             }
         }
     }
 
-    fn codegen_let(&self, env: &mut Env<Value>, lst: &Node) -> Value {
-        let args = car_ref(lst).unwrap();
-        let body = cdr_ref(lst).and_then(|v| car_ref(v)).unwrap();
-        let args = node_to_vec(args.clone());
+    fn codegen_let(&self, env: &mut Env<Value>, lst: &Node) -> CompileResult<Value> {
+        let args = try!(list_to_vec(try!(car(lst))));
+        let body = try!(cdr(lst));
 
         for n in args.iter() {
-            let key: &str = car_ref(n).and_then(|n| sym_to_str(n)).unwrap();
-            let val: &Node = cdr_ref(n).and_then(|v| car_ref(v)).unwrap();
-            let v = self.codegen(val, env);
+            let key: &str = try!(sym(try!(car(n))));
+            let val: &Node = try!(car(try!(cdr(n))));
+            let v = try!(self.codegen(val, env));
             match v {
                 Value::Lambda(_, _, _) => {
                     env.register(key, v);
                 }
                 _ => {
-                    let p = self.allocate_mem(key.as_ref(), self.int_value_type);
+                    let p = self.allocate_variable(key, try!(value_ref(&v, val)));
                     env.register(key, v.create_from(p));
-                    self.llmv_store(v.to_ref(), p);
                 }
             }
         }
 
-        self.apply_fun(env, "progn", &rcell(body.clone(), rnil()))
+        self.apply_fun(env, "progn", body)
     }
 
     fn codegen_lambda(&self,
@@ -346,20 +855,22 @@ impl VM {
                       body: &Node,
                       aargs: &Node,
                       env: &mut Env<Value>)
-                      -> Value {
-        let arg_values = self.codegen_list(env, aargs);
-        let vargs = node_to_vec(vargs.clone());
+                      -> CompileResult<Value> {
+        let arg_values = try!(self.codegen_list(env, aargs));
+        let names = try!(list_to_vec(vargs));
+        if arg_values.len() != names.len() {
+            return Err(CompileError::InvalidArgNumber(aargs.clone()));
+        }
 
-        for (a, name) in arg_values.iter().zip(vargs.iter()) {
-            let n = sym_to_str(name).unwrap();
+        for (a, name) in arg_values.iter().zip(names.iter()) {
+            let n = try!(sym(name));
             match a {
                 &Value::Lambda(_, _, _) => {
                     lambda_env.register(n, a.clone());
                 }
                 _ => {
-                    let p = self.allocate_mem(n.as_ref(), self.int_value_type);
+                    let p = self.allocate_variable(n, try!(value_ref(a, name)));
                     lambda_env.register(n, a.create_from(p));
-                    self.llmv_store(a.to_ref(), p);
                 }
             }
         }
@@ -367,40 +878,175 @@ impl VM {
         self.apply_fun(lambda_env, "progn", body)
     }
 
-    fn codegen_list(&self, env: &mut Env<Value>, n: &Node) -> Vec<Value> {
-        let args = node_to_vec(n.clone());
-        args.iter().map(|a| self.codegen(a, env)).collect() // side effect...
+    // Evaluated arguments are parked in rooted slots until all of them are
+    // ready, since evaluating a later one may allocate and collect.
+    fn codegen_list(&self, env: &mut Env<Value>, n: &Node) -> CompileResult<Vec<Value>> {
+        let args = try!(list_to_vec(n));
+        let mut values = Vec::new();
+        for (i, a) in args.iter().enumerate() {
+            match try!(self.codegen(a, env)) {
+                Value::Int(v) if i + 1 < args.len() => {
+                    let p = self.allocate_root("arg");
+                    self.llmv_store(v, p);
+                    values.push((Value::Int(p), true));
+                }
+                v => values.push((v, false)),
+            }
+        }
+        Ok(values.into_iter()
+            .map(|(v, spilled)| if spilled {
+                Value::Int(self.build_load(v.to_ref(), "arg"))
+            } else {
+                v
+            })
+            .collect())
     }
 
-    fn codegen_arith(&self, fname: &str, rest: &Node, env: &mut Env<Value>) -> Value {
-        match rest {
-            &Node::Cell(ref car, ref cdr) => {
-                let lh = self.codegen(car, env); // to fix
-                if **cdr == rnil() {
-                    return lh;
-                }
-                let rh = self.codegen_arith(fname, cdr, env);
-                let v = &fname.into();
-                let name = self.prims.find(fname).unwrap_or(v);
-                let fun = self.find_function(name).unwrap();
-                let args = &mut Vec::new();
-                match (lh, rh) {
-                    (Value::Int(v), Value::Int(v2)) => {
-                        args.push(v);
-                        args.push(v2);
-                    }
-                    _ => panic!("Wrong type of argument"),
+    // Folds left like `primitives::do_sub`, so `(- 10 2 3)` is `(10 - 2) - 3`.
+    fn codegen_arith(&self,
+                     fname: &str,
+                     rest: &Node,
+                     env: &mut Env<Value>)
+                     -> CompileResult<Value> {
+        let mut args = Vec::new();
+        for v in try!(self.codegen_list(env, rest)) {
+            match v {
+                Value::Int(v) => args.push(self.untag_fixnum(v)),
+                _ => return Err(CompileError::WrongTypeArg(rest.clone())),
+            }
+        }
+
+        let (init, rest_args) = match (fname, args.len()) {
+            ("+", 0) => return Ok(Value::Int(self.word_value(runtime::fixnum(0)))),
+            ("*", 0) => return Ok(Value::Int(self.word_value(runtime::fixnum(1)))),
+            (_, 0) => return Err(CompileError::InvalidArgNumber(rcell(rsym(fname), rnil()))),
+            ("-", 1) => (self.fixnum_value(0), &args[..]),
+            ("/", 1) => (self.fixnum_value(1), &args[..]),
+            _ => (args[0], &args[1..]),
+        };
+
+        let ret = rest_args.iter().fold(init, |lh, &rh| {
+            match fname {
+                "+" => self.llvm_checked_arith("llvm.sadd.with.overflow", lh, rh),
+                "-" => self.llvm_checked_arith("llvm.ssub.with.overflow", lh, rh),
+                "*" => self.llvm_checked_arith("llvm.smul.with.overflow", lh, rh),
+                _ => self.llvm_checked_div(lh, rh),
+            }
+        });
+        Ok(Value::Int(self.tag_fixnum(ret)))
+    }
+
+    fn codegen_runtime_call(&self,
+                            fname: &str,
+                            rest: &Node,
+                            env: &mut Env<Value>)
+                            -> CompileResult<Value> {
+        let word = self.int_value_type;
+        let (runtime_name, arity) = match fname {
+            "cons" => ("rlisp_cons", 2),
+            "car" => ("rlisp_car", 1),
+            "cdr" => ("rlisp_cdr", 1),
+            _ => ("rlisp_is_null", 1),
+        };
+
+        let mut args = Vec::new();
+        for v in try!(self.codegen_list(env, rest)) {
+            match v {
+                Value::Int(v) => args.push(v),
+                _ => return Err(CompileError::WrongTypeArg(rest.clone())),
+            }
+        }
+        if args.len() != arity {
+            return Err(CompileError::InvalidArgNumber(rcell(rsym(fname), rest.clone())));
+        }
+
+        let types = &mut vec![word; arity];
+        let fun = self.declare_function(runtime_name, word, types);
+        Ok(Value::Int(self.build_call(fun, &mut args, arity as u32, "v")))
+    }
+
+    // Quoted data is laid out as constant globals, so it costs nothing at
+    // runtime and `runtime::rlisp_car` can read it like any other cell.
+    fn codegen_quote(&self, node: &Node) -> CompileResult<LLVMValueRef> {
+        match *node {
+            Node::Int(v) => Ok(self.word_value(runtime::fixnum(v))),
+            Node::Nil => Ok(self.word_value(runtime::NIL)),
+            Node::Bool(Bool::True) => Ok(self.word_value(runtime::TRUE)),
+            Node::Bool(Bool::False) => Ok(self.word_value(runtime::FALSE)),
+            Node::Sym(ref name) => Ok(self.symbol_value(name)),
+            Node::Cell(ref car, ref cdr) => {
+                let fields = &mut [try!(self.codegen_quote(car)), try!(self.codegen_quote(cdr))];
+                unsafe {
+                    let cell = llvm::core::LLVMConstStructInContext(self.context,
+                                                                    fields.as_mut_ptr(),
+                                                                    2,
+                                                                    0);
+                    let global = self.add_constant_global("quote", cell, 8);
+                    Ok(llvm::core::LLVMConstPtrToInt(global, self.int_value_type))
                 }
-                Value::Int(self.build_call(fun, args, 2, "v"))
             }
-            _ => panic!("siran"),
+            Node::Prim(_) | Node::Local(_, _) | Node::Global(_) | Node::Condition(_) => {
+                Err(CompileError::NotSupported(node.clone()))
+            }
         }
     }
 
-    fn find_function(&self, name: &str) -> Option<LLVMValueRef> {
-        let v = unsafe { llvm::core::LLVMGetNamedFunction(self.module, cptr!(name)) };
-        let is_null = unsafe { llvm::core::LLVMIsNull(v) > 0 };
-        if is_null { None } else { Some(v) }
+    // Each symbol name is emitted once per module, which interns it: equal
+    // symbols are equal words.
+    fn symbol_value(&self, name: &str) -> LLVMValueRef {
+        let global_name = format!("symbol.{}", name);
+        unsafe {
+            let mut global = llvm::core::LLVMGetNamedGlobal(self.module,
+                                                            cptr!(global_name.as_str()));
+            if global.is_null() {
+                let s = llvm::core::LLVMConstStringInContext(self.context,
+                                                             cptr!(name),
+                                                             name.len() as u32,
+                                                             0);
+                global = self.add_constant_global(&global_name, s, 4);
+            }
+            // `add` rather than `or` keeps the constant relocatable; the two
+            // are the same here because the name is 4 byte aligned.
+            let ptr = llvm::core::LLVMConstPtrToInt(global, self.int_value_type);
+            llvm::core::LLVMConstAdd(ptr, self.word_value(runtime::TAG_SYMBOL))
+        }
+    }
+
+    fn add_constant_global(&self, name: &str, init: LLVMValueRef, align: u32) -> LLVMValueRef {
+        unsafe {
+            let global = llvm::core::LLVMAddGlobal(self.module,
+                                                   llvm::core::LLVMTypeOf(init),
+                                                   cptr!(name));
+            llvm::core::LLVMSetInitializer(global, init);
+            llvm::core::LLVMSetGlobalConstant(global, 1);
+            llvm::core::LLVMSetLinkage(global, llvm::LLVMLinkage::LLVMPrivateLinkage);
+            llvm::core::LLVMSetAlignment(global, align);
+            global
+        }
+    }
+
+    // Top-level definitions live in globals so later top-level functions
+    // can see them.
+    fn add_global(&self, name: &str) -> LLVMValueRef {
+        unsafe {
+            let global = llvm::core::LLVMAddGlobal(self.module,
+                                                   self.int_value_type,
+                                                   cptr!(format!("define.{}", name)));
+            llvm::core::LLVMSetInitializer(global, self.word_value(runtime::NIL));
+            llvm::core::LLVMSetLinkage(global, llvm::LLVMLinkage::LLVMInternalLinkage);
+            self.globals.borrow_mut().push(global);
+            global
+        }
+    }
+
+    fn declare_function(&self,
+                        name: &str,
+                        ret_ty: LLVMTypeRef,
+                        arg_types: &mut [LLVMTypeRef])
+                        -> LLVMValueRef {
+        self.find_function(name).unwrap_or_else(|| {
+            self.add_function(name, self.get_function_type(ret_ty, arg_types))
+        })
     }
 
     fn build_call(&self,
@@ -418,12 +1064,72 @@ impl VM {
         }
     }
 
-    fn int_value(&self, val: u64) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMConstInt(self.int_value_type, val, 0) }
+    fn fixnum_value(&self, val: i32) -> LLVMValueRef {
+        unsafe { llvm::core::LLVMConstInt(self.fixnum_type, val as u64, 0) }
     }
 
+    fn word_value(&self, val: runtime::Word) -> LLVMValueRef {
+        unsafe { llvm::core::LLVMConstInt(self.int_value_type, val as u64, 0) }
+    }
+
+    // Allocas go at the start of the entry block whatever block the builder
+    // is in, since mem2reg only promotes the ones there.
     fn allocate_mem(&self, name: &str, typ: LLVMTypeRef) -> LLVMValueRef {
-        unsafe { llvm::core::LLVMBuildAlloca(self.builder, typ, cptr!(name)) }
+        unsafe {
+            let current = llvm::core::LLVMGetInsertBlock(self.builder);
+            let fun = llvm::core::LLVMGetBasicBlockParent(current);
+            let entry = llvm::core::LLVMGetEntryBasicBlock(fun);
+            let builder = llvm::core::LLVMCreateBuilderInContext(self.context);
+            let first = llvm::core::LLVMGetFirstInstruction(entry);
+            if first.is_null() {
+                llvm::core::LLVMPositionBuilderAtEnd(builder, entry);
+            } else {
+                llvm::core::LLVMPositionBuilderBefore(builder, first);
+            }
+            let p = llvm::core::LLVMBuildAlloca(builder, typ, cptr!(name));
+            llvm::core::LLVMDisposeBuilder(builder);
+            p
+        }
+    }
+
+    // Constants never point into the heap, so a variable bound to one needs
+    // no root and its slot can become a register.
+    fn allocate_variable(&self, name: &str, v: LLVMValueRef) -> LLVMValueRef {
+        let p = if unsafe { llvm::core::LLVMIsConstant(v) } != 0 {
+            self.allocate_mem(name, self.int_value_type)
+        } else {
+            self.allocate_root(name)
+        };
+        self.llmv_store(v, p);
+        p
+    }
+
+    // Allocates a word slot and registers it with `gc::rlisp_gc_root`, so the
+    // collector sees whatever is stored there until the enclosing frame ends.
+    fn allocate_root(&self, name: &str) -> LLVMValueRef {
+        let p = self.allocate_mem(name, self.int_value_type);
+        self.llmv_store(self.word_value(runtime::NIL), p);
+        self.gc_root(p);
+        p
+    }
+
+    fn gc_root(&self, slot: LLVMValueRef) {
+        let void = unsafe { llvm::core::LLVMVoidTypeInContext(self.context) };
+        let ptr_ty = unsafe { llvm::core::LLVMPointerType(self.int_value_type, 0) };
+        let fun = self.declare_function("rlisp_gc_root", void, &mut [ptr_ty]);
+        self.build_call(fun, &mut [slot], 1, "");
+    }
+
+    fn gc_enter(&self) -> LLVMValueRef {
+        let word = self.int_value_type;
+        let fun = self.declare_function("rlisp_gc_enter", word, &mut []);
+        self.build_call(fun, &mut [], 0, "frame")
+    }
+
+    fn gc_leave(&self, frame: LLVMValueRef) {
+        let void = unsafe { llvm::core::LLVMVoidTypeInContext(self.context) };
+        let fun = self.declare_function("rlisp_gc_leave", void, &mut [self.int_value_type]);
+        self.build_call(fun, &mut [frame], 1, "");
     }
 
 
@@ -461,8 +1167,8 @@ impl VM {
         unsafe { llvm::core::LLVMAddFunction(self.module, cptr!(name), fun_type) }
     }
 
-    fn create_module_with_name(name: &str) -> LLVMModuleRef {
-        unsafe { llvm::core::LLVMModuleCreateWithName(cptr!(name)) }
+    fn create_module_with_name_in_context(name: &str, context: LLVMContextRef) -> LLVMModuleRef {
+        unsafe { llvm::core::LLVMModuleCreateWithNameInContext(cptr!(name), context) }
     }
 
     fn create_builder_in_context(context: LLVMContextRef) -> LLVMBuilderRef {
@@ -476,4 +1182,18 @@ impl VM {
     fn int_type(context: LLVMContextRef) -> LLVMTypeRef {
         unsafe { llvm::core::LLVMInt32TypeInContext(context) }
     }
+
+    fn word_type(context: LLVMContextRef) -> LLVMTypeRef {
+        unsafe { llvm::core::LLVMInt64TypeInContext(context) }
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        unsafe {
+            llvm::core::LLVMDisposeBuilder(self.builder);
+            llvm::core::LLVMDisposeModule(self.module);
+            llvm::core::LLVMContextDispose(self.context);
+        }
+    }
 }
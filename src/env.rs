@@ -1,68 +1,266 @@
-use std::collections::{HashMap, LinkedList};
-use std::collections::hash_map::Entry;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use symbol::Symbol;
 
-#[derive(Debug, PartialEq, Clone)]
+// Frames of local variables are vectors, so a variable whose position
+// `resolver` worked out is a (depth, index) pair, and globals live in
+// numbered slots. Names are still searched for variables it left alone.
+#[derive(Debug, Clone)]
 pub struct Env<T> {
-    global: HashMap<String, T>,
-    local: LinkedList<HashMap<String, T>>,
+    // Shared by every clone, so a global is the same variable in all of
+    // them: a lambda sees `define`s made after it was created, and a
+    // continuation only brings back the local variables.
+    globals: Shared<T>,
+    // Innermost last.
+    local: Vec<Vec<(Symbol, T)>>,
+}
+
+// A lambda or a continuation may be stored in a global, so the environment
+// it keeps only refers to the globals weakly; the ones evaluation runs in
+// keep them alive.
+#[derive(Debug, Clone)]
+enum Shared<T> {
+    Owned(Rc<RefCell<Globals<T>>>),
+    Captured(Weak<RefCell<Globals<T>>>),
+}
+
+#[derive(Debug, Clone)]
+struct Globals<T> {
+    index: HashMap<Symbol, usize>,
+    names: Vec<Symbol>,
+    values: Vec<Option<T>>,
+}
+
+impl<T> Globals<T> {
+    fn set(&mut self, slot: usize, value: T) {
+        while self.values.len() <= slot {
+            self.values.push(None);
+        }
+        self.values[slot] = Some(value);
+    }
+}
+
+pub enum Entry<'a, T: 'a> {
+    Occupied(OccupiedEntry<'a, T>),
+    Vacant(VacantEntry<'a, T>),
+}
+
+// Globals can't be borrowed out of the storage clones share, so entries
+// hand out copies of the values.
+pub enum OccupiedEntry<'a, T: 'a> {
+    Local(&'a mut T),
+    Global(&'a Env<T>, usize),
+}
+
+impl<'a, T: Clone> OccupiedEntry<'a, T> {
+    pub fn get(&self) -> T {
+        match *self {
+            OccupiedEntry::Local(ref value) => (**value).clone(),
+            OccupiedEntry::Global(env, slot) => env.global(slot).unwrap(),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> T {
+        match *self {
+            OccupiedEntry::Local(ref mut old) => ::std::mem::replace(*old, value),
+            OccupiedEntry::Global(env, slot) => {
+                let old = env.global(slot).unwrap();
+                env.with_globals(|g| g.set(slot, value));
+                old
+            }
+        }
+    }
+}
+
+pub enum VacantEntry<'a, T: 'a> {
+    Local(&'a mut Vec<(Symbol, T)>, Symbol),
+    Global(&'a Env<T>, usize),
+}
+
+impl<'a, T: Clone> VacantEntry<'a, T> {
+    pub fn insert(self, value: T) -> T {
+        match self {
+            VacantEntry::Local(frame, key) => {
+                frame.push((key, value.clone()));
+                value
+            }
+            VacantEntry::Global(env, slot) => {
+                env.with_globals(|g| g.set(slot, value.clone()));
+                value
+            }
+        }
+    }
 }
 
 impl<T> Env<T> {
     pub fn new() -> Env<T> {
         Env {
-            global: HashMap::new(),
-            local: LinkedList::new(),
+            globals: Shared::Owned(Rc::new(RefCell::new(Globals {
+                index: HashMap::new(),
+                names: Vec::new(),
+                values: Vec::new(),
+            }))),
+            local: Vec::new(),
+        }
+    }
+
+    // `self` keeping its globals alive again, to be evaluated in.
+    pub fn enter(self) -> Env<T> {
+        if let Shared::Owned(_) = self.globals {
+            return self;
+        }
+        Env {
+            globals: Shared::Owned(self.shared()),
+            local: self.local,
+        }
+    }
+
+    fn shared(&self) -> Rc<RefCell<Globals<T>>> {
+        match self.globals {
+            Shared::Owned(ref g) => g.clone(),
+            Shared::Captured(ref g) => {
+                g.upgrade().expect("the environment a closure was made in was dropped")
+            }
+        }
+    }
+
+    // Runs `f` on the globals, which a captured environment has to get hold
+    // of first.
+    fn with_globals<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut Globals<T>) -> R
+    {
+        match self.globals {
+            Shared::Owned(ref g) => f(&mut g.borrow_mut()),
+            Shared::Captured(_) => f(&mut self.shared().borrow_mut()),
         }
     }
 
     pub fn push_local_scope(&mut self) {
-        self.local.push_front(HashMap::new());
+        self.local.push(Vec::new());
     }
 
     // Need a return value?
     pub fn pop_local_scope(&mut self) {
-        self.local.pop_front();
+        self.local.pop();
     }
 
-    pub fn register<S: Into<String>>(&mut self, key: S, value: T) {
-        match self.local.front_mut() {
-            None => self.global.insert(key.into(), value),
-            Some(v) => v.insert(key.into(), value),
-        };
+    // A name registered twice in a frame keeps its first index.
+    pub fn register<S: Into<Symbol>>(&mut self, key: S, value: T) {
+        let key = key.into();
+        match self.local.last_mut() {
+            None => {
+                let slot = self.global_slot(key);
+                self.with_globals(|g| g.set(slot, value));
+            }
+            Some(frame) => {
+                match frame.iter().position(|&(k, _)| k == key) {
+                    Some(i) => frame[i].1 = value,
+                    None => frame.push((key, value)),
+                }
+            }
+        }
+    }
+
+    // `depth` frames out from the innermost one.
+    pub fn local(&self, depth: usize, index: usize) -> Option<&T> {
+        self.local
+            .len()
+            .checked_sub(depth + 1)
+            .and_then(|d| self.local[d].get(index))
+            .map(|&(_, ref v)| v)
     }
 
-    pub fn entry<S: Into<String>>(&mut self, key: S) -> Entry<String, T> {
+    // Numbers `key` if no clone of this environment has done so yet.
+    pub fn global_slot<S: Into<Symbol>>(&self, key: S) -> usize {
         let key = key.into();
-        let mut v = self.global.entry(key.to_string());
-
-        // Get newer pushed env
-        for lhash in self.local.iter_mut().rev() {
-            match (&v, lhash.entry(key.to_string())) {
-                (&Entry::Vacant(_), nv) => v = nv,
-                (&Entry::Occupied(_), o @ Entry::Occupied(_)) => v = o,
-                (&Entry::Occupied(_), Entry::Vacant(_)) => {}
+        self.with_globals(|globals| {
+            if let Some(&slot) = globals.index.get(&key) {
+                return slot;
             }
+            let slot = globals.names.len();
+            globals.names.push(key);
+            globals.index.insert(key, slot);
+            slot
+        })
+    }
+
+    pub fn global_name(&self, slot: usize) -> String {
+        self.with_globals(|g| g.names[slot].to_string())
+    }
+}
+
+impl<T: Clone> Env<T> {
+    // The innermost variable called `key`, or a place in the innermost
+    // frame to add one.
+    pub fn entry<S: Into<Symbol>>(&mut self, key: S) -> Entry<T> {
+        let key = key.into();
+        let found = self.local
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(d, frame)| frame.iter().position(|&(k, _)| k == key).map(|i| (d, i)))
+            .next();
+        if let Some((d, i)) = found {
+            return Entry::Occupied(OccupiedEntry::Local(&mut self.local[d][i].1));
         }
-        v
+
+        let slot = self.global_slot(key);
+        if self.global(slot).is_some() {
+            return Entry::Occupied(OccupiedEntry::Global(self, slot));
+        }
+        if self.local.is_empty() {
+            return Entry::Vacant(VacantEntry::Global(self, slot));
+        }
+        Entry::Vacant(VacantEntry::Local(self.local.last_mut().unwrap(), key))
     }
 
-    pub fn find(&self, key: &str) -> Option<&T> {
-        for lhash in self.local.iter() {
-            if let v @ Some(_) = lhash.get(key) {
-                return v;
+    // A copy for a closure or a continuation to keep, which doesn't keep the
+    // globals alive.
+    pub fn capture(&self) -> Env<T> {
+        let globals = match self.globals {
+            Shared::Owned(ref g) => Rc::downgrade(g),
+            Shared::Captured(ref g) => g.clone(),
+        };
+        Env {
+            globals: Shared::Captured(globals),
+            local: self.local.clone(),
+        }
+    }
+
+    // A copy with globals of its own, for changes that may be thrown away.
+    pub fn fork(&self) -> Env<T> {
+        Env {
+            globals: Shared::Owned(Rc::new(RefCell::new(self.with_globals(|g| g.clone())))),
+            local: self.local.clone(),
+        }
+    }
+
+    pub fn find<S: Into<Symbol>>(&self, key: S) -> Option<T> {
+        let key = key.into();
+        for frame in self.local.iter().rev() {
+            if let Some(&(_, ref v)) = frame.iter().find(|&&(k, _)| k == key) {
+                return Some(v.clone());
             }
         }
-        self.global.get(key)
+        let slot = self.with_globals(|g| g.index.get(&key).cloned());
+        slot.and_then(|s| self.global(s))
+    }
+
+    pub fn global(&self, slot: usize) -> Option<T> {
+        self.with_globals(|g| g.values.get(slot).and_then(|v| v.clone()))
     }
 
     pub fn debug_list_all_variable(&self) {
-        for lhash in self.local.iter() {
-            for (key, value) in lhash {
+        for frame in self.local.iter() {
+            for &(ref key, _) in frame {
                 println!("{:?} => llvm value in local", key);
             }
         }
-        for (key, value) in &self.global {
-            println!("{:?} => llvm value in global", key);
-        }
+        self.with_globals(|globals| for (slot, value) in globals.values.iter().enumerate() {
+            if value.is_some() {
+                println!("{:?} => llvm value in global", globals.names[slot].to_string());
+            }
+        })
     }
 }
@@ -1,5 +1,7 @@
 use std::error;
 use std::fmt;
+use node::{Node, rsym, rcell};
+use printer;
 
 pub type RResult<T, E> where E: error::Error = Result<T, E>;
 
@@ -32,6 +34,8 @@ pub enum ParseError {
     InvalidSyntax(u32),
     UnmatchedParen(u32),
     RequireString(u32),
+    // An integer literal that doesn't fit in an `i32`.
+    OutOfRange(u32),
 }
 
 impl fmt::Display for ParseError {
@@ -40,6 +44,7 @@ impl fmt::Display for ParseError {
             ParseError::InvalidSyntax(ref p) => write!(f, "Invalid Syntax as: {}", p),
             ParseError::UnmatchedParen(ref p) => write!(f, "Unmatched Paren at {}", p),
             ParseError::RequireString(ref p) => write!(f, "Requred Charater at {}", p),
+            ParseError::OutOfRange(ref p) => write!(f, "Number out of range at {}", p),
         }
     }
 }
@@ -50,12 +55,27 @@ impl error::Error for ParseError {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum EvalError {
     E, // must be fix
     UnknowSymbol(String),
     InvalidArgNumber,
     WrongTypeArg,
+    DivisionByZero,
+    Overflow,
+    SpecialFormValue(String),
+    SpecialFormRebind(String),
+    MacroValue(String),
+    InvalidSyntax(String),
+    // Not errors, but a `throw` to a tag and a call of an escape procedure
+    // made by `call/ec`, on their way to what catches them.
+    Throw(Node, Node),
+    Escape(usize, Node),
+    // `(error message irritant...)`, and what `raise` was given when no
+    // handler took it.
+    Error(Node, Node),
+    Raise(Node),
+    HandlerReturned(Node),
 }
 
 impl fmt::Display for EvalError {
@@ -65,6 +85,73 @@ impl fmt::Display for EvalError {
             EvalError::UnknowSymbol(ref s) => write!(f, "Unknow symbol: {}", s),
             EvalError::InvalidArgNumber => write!(f, "Invalid argument number"),
             EvalError::WrongTypeArg => write!(f, "Wrong type argument"),
+            EvalError::DivisionByZero => write!(f, "Division by zero"),
+            EvalError::Overflow => write!(f, "Integer overflow"),
+            EvalError::SpecialFormValue(ref s) => {
+                write!(f, "Special form can't be used as a value: {}", s)
+            }
+            EvalError::SpecialFormRebind(ref s) => {
+                write!(f, "Special form can't be redefined: {}", s)
+            }
+            EvalError::MacroValue(ref s) => write!(f, "Macro can't be used as a value: {}", s),
+            EvalError::InvalidSyntax(ref s) => write!(f, "Invalid syntax: {}", s),
+            EvalError::Throw(ref tag, _) => write!(f, "Uncaught throw to tag: {:?}", tag),
+            EvalError::Escape(..) => write!(f, "Escape procedure called after call/ec returned"),
+            EvalError::Error(ref message, ref irritants) => {
+                try!(write!(f, "Error: {}", printer::pretty(message)));
+                let mut rest = irritants;
+                while let Node::Cell(ref v, ref next) = *rest {
+                    try!(write!(f, " {}", printer::pretty(v)));
+                    rest = next;
+                }
+                Ok(())
+            }
+            EvalError::Raise(ref v) => write!(f, "Uncaught raise: {}", printer::pretty(v)),
+            EvalError::HandlerReturned(ref v) => {
+                write!(f, "Exception handler returned from raise: {}", printer::pretty(v))
+            }
+        }
+    }
+}
+
+// What `error-object-message` and `error-object-irritants` give for the
+// condition an error is raised as.
+impl EvalError {
+    pub fn message(&self) -> Node {
+        let name = match *self {
+            EvalError::Error(ref message, _) => return message.clone(),
+            EvalError::E => "error",
+            EvalError::UnknowSymbol(_) => "unknown-symbol",
+            EvalError::InvalidArgNumber => "invalid-argument-number",
+            EvalError::WrongTypeArg => "wrong-type-argument",
+            EvalError::DivisionByZero => "division-by-zero",
+            EvalError::Overflow => "integer-overflow",
+            EvalError::SpecialFormValue(_) => "special-form-value",
+            EvalError::SpecialFormRebind(_) => "special-form-rebind",
+            EvalError::MacroValue(_) => "macro-value",
+            EvalError::InvalidSyntax(_) => "invalid-syntax",
+            EvalError::Throw(..) => "uncaught-throw",
+            EvalError::Escape(..) => "escape-after-return",
+            EvalError::Raise(_) => "uncaught-raise",
+            EvalError::HandlerReturned(_) => "handler-returned",
+        };
+        rsym(name)
+    }
+
+    pub fn irritants(&self) -> Node {
+        let one = |v: Node| rcell(v, Node::Nil);
+        match *self {
+            EvalError::Error(_, ref irritants) => irritants.clone(),
+            EvalError::UnknowSymbol(ref s) |
+            EvalError::SpecialFormValue(ref s) |
+            EvalError::SpecialFormRebind(ref s) |
+            EvalError::MacroValue(ref s) |
+            EvalError::InvalidSyntax(ref s) => one(rsym(s.as_str())),
+            EvalError::Throw(ref tag, ref v) => rcell(tag.clone(), one(v.clone())),
+            EvalError::Escape(_, ref v) |
+            EvalError::Raise(ref v) |
+            EvalError::HandlerReturned(ref v) => one(v.clone()),
+            _ => Node::Nil,
         }
     }
 }
@@ -74,3 +161,37 @@ impl error::Error for EvalError {
         ""
     }
 }
+
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    NotSupported(Node),
+    UnknowSymbol(Node),
+    InvalidArgNumber(Node),
+    WrongTypeArg(Node),
+    InvalidModule(String),
+    ExecutionEngine(String),
+    InvalidTarget(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::NotSupported(ref n) => write!(f, "Not supported in compiler: {:?}", n),
+            CompileError::UnknowSymbol(ref n) => write!(f, "Unknow symbol: {:?}", n),
+            CompileError::InvalidArgNumber(ref n) => write!(f, "Invalid argument number: {:?}", n),
+            CompileError::WrongTypeArg(ref n) => write!(f, "Wrong type argument: {:?}", n),
+            CompileError::InvalidModule(ref s) => write!(f, "Invalid module: {}", s),
+            CompileError::ExecutionEngine(ref s) => write!(f, "Execution engine: {}", s),
+            CompileError::InvalidTarget(ref s) => write!(f, "Invalid target: {}", s),
+        }
+    }
+}
+
+impl error::Error for CompileError {
+    fn description(&self) -> &str {
+        ""
+    }
+}
+
+// What `codegen` and `ccodegen` return; neither needs LLVM to name it.
+pub type CompileResult<T> = RResult<T, CompileError>;
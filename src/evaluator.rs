@@ -1,12 +1,204 @@
-use node::{Prim, Node, rcell, rnil, rcar, rcdr};
+use std::cell::Cell;
+use std::mem;
+use std::rc::Rc;
+use node::{Prim, Node, rcell, rnil, rcar, rcdr, rquote, sym_to_str};
 use env::Env;
+use symbol::Symbol;
 use error::{RResult as Result, EvalError};
 
 pub type EvalResult<T> = Result<T, EvalError>;
 
-fn apply_function(renv: &mut Env<Node>, args: (&Node, &Node), body: &Node) -> EvalResult<Node> {
-    try!(register_all(renv, args.0, args.1));
-    eval(renv, body)
+// What a special form or a `Prim::Control` procedure leaves `eval` to do.
+// Nothing is evaluated on the Rust stack, so everything still to be done is
+// in `eval`'s frames, which is what a continuation captures.
+#[derive(Clone)]
+pub enum Step {
+    Value(Node),
+    // Evaluates a form in place of the one being evaluated, as a tail call.
+    Eval(Node),
+    // Evaluates a form and passes its value to the function, which returns
+    // what to do next.
+    Then(Node, Rc<Fn(&mut Env<Node>, Node) -> EvalResult<Step>>),
+    // Does a step with a new frame of local variables, which is dropped
+    // afterwards.
+    Scope(Vec<(Symbol, Node)>, Box<Step>),
+    // Calls a procedure with a list of evaluated arguments.
+    Apply(Node, Node),
+    // Calls a procedure with the continuation of the `call/cc`.
+    CallCC(Node),
+    // Does a step, and returns the value of a `throw` or an escape it
+    // catches instead.
+    Catch(Catcher, Box<Step>),
+    // Does a step, then evaluates the form however the step is left.
+    Protect(Node, Box<Step>),
+    // Calls the first procedure, the second and then the third, as
+    // `dynamic-wind` does.
+    Wind(Node, Node, Node),
+    // Calls the thunk with the handler installed, as
+    // `with-exception-handler` does.
+    Handle(Node, Node),
+    // Returns what the handler `raise-continuable` goes to returns.
+    RaiseContinuable(Node),
+    // Does a step, and if something is raised in it, goes back to where the
+    // step was and does what the function returns for the object raised.
+    Guard(Rc<Fn(&mut Env<Node>, Node) -> EvalResult<Step>>, Box<Step>),
+}
+
+impl Step {
+    pub fn then<F>(form: Node, f: F) -> Step
+        where F: Fn(&mut Env<Node>, Node) -> EvalResult<Step> + 'static
+    {
+        Step::Then(form, Rc::new(f))
+    }
+}
+
+// What a `catch` or a `call/ec` is waiting for.
+#[derive(Clone)]
+pub enum Catcher {
+    Tag(Node),
+    Escape(usize),
+    // A `guard`, which what is raised to it is escaped to with, so that the
+    // cleanups on the way run.
+    Guard(usize),
+}
+
+impl Catcher {
+    // The value `e` carries, if it is one this catches.
+    pub fn catches(&self, e: &EvalError) -> Option<Node> {
+        match (self, e) {
+            (&Catcher::Tag(ref a), &EvalError::Throw(ref b, ref v)) if same_tag(a, b) => {
+                Some(v.clone())
+            }
+            (&Catcher::Escape(a), &EvalError::Escape(b, ref v)) |
+            (&Catcher::Guard(a), &EvalError::Escape(b, ref v)) if a == b => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+// Tags are compared like `eq?`: lists by identity, and procedures never
+// match.
+fn same_tag(a: &Node, b: &Node) -> bool {
+    match (a, b) {
+        (&Node::Cell(ref a, ref d), &Node::Cell(ref b, ref e)) => {
+            Rc::ptr_eq(a, b) && Rc::ptr_eq(d, e)
+        }
+        (&Node::Prim(_), _) | (_, &Node::Prim(_)) => false,
+        _ => a == b,
+    }
+}
+
+thread_local!(static ESCAPES: Cell<usize> = Cell::new(0));
+
+// A number no escape procedure or `guard` has had before.
+pub fn escape_id() -> usize {
+    ESCAPES.with(|n| {
+        n.set(n.get() + 1);
+        n.get()
+    })
+}
+
+// A new escape procedure, which unwinds to `Catcher::Escape` of the number
+// with the value it is called with.
+pub fn escape_procedure() -> (usize, Node) {
+    let id = escape_id();
+    let f = move |_: &mut Env<Node>, args: &Node| -> EvalResult<Node> {
+        match *args {
+            Node::Cell(ref v, ref end) if **end == Node::Nil => {
+                Err(EvalError::Escape(id, (**v).clone()))
+            }
+            _ => Err(EvalError::InvalidArgNumber),
+        }
+    };
+    (id, Node::Prim(Prim::Proc(Rc::new(f))))
+}
+
+// What handlers are given for `e`: the object `raise` was given, or a
+// condition. `throw`s and escapes aren't raised.
+pub fn raised_object(e: &EvalError) -> Option<Node> {
+    match *e {
+        EvalError::Throw(..) | EvalError::Escape(..) => None,
+        EvalError::Raise(ref v) => Some(v.clone()),
+        _ => Some(Node::Condition(Rc::new(e.clone()))),
+    }
+}
+
+// What a `guard` is escaped to with: the object raised, and a procedure that
+// raises it again the same way, for when no clause applies.
+pub fn guarded(v: Node, continuable: bool) -> Node {
+    let obj = v.clone();
+    let again = if continuable {
+        Prim::Control(Rc::new(move |_: &mut Env<Node>, _: &Node| {
+            Ok(Step::RaiseContinuable(obj.clone()))
+        }))
+    } else {
+        Prim::Proc(Rc::new(move |_: &mut Env<Node>, _: &Node| Err(unhandled(obj.clone()))))
+    };
+    rcell(v, Node::Prim(again))
+}
+
+// The error raising `v` ends with when nothing handles it.
+pub fn unhandled(v: Node) -> EvalError {
+    match v {
+        Node::Condition(e) => (*e).clone(),
+        v => EvalError::Raise(v),
+    }
+}
+
+// Where the value being computed goes.
+#[derive(Clone)]
+enum Frame {
+    // The operator of a call, with the unevaluated arguments.
+    Call(Node),
+    // An argument, after the procedure and the arguments before it.
+    Arg(Node, Vec<Node>, Node),
+    Then(Rc<Fn(&mut Env<Node>, Node) -> EvalResult<Step>>),
+    // The result of a macro, which is evaluated in place of the call.
+    Expansion,
+    PopScope,
+    // The environment of the caller, which a call or a macro replaced.
+    Restore(Env<Node>),
+    Catch(Catcher),
+    // The cleanup of an `unwind-protect` or a `dynamic-wind`, with a number
+    // no other has, and the `before` of a `dynamic-wind`, which a
+    // continuation going back into it calls.
+    Protect(usize, Node, Option<Node>),
+    // Pushes the frame once the `before` of a `dynamic-wind` has returned,
+    // and goes on with the step.
+    Enter(Box<Frame>, Box<Step>),
+    // An error a cleanup was run for, which goes on to the frames outside
+    // once it is done. Handlers have already seen it.
+    Reraise(EvalError),
+    // A handler installed by `with-exception-handler`.
+    Handler(Node),
+    // While a handler runs, the ones from the frame at the index up are
+    // left out.
+    Mask(usize),
+    Guard(usize, Rc<Fn(&mut Env<Node>, Node) -> EvalResult<Step>>),
+}
+
+// Everything left to do after a `call/cc`, and the environment to do it in,
+// which shares its globals with every other.
+pub struct Continuation {
+    frames: Vec<Frame>,
+    env: Env<Node>,
+}
+
+impl Continuation {
+    // The environments are captured, as a lambda's is, since the
+    // continuation may be stored in a global.
+    fn new(frames: &[Frame], renv: &Env<Node>) -> Continuation {
+        let frames = frames.iter()
+            .map(|f| match *f {
+                Frame::Restore(ref env) => Frame::Restore(env.capture()),
+                ref f => f.clone(),
+            })
+            .collect();
+        Continuation {
+            frames: frames,
+            env: renv.capture(),
+        }
+    }
 }
 
 fn register_all(renv: &mut Env<Node>, keys: &Node, values: &Node) -> EvalResult<Node> {
@@ -25,47 +217,465 @@ fn register_all(renv: &mut Env<Node>, keys: &Node, values: &Node) -> EvalResult<
     }
 }
 
-fn apply(renv: &mut Env<Node>, fun: &Node, args: &Node) -> EvalResult<Node> {
-    match *fun {
-        Node::Prim(ref prim) => {
-            match *prim {
-                Prim::Proc(ref f) => f(renv, args),
-                Prim::Lambda(ref v, ref a, ref body) => {
-                    let new_env = &mut v.clone();
-                    new_env.push_local_scope();
-                    let ret = apply_function(new_env, (a, &try!(eval_list(renv, args))), body);
-                    new_env.pop_local_scope();
-                    ret
+pub fn eval(renv: &mut Env<Node>, ast: &Node) -> EvalResult<Node> {
+    run(renv, Step::Eval(ast.clone()))
+}
+
+// Calls `fun` with the list of evaluated arguments `args`.
+pub fn apply(renv: &mut Env<Node>, fun: &Node, args: &Node) -> EvalResult<Node> {
+    run(renv, Step::Apply(fun.clone(), args.clone()))
+}
+
+// Does `step` and everything it leads to. After an error `renv` is left as
+// it was outside every call and scope.
+pub fn run(renv: &mut Env<Node>, step: Step) -> EvalResult<Node> {
+    let frames = &mut Vec::new();
+    let ret = run_frames(renv, frames, step);
+    if ret.is_err() {
+        unwind(renv, frames, 0);
+    }
+    ret
+}
+
+fn run_frames(renv: &mut Env<Node>, frames: &mut Vec<Frame>, step: Step) -> EvalResult<Node> {
+    let mut step = step;
+    loop {
+        if let Step::Value(ref v) = step {
+            if frames.is_empty() {
+                return Ok(v.clone());
+            }
+        }
+        step = match advance(renv, frames, step) {
+            Ok(next) => next,
+            Err(e) => try!(handle(renv, frames, e)),
+        };
+    }
+}
+
+// Calls the handler of `e`, or goes back to the innermost frame that
+// catches it or has some cleanup to do before it goes on.
+fn handle(renv: &mut Env<Node>, frames: &mut Vec<Frame>, e: EvalError) -> EvalResult<Step> {
+    let e = match raised_object(&e) {
+        Some(v) => {
+            match raise(frames, v, false) {
+                Ok(step) => return Ok(step),
+                Err(e) => e,
+            }
+        }
+        None => e,
+    };
+    propagate(renv, frames, e)
+}
+
+// Goes back to the innermost frame that catches `e` or has some cleanup to
+// do before it goes on, without calling handlers.
+fn propagate(renv: &mut Env<Node>, frames: &mut Vec<Frame>, e: EvalError) -> EvalResult<Step> {
+    for at in (0..frames.len()).rev() {
+        let (step, cleanup) = match frames[at] {
+            Frame::Catch(ref c) => {
+                match c.catches(&e) {
+                    Some(v) => (Step::Value(v), false),
+                    None => continue,
+                }
+            }
+            Frame::Protect(_, ref form, _) => (Step::Eval(form.clone()), true),
+            Frame::Guard(id, ref f) => {
+                match Catcher::Guard(id).catches(&e) {
+                    Some(v) => (try!(f(renv, v)), false),
+                    None => continue,
                 }
             }
+            _ => continue,
+        };
+        unwind(renv, frames, at);
+        if cleanup {
+            frames.push(Frame::Reraise(e));
         }
-        _ => Err(EvalError::UnknowSymbol(format!("{:?}", fun))),
+        return Ok(step);
+    }
+    Err(e)
+}
+
+// Calls the innermost handler that isn't running with `v`, with the ones
+// outside it in place, or escapes to the innermost `guard`. A handler
+// returning from a raise that isn't continuable is an error.
+fn raise(frames: &mut Vec<Frame>, v: Node, continuable: bool) -> EvalResult<Step> {
+    let mut at = frames.len();
+    while at > 0 {
+        at -= 1;
+        let handler = match frames[at] {
+            Frame::Handler(ref h) => h.clone(),
+            Frame::Guard(id, _) => return Err(EvalError::Escape(id, guarded(v, continuable))),
+            Frame::Mask(n) => {
+                at = n;
+                continue;
+            }
+            _ => continue,
+        };
+        frames.push(Frame::Mask(at));
+        if !continuable {
+            let v = v.clone();
+            frames.push(Frame::Then(Rc::new(move |_, _| {
+                Err(EvalError::HandlerReturned(v.clone()))
+            })));
+        }
+        return Ok(Step::Apply(handler, rcell(v, rnil())));
     }
+    Err(unhandled(v))
 }
 
-pub fn eval_list(renv: &mut Env<Node>, ast: &Node) -> EvalResult<Node> {
+// Drops the frames above the first `len`, going back to the environment
+// they were entered from.
+fn unwind(renv: &mut Env<Node>, frames: &mut Vec<Frame>, len: usize) {
+    while frames.len() > len {
+        match frames.pop() {
+            Some(Frame::Restore(env)) => *renv = env.enter(),
+            Some(Frame::PopScope) => renv.pop_local_scope(),
+            _ => (),
+        }
+    }
+}
+
+fn advance(renv: &mut Env<Node>, frames: &mut Vec<Frame>, step: Step) -> EvalResult<Step> {
+    let v = match step {
+        Step::Value(v) => v,
+        Step::Eval(ast) => return eval_form(renv, frames, &ast),
+        Step::Then(form, f) => {
+            frames.push(Frame::Then(f));
+            return Ok(Step::Eval(form));
+        }
+        Step::Scope(bindings, next) => {
+            if !drops_env(frames) {
+                frames.push(Frame::PopScope);
+            }
+            renv.push_local_scope();
+            for (name, v) in bindings {
+                renv.register(name, v);
+            }
+            return Ok(*next);
+        }
+        Step::Apply(fun, args) => return apply_values(renv, frames, &fun, &args),
+        Step::CallCC(fun) => {
+            let k = Continuation::new(frames, renv);
+            let args = rcell(Node::Prim(Prim::Continuation(Rc::new(k))), rnil());
+            return apply_values(renv, frames, &fun, &args);
+        }
+        Step::Catch(c, next) => {
+            frames.push(Frame::Catch(c));
+            return Ok(*next);
+        }
+        Step::Protect(form, next) => {
+            frames.push(Frame::Protect(escape_id(), form, None));
+            return Ok(*next);
+        }
+        Step::Wind(before, thunk, after) => {
+            let cleanup = rcell(rquote(after), rnil());
+            let after = Frame::Protect(escape_id(), cleanup, Some(before.clone()));
+            frames.push(Frame::Enter(Box::new(after), Box::new(Step::Apply(thunk, rnil()))));
+            return Ok(Step::Apply(before, rnil()));
+        }
+        Step::Handle(handler, thunk) => {
+            frames.push(Frame::Handler(handler));
+            return Ok(Step::Apply(thunk, rnil()));
+        }
+        Step::RaiseContinuable(v) => return raise(frames, v, true),
+        Step::Guard(f, next) => {
+            frames.push(Frame::Guard(escape_id(), f));
+            return Ok(*next);
+        }
+    };
+
+    match frames.pop() {
+        None => Ok(Step::Value(v)),
+        Some(Frame::Call(args)) => apply_form(renv, frames, v, &args),
+        Some(Frame::Arg(fun, mut values, rest)) => {
+            values.push(v);
+            next_arg(frames, fun, values, &rest)
+        }
+        Some(Frame::Then(f)) => f(renv, v),
+        Some(Frame::Expansion) => Ok(Step::Eval(v)),
+        Some(Frame::PopScope) => {
+            renv.pop_local_scope();
+            Ok(Step::Value(v))
+        }
+        Some(Frame::Restore(env)) => {
+            *renv = env.enter();
+            Ok(Step::Value(v))
+        }
+        Some(Frame::Catch(_)) |
+        Some(Frame::Handler(_)) |
+        Some(Frame::Mask(_)) |
+        Some(Frame::Guard(..)) => Ok(Step::Value(v)),
+        Some(Frame::Protect(_, form, _)) => {
+            Ok(Step::then(form, move |_, _| Ok(Step::Value(v.clone()))))
+        }
+        Some(Frame::Enter(frame, next)) => {
+            frames.push(*frame);
+            Ok(*next)
+        }
+        // Nothing is left for `handle` to look at when nothing catches it.
+        Some(Frame::Reraise(e)) => {
+            propagate(renv, frames, e).map_err(|e| {
+                unwind(renv, frames, 0);
+                e
+            })
+        }
+    }
+}
+
+// Whether the environment is only going to be replaced by a caller's, so a
+// call needn't keep it: its scopes are dropped. This is what makes tail
+// calls run in constant space.
+fn drops_env(frames: &mut Vec<Frame>) -> bool {
+    let scopes = frames.iter()
+        .rev()
+        .take_while(|f| match **f {
+            Frame::PopScope => true,
+            _ => false,
+        })
+        .count();
+    let n = frames.len() - scopes;
+    match frames[..n].last() {
+        Some(&Frame::Restore(_)) => {
+            frames.truncate(n);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn eval_form(renv: &mut Env<Node>, frames: &mut Vec<Frame>, ast: &Node) -> EvalResult<Step> {
     match *ast {
+        Node::Int(_) | Node::Bool(_) | Node::Nil => Ok(Step::Value(ast.clone())),
         Node::Cell(ref car, ref cdr) => {
-            Ok(rcell(try!(eval(renv, car)), try!(eval_list(renv, cdr))))
+            match **car {
+                Node::Sym(_) | Node::Local(..) | Node::Global(_) => {
+                    let f = try!(lookup(renv, car));
+                    apply_form(renv, frames, f, cdr)
+                }
+                _ => {
+                    frames.push(Frame::Call((**cdr).clone()));
+                    Ok(Step::Eval((**car).clone()))
+                }
+            }
+        }
+        Node::Sym(_) | Node::Local(..) | Node::Global(_) => {
+            let v = try!(lookup(renv, ast));
+            let err: fn(String) -> EvalError = match v {
+                Node::Prim(Prim::Special(_)) => EvalError::SpecialFormValue,
+                Node::Prim(Prim::Macro(..)) |
+                Node::Prim(Prim::Syntax(_)) => EvalError::MacroValue,
+                _ => return Ok(Step::Value(v)),
+            };
+            let name = match *ast {
+                Node::Global(slot) => renv.global_name(slot),
+                _ => sym_to_str(ast).unwrap_or("").to_string(),
+            };
+            Err(err(name))
         }
-        Node::Nil => Ok(rnil()),
         _ => Err(EvalError::E),
     }
 }
 
-pub fn eval(renv: &mut Env<Node>, ast: &Node) -> EvalResult<Node> {
-    match *ast {
-        Node::Int(_) | Node::Bool(_) | Node::Nil => Ok(ast.clone()),
+// A call of `fun` with the unevaluated arguments `args`.
+fn apply_form(renv: &mut Env<Node>,
+              frames: &mut Vec<Frame>,
+              fun: Node,
+              args: &Node)
+              -> EvalResult<Step> {
+    match fun {
+        Node::Prim(Prim::Special(ref f)) => f(renv, args),
+        Node::Prim(ref mac @ Prim::Macro(..)) => {
+            let (env, body) = try!(macro_env(mac, args));
+            frames.push(Frame::Expansion);
+            frames.push(Frame::Restore(mem::replace(renv, env)));
+            Ok(Step::Eval(body))
+        }
+        // Only `bytecode::Machine` can run these.
+        Node::Prim(Prim::Closure(_)) |
+        Node::Prim(Prim::Syntax(_)) |
+        Node::Prim(Prim::Resume(_)) => Err(EvalError::UnknowSymbol(format!("{:?}", fun))),
+        Node::Prim(_) => next_arg(frames, fun, Vec::new(), args),
+        _ => Err(EvalError::UnknowSymbol(format!("{:?}", fun))),
+    }
+}
+
+// Evaluates the argument `rest` starts with, or calls `fun` once they are
+// all in `values`.
+fn next_arg(frames: &mut Vec<Frame>,
+            fun: Node,
+            values: Vec<Node>,
+            rest: &Node)
+            -> EvalResult<Step> {
+    match *rest {
         Node::Cell(ref car, ref cdr) => {
-            let f = try!(eval(renv, car));
-            apply(renv, &f, cdr)
+            frames.push(Frame::Arg(fun, values, (**cdr).clone()));
+            Ok(Step::Eval((**car).clone()))
         }
-        Node::Sym(ref v) => {
+        Node::Nil => {
+            let args = values.into_iter().rev().fold(Node::Nil, |list, v| rcell(v, list));
+            Ok(Step::Apply(fun, args))
+        }
+        _ => Err(EvalError::E),
+    }
+}
+
+fn apply_values(renv: &mut Env<Node>,
+                frames: &mut Vec<Frame>,
+                fun: &Node,
+                args: &Node)
+                -> EvalResult<Step> {
+    match *fun {
+        Node::Prim(Prim::Proc(ref f)) => f(renv, args).map(Step::Value),
+        Node::Prim(Prim::Control(ref f)) => f(renv, args),
+        Node::Prim(Prim::Lambda(ref v, ref params, ref body)) => {
+            let mut env = v.clone().enter();
+            env.push_local_scope();
+            try!(register_all(&mut env, params, args));
+            let caller = mem::replace(renv, env);
+            if !drops_env(frames) {
+                frames.push(Frame::Restore(caller));
+            }
+            Ok(Step::Eval((**body).clone()))
+        }
+        Node::Prim(Prim::Continuation(ref k)) => {
+            let v = match *args {
+                Node::Cell(ref v, ref end) if **end == Node::Nil => (**v).clone(),
+                _ => return Err(EvalError::InvalidArgNumber),
+            };
+            jump(renv, frames, k, v)
+        }
+        _ => Err(EvalError::UnknowSymbol(format!("{:?}", fun))),
+    }
+}
+
+// Goes on from `k` with `v`. The cleanups of the `unwind-protect`s and
+// `dynamic-wind`s being left are run first, innermost first, and then the
+// `before`s of the `dynamic-wind`s being gone back into, outermost first,
+// each where its frame is.
+fn jump(renv: &mut Env<Node>,
+        frames: &mut Vec<Frame>,
+        k: &Rc<Continuation>,
+        v: Node)
+        -> EvalResult<Step> {
+    let (from, to) = (protects(frames), protects(&k.frames));
+    let common = from.iter().zip(to.iter()).take_while(|&(a, b)| a.1 == b.1).count();
+    let again = Step::Apply(Node::Prim(Prim::Continuation(k.clone())), rcell(v.clone(), rnil()));
+    if let Some(&(at, _)) = from[common..].last() {
+        let cleanup = match frames[at] {
+            Frame::Protect(_, ref form, _) => form.clone(),
+            _ => unreachable!(),
+        };
+        unwind(renv, frames, at);
+        return Ok(Step::then(cleanup, move |_, _| Ok(again.clone())));
+    }
+    for &(at, _) in to[common..].iter() {
+        if let Frame::Protect(_, _, Some(ref before)) = k.frames[at] {
+            let (mut env, mut outer) = (k.env.clone().enter(), k.frames.clone());
+            unwind(&mut env, &mut outer, at);
+            *renv = env;
+            *frames = outer;
+            frames.push(Frame::Enter(Box::new(k.frames[at].clone()), Box::new(again)));
+            return Ok(Step::Apply(before.clone(), rnil()));
+        }
+    }
+    *frames = k.frames.clone();
+    *renv = k.env.clone().enter();
+    Ok(Step::Value(v))
+}
+
+// Where the `Frame::Protect`s are, and their numbers.
+fn protects(frames: &[Frame]) -> Vec<(usize, usize)> {
+    frames.iter()
+        .enumerate()
+        .filter_map(|(at, f)| match *f {
+            Frame::Protect(id, _, _) => Some((at, id)),
+            _ => None,
+        })
+        .collect()
+}
+
+// The value of a variable, which is only allowed to be a special form where
+// it is called.
+fn lookup(renv: &Env<Node>, var: &Node) -> EvalResult<Node> {
+    match *var {
+        Node::Sym(v) => {
             match renv.find(v) {
-                Some(k) => Ok(k.clone()),
-                None => Err(EvalError::UnknowSymbol(v.to_owned())),
+                Some(k) => Ok(k),
+                None => Err(EvalError::UnknowSymbol(v.to_string())),
+            }
+        }
+        Node::Local(depth, index) => renv.local(depth, index).cloned().ok_or(EvalError::E),
+        Node::Global(slot) => {
+            match renv.global(slot) {
+                Some(k) => Ok(k),
+                None => Err(EvalError::UnknowSymbol(renv.global_name(slot))),
             }
         }
         _ => Err(EvalError::E),
     }
 }
+
+// What a call of the macro `mac` with `args` is replaced by.
+pub fn expand_macro(mac: &Prim, args: &Node) -> EvalResult<Node> {
+    let (ref mut env, body) = try!(macro_env(mac, args));
+    eval(env, &body)
+}
+
+// The environment the body of `mac` is evaluated in for a call with `args`,
+// and the body.
+fn macro_env(mac: &Prim, args: &Node) -> EvalResult<(Env<Node>, Node)> {
+    match *mac {
+        Prim::Macro(ref v, ref params, ref body) => {
+            let mut env = v.clone().enter();
+            env.push_local_scope();
+            try!(register_macro_args(&mut env, params, args));
+            Ok((env, (**body).clone()))
+        }
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+// Like `register_all`, but `&rest name` takes the remaining arguments as a
+// list.
+fn register_macro_args(renv: &mut Env<Node>, params: &Node, args: &Node) -> EvalResult<()> {
+    match *params {
+        Node::Nil if *args == Node::Nil => Ok(()),
+        Node::Nil => Err(EvalError::InvalidArgNumber),
+        Node::Cell(ref p, ref rest) => {
+            let name = try!(sym_to_str(p));
+            if name == "&rest" {
+                return match **rest {
+                    Node::Cell(ref name, ref end) if **end == Node::Nil => {
+                        renv.register(try!(sym_to_str(name)), args.clone());
+                        Ok(())
+                    }
+                    _ => Err(EvalError::WrongTypeArg),
+                };
+            }
+            match *args {
+                Node::Cell(ref arg, ref args) => {
+                    renv.register(name, (**arg).clone());
+                    register_macro_args(renv, rest, args)
+                }
+                _ => Err(EvalError::InvalidArgNumber),
+            }
+        }
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+pub fn is_macro(v: &Node) -> bool {
+    match *v {
+        Node::Prim(Prim::Macro(..)) |
+        Node::Prim(Prim::Syntax(_)) => true,
+        _ => false,
+    }
+}
+
+pub fn is_special_form(v: &Node) -> bool {
+    match *v {
+        Node::Prim(Prim::Special(_)) => true,
+        _ => false,
+    }
+}
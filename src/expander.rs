@@ -0,0 +1,949 @@
+// Expands the `syntax-rules` macros of a program before it runs.
+//
+// `define-syntax` and `let-syntax` bind macros in the scopes the expander
+// keeps alongside the variables bound by `lambda`, `let` and `define`, so a
+// variable shadows a macro and the other way around. Expansions are hygienic
+// in the usual way: every identifier a template introduces is renamed (to
+// `name#n`, which the reader never produces), so a variable the template
+// binds can't capture one of the user's. A renamed identifier the expansion
+// doesn't bind means what its name means where the macro was defined, which
+// for anything that isn't a macro is its original name.
+//
+// Macros defined at the top level are also registered in the environment
+// the program runs in, as `Prim::Syntax`, so later inputs to the same
+// environment and the `macroexpand` primitives can use them. A `defmacro` is
+// left to run with the program, so its macro is only expanded here once it is
+// in the environment; until then `evaluator` expands it when called.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use node::*;
+use env::Env;
+use symbol::Symbol;
+use error::EvalError;
+use evaluator::{EvalResult, expand_macro, is_special_form};
+
+pub struct Macro {
+    name: Symbol,
+    ellipsis: Symbol,
+    literals: Vec<Symbol>,
+    // Patterns without the macro keyword, and templates.
+    rules: Vec<(Node, Node)>,
+    scope: Rc<Scope>,
+}
+
+#[derive(Clone)]
+enum Binding {
+    Variable,
+    Macro(Rc<Macro>),
+}
+
+#[derive(Default)]
+struct Scope {
+    bindings: RefCell<HashMap<Symbol, Binding>>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    fn new(parent: &Rc<Scope>) -> Rc<Scope> {
+        Rc::new(Scope {
+            bindings: RefCell::new(HashMap::new()),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    fn find(&self, name: Symbol) -> Option<Binding> {
+        match self.bindings.borrow().get(&name) {
+            Some(b) => Some(b.clone()),
+            None => self.parent.as_ref().and_then(|p| p.find(name)),
+        }
+    }
+
+    fn bind(&self, name: Symbol, binding: Binding) {
+        self.bindings.borrow_mut().insert(name, binding);
+    }
+}
+
+// What an identifier refers to where it is used.
+enum Meaning {
+    Variable(Symbol),
+    Macro(Rc<Macro>),
+    // Made by `defmacro`.
+    Procedural(Prim),
+    // A global or a special form, by its original name.
+    Free(Symbol),
+}
+
+#[derive(Clone)]
+enum Match {
+    One(Node),
+    // What a pattern followed by an ellipsis matched, in order.
+    Many(Vec<Match>),
+}
+
+type Bindings = HashMap<Symbol, Match>;
+
+pub struct Expander<'a> {
+    env: &'a mut Env<Node>,
+    top: Rc<Scope>,
+    // The identifier each renamed one stands for, and the scope of the macro
+    // whose template it comes from.
+    aliases: HashMap<Symbol, (Symbol, Rc<Scope>)>,
+}
+
+// Numbers every expansion in the thread, so that identifiers renamed by
+// different `Expander`s are still different.
+thread_local!(static EXPANSIONS: Cell<usize> = Cell::new(0));
+
+// `ast` is a whole program, to be run in `env`.
+pub fn expand(env: &mut Env<Node>, ast: &Node) -> EvalResult<Node> {
+    Expander::new(env).expand(ast)
+}
+
+fn list<'a>(mut node: &'a Node) -> Option<Vec<&'a Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push(&**car);
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Some(ret),
+        _ => None,
+    }
+}
+
+fn syms(nodes: &[&Node]) -> Option<Vec<Symbol>> {
+    nodes.iter()
+        .map(|n| match **n {
+            Node::Sym(s) => Some(s),
+            _ => None,
+        })
+        .collect()
+}
+
+// `old` itself when `car` and `cdr` are its own, so code no macro touched
+// isn't copied.
+fn share(old: &Node, car: Node, cdr: Node) -> Node {
+    if let Node::Cell(ref a, ref d) = *old {
+        if same(a, &car) && same(d, &cdr) {
+            return old.clone();
+        }
+    }
+    rcell(car, cdr)
+}
+
+fn same(old: &Rc<Node>, new: &Node) -> bool {
+    match (&**old, new) {
+        (&Node::Cell(ref a, ref d), &Node::Cell(ref b, ref e)) => {
+            Rc::ptr_eq(a, b) && Rc::ptr_eq(d, e)
+        }
+        (&Node::Cell(..), _) | (_, &Node::Cell(..)) => false,
+        (&Node::Prim(_), _) | (_, &Node::Prim(_)) => false,
+        (a, b) => a == b,
+    }
+}
+
+fn car_or_self(node: &Node) -> &Node {
+    match *node {
+        Node::Cell(ref car, _) => car,
+        _ => node,
+    }
+}
+
+fn invalid(name: &str) -> EvalError {
+    EvalError::InvalidSyntax(name.to_string())
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(env: &'a mut Env<Node>) -> Expander<'a> {
+        Expander {
+            env: env,
+            top: Rc::new(Scope::default()),
+            aliases: HashMap::new(),
+        }
+    }
+
+    // Expands every macro in `ast`, which is what `macroexpand-all` does.
+    pub fn expand(&mut self, ast: &Node) -> EvalResult<Node> {
+        let top = self.top.clone();
+        self.expand_in(ast, &top)
+    }
+
+    pub fn macroexpand_all(&mut self, form: &Node) -> EvalResult<Node> {
+        self.expand(form)
+    }
+
+    // What `form` expands to if it is a macro call, leaving its subforms as
+    // they are.
+    pub fn macroexpand_1(&mut self, form: &Node) -> EvalResult<Option<Node>> {
+        if let Node::Cell(ref car, ref args) = *form {
+            if let Node::Sym(s) = **car {
+                let top = self.top.clone();
+                match self.meaning(s, &top) {
+                    Meaning::Macro(m) => {
+                        let expansion = try!(self.transcribe(&m, args, &top));
+                        return Ok(Some(self.strip_free(&expansion)));
+                    }
+                    Meaning::Procedural(p) => return expand_macro(&p, args).map(Some),
+                    _ => (),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Expands `form` until it isn't a macro call.
+    pub fn macroexpand(&mut self, form: &Node) -> EvalResult<Node> {
+        let mut form = form.clone();
+        while let Some(expansion) = try!(self.macroexpand_1(&form)) {
+            form = expansion;
+        }
+        Ok(form)
+    }
+
+    fn meaning(&self, name: Symbol, scope: &Rc<Scope>) -> Meaning {
+        match scope.find(name) {
+            Some(Binding::Variable) => Meaning::Variable(name),
+            Some(Binding::Macro(m)) => Meaning::Macro(m),
+            None => {
+                match self.aliases.get(&name) {
+                    Some(&(original, ref scope)) => self.meaning(original, scope),
+                    None => {
+                        match self.env.find(name).as_ref() {
+                            Some(&Node::Prim(Prim::Syntax(ref m))) => Meaning::Macro(m.clone()),
+                            Some(&Node::Prim(ref p @ Prim::Macro(..))) => {
+                                Meaning::Procedural(p.clone())
+                            }
+                            _ => Meaning::Free(name),
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn same_identifier(&self,
+                       a: Symbol,
+                       a_scope: &Rc<Scope>,
+                       b: Symbol,
+                       b_scope: &Rc<Scope>)
+                       -> bool {
+        match (self.meaning(a, a_scope), self.meaning(b, b_scope)) {
+            (Meaning::Variable(a), Meaning::Variable(b)) |
+            (Meaning::Free(a), Meaning::Free(b)) => a == b,
+            (Meaning::Macro(a), Meaning::Macro(b)) => Rc::ptr_eq(&a, &b),
+            (Meaning::Procedural(a), Meaning::Procedural(b)) => {
+                match (a, b) {
+                    (Prim::Macro(_, _, a), Prim::Macro(_, _, b)) => Rc::ptr_eq(&a, &b),
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn original(&self, mut s: Symbol) -> Symbol {
+        while let Some(&(original, _)) = self.aliases.get(&s) {
+            s = original;
+        }
+        s
+    }
+
+    // `node` with every renamed identifier back to its original name, for
+    // what is quoted.
+    fn strip(&self, node: &Node) -> Node {
+        match *node {
+            Node::Sym(s) => Node::Sym(self.original(s)),
+            Node::Cell(ref car, ref cdr) => share(node, self.strip(car), self.strip(cdr)),
+            _ => node.clone(),
+        }
+    }
+
+    // `node` with the identifiers a template introduced back to their original
+    // names, except those bound in it, which are kept apart from the user's.
+    fn strip_free(&self, node: &Node) -> Node {
+        let bound = &mut HashSet::new();
+        self.binders(node, bound);
+        self.strip_except(node, bound)
+    }
+
+    fn binders(&self, node: &Node, bound: &mut HashSet<Symbol>) {
+        if let Node::Cell(ref car, ref cdr) = *node {
+            let vars = match (&**car, list(cdr)) {
+                (&Node::Sym(s), Some(ref rest)) if !rest.is_empty() => {
+                    match self.original(s).as_str() {
+                        "lambda" | "define" => list(rest[0]).unwrap_or(vec![rest[0]]),
+                        "let" => {
+                            list(rest[0]).unwrap_or(vec![]).into_iter().map(car_or_self).collect()
+                        }
+                        _ => vec![],
+                    }
+                }
+                _ => vec![],
+            };
+            for var in vars {
+                if let Node::Sym(s) = *var {
+                    bound.insert(s);
+                }
+            }
+            self.binders(car, bound);
+            self.binders(cdr, bound);
+        }
+    }
+
+    fn strip_except(&self, node: &Node, bound: &HashSet<Symbol>) -> Node {
+        match *node {
+            Node::Sym(s) if !bound.contains(&s) => Node::Sym(self.original(s)),
+            Node::Cell(ref car, ref cdr) => {
+                share(node, self.strip_except(car, bound), self.strip_except(cdr, bound))
+            }
+            _ => node.clone(),
+        }
+    }
+
+    fn expand_in(&mut self, ast: &Node, scope: &Rc<Scope>) -> EvalResult<Node> {
+        match *ast {
+            Node::Sym(s) => {
+                match self.meaning(s, scope) {
+                    Meaning::Variable(s) | Meaning::Free(s) => Ok(Node::Sym(s)),
+                    Meaning::Macro(_) | Meaning::Procedural(_) => {
+                        Err(EvalError::MacroValue(self.original(s).to_string()))
+                    }
+                }
+            }
+            Node::Cell(ref car, ref cdr) => {
+                if let Node::Sym(s) = **car {
+                    match self.meaning(s, scope) {
+                        Meaning::Macro(m) => {
+                            let expansion = try!(self.transcribe(&m, cdr, scope));
+                            return self.expand_in(&expansion, scope);
+                        }
+                        Meaning::Procedural(p) => {
+                            let expansion = try!(expand_macro(&p, cdr));
+                            return self.expand_in(&expansion, scope);
+                        }
+                        Meaning::Free(name) => {
+                            if let Some(ret) = self.expand_special(name, ast, cdr, scope) {
+                                return ret;
+                            }
+                        }
+                        Meaning::Variable(_) => (),
+                    }
+                }
+                let car = try!(self.expand_in(car, scope));
+                let cdr = try!(self.expand_list(cdr, scope));
+                Ok(share(ast, car, cdr))
+            }
+            _ => Ok(ast.clone()),
+        }
+    }
+
+    fn expand_list(&mut self, node: &Node, scope: &Rc<Scope>) -> EvalResult<Node> {
+        match *node {
+            Node::Cell(ref car, ref cdr) => {
+                let car = try!(self.expand_in(car, scope));
+                let cdr = try!(self.expand_list(cdr, scope));
+                Ok(share(node, car, cdr))
+            }
+            _ => Ok(node.clone()),
+        }
+    }
+
+    // Returns `None` when `name` isn't a form the expander needs to know
+    // about, or when it is malformed, which is left for `evaluator` to report.
+    fn expand_special(&mut self,
+                      name: Symbol,
+                      ast: &Node,
+                      rest: &Node,
+                      scope: &Rc<Scope>)
+                      -> Option<EvalResult<Node>> {
+        let ret = match name.as_str() {
+            "quote" => Ok(self.strip(rest)),
+            "defmacro" => Ok(self.strip(rest)),
+            "quasiquote" => {
+                match *rest {
+                    Node::Cell(ref template, ref end) => {
+                        self.expand_template(template, 0, scope)
+                            .map(|t| share(rest, t, (**end).clone()))
+                    }
+                    _ => return None,
+                }
+            }
+            "lambda" => {
+                match self.expand_lambda(rest, scope) {
+                    Some(ret) => ret,
+                    None => return None,
+                }
+            }
+            "let" => {
+                match self.expand_let(rest, scope) {
+                    Some(ret) => ret,
+                    None => return None,
+                }
+            }
+            "let*" => {
+                match self.expand_let_star(rest, scope) {
+                    Some(ret) => ret,
+                    None => return None,
+                }
+            }
+            "do" => {
+                match self.expand_do(rest, scope) {
+                    Some(ret) => ret,
+                    None => return None,
+                }
+            }
+            "case" => {
+                match self.expand_case(rest, scope) {
+                    Some(ret) => ret,
+                    None => return None,
+                }
+            }
+            "guard" => {
+                match self.expand_guard(rest, scope) {
+                    Some(ret) => ret,
+                    None => return None,
+                }
+            }
+            "define" => {
+                match *rest {
+                    Node::Cell(ref var, ref value) => {
+                        if let Node::Sym(var) = **var {
+                            scope.bind(var, Binding::Variable);
+                        }
+                        self.expand_list(value, scope).map(|v| share(rest, (**var).clone(), v))
+                    }
+                    _ => return None,
+                }
+            }
+            "define-syntax" => {
+                return Some(self.define_syntax(rest, scope).map(|_| Node::Nil));
+            }
+            "let-syntax" => return Some(self.let_syntax(rest, scope)),
+            _ => return None,
+        };
+        Some(ret.map(|rest| share(ast, Node::Sym(name), rest)))
+    }
+
+    fn expand_template(&mut self,
+                       template: &Node,
+                       depth: usize,
+                       scope: &Rc<Scope>)
+                       -> EvalResult<Node> {
+        match quote_form(template) {
+            Some((name, x)) if depth == 0 && name.starts_with("unquote") => {
+                let x = try!(self.expand_in(x, scope));
+                return Ok(rlist(rsym(name), x));
+            }
+            Some((name, x)) if name != "quote" => {
+                let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+                let x = try!(self.expand_template(x, depth, scope));
+                return Ok(rlist(rsym(name), x));
+            }
+            _ => (),
+        }
+        match *template {
+            Node::Cell(ref car, ref cdr) => {
+                let car = try!(self.expand_template(car, depth, scope));
+                let cdr = try!(self.expand_template(cdr, depth, scope));
+                Ok(share(template, car, cdr))
+            }
+            _ => Ok(self.strip(template)),
+        }
+    }
+
+    fn expand_lambda(&mut self, rest: &Node, scope: &Rc<Scope>) -> Option<EvalResult<Node>> {
+        let (params, body) = match *rest {
+            Node::Cell(ref params, ref body) => (params, body),
+            _ => return None,
+        };
+        let names = match list(params).and_then(|p| syms(&p)) {
+            Some(names) => names,
+            None => return None,
+        };
+        let inner = Scope::new(scope);
+        for n in names {
+            inner.bind(n, Binding::Variable);
+        }
+        Some(self.expand_list(body, &inner).map(|body| share(rest, (**params).clone(), body)))
+    }
+
+    // The inits are expanded outside the variables, and the steps inside.
+    fn expand_do(&mut self, rest: &Node, scope: &Rc<Scope>) -> Option<EvalResult<Node>> {
+        let (specs, body) = match *rest {
+            Node::Cell(ref specs, ref body) => (specs, body),
+            _ => return None,
+        };
+        let specs = match list(specs) {
+            Some(specs) => specs,
+            None => return None,
+        };
+        let inner = Scope::new(scope);
+        let mut inits = Vec::new();
+        for spec in specs.iter() {
+            match list(spec) {
+                Some(ref parts) if parts.len() == 2 || parts.len() == 3 => {
+                    match *parts[0] {
+                        Node::Sym(var) => inner.bind(var, Binding::Variable),
+                        _ => return None,
+                    }
+                    match self.expand_in(parts[1], scope) {
+                        Ok(init) => inits.push(init),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                _ => return None,
+            }
+        }
+        let mut expanded = Vec::new();
+        for (spec, init) in specs.iter().zip(inits.into_iter()) {
+            let (var, step) = match **spec {
+                Node::Cell(ref var, ref rest) => (var, cdr_ref(rest).unwrap()),
+                _ => unreachable!(),
+            };
+            match self.expand_list(step, &inner) {
+                Ok(step) => expanded.push(rcell((**var).clone(), rcell(init, step))),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let specs = expanded.into_iter().rev().fold(Node::Nil, |rest, s| rcell(s, rest));
+        Some(self.expand_list(body, &inner).map(|body| rcell(specs, body)))
+    }
+
+    // The datums of the clauses are quoted.
+    fn expand_case(&mut self, rest: &Node, scope: &Rc<Scope>) -> Option<EvalResult<Node>> {
+        let (key, clauses) = match *rest {
+            Node::Cell(ref key, ref clauses) => (key, clauses),
+            _ => return None,
+        };
+        let clauses = match list(clauses) {
+            Some(clauses) => clauses,
+            None => return None,
+        };
+        let key = match self.expand_in(key, scope) {
+            Ok(key) => key,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut expanded = Vec::new();
+        for clause in clauses {
+            expanded.push(match *clause {
+                Node::Cell(ref datums, ref body) => {
+                    match self.expand_list(body, scope) {
+                        Ok(body) => share(clause, self.strip(datums), body),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                _ => clause.clone(),
+            });
+        }
+        let clauses = expanded.into_iter().rev().fold(Node::Nil, |rest, c| rcell(c, rest));
+        Some(Ok(rcell(key, clauses)))
+    }
+
+    // Only the clauses see the variable.
+    fn expand_guard(&mut self, rest: &Node, scope: &Rc<Scope>) -> Option<EvalResult<Node>> {
+        let (spec, body) = match *rest {
+            Node::Cell(ref spec, ref body) => (spec, body),
+            _ => return None,
+        };
+        let (var, clauses) = match **spec {
+            Node::Cell(ref var, ref clauses) => {
+                match **var {
+                    Node::Sym(var) => (var, clauses),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        let body = match self.expand_list(body, scope) {
+            Ok(body) => body,
+            Err(e) => return Some(Err(e)),
+        };
+        let inner = Scope::new(scope);
+        inner.bind(var, Binding::Variable);
+        Some(self.expand_list(clauses, &inner)
+            .map(|clauses| share(rest, share(spec, Node::Sym(var), clauses), body)))
+    }
+
+    fn expand_let(&mut self, rest: &Node, scope: &Rc<Scope>) -> Option<EvalResult<Node>> {
+        // The name of a named `let` is bound around the variables.
+        let (name, named) = match *rest {
+            Node::Cell(ref car, ref cdr) => {
+                match **car {
+                    Node::Sym(name) => (Some(name), &**cdr),
+                    _ => (None, rest),
+                }
+            }
+            _ => (None, rest),
+        };
+        let (bindings, body) = match *named {
+            Node::Cell(ref bindings, ref body) => (bindings, body),
+            _ => return None,
+        };
+        let pairs = match list(bindings) {
+            Some(pairs) => pairs,
+            None => return None,
+        };
+        let mut names = Vec::new();
+        for b in pairs.iter() {
+            match (car_ref(b), cdr_ref(b)) {
+                (Ok(&Node::Sym(name)), Ok(&Node::Cell(_, ref end))) if **end == Node::Nil => {
+                    names.push(name)
+                }
+                _ => return None,
+            }
+        }
+        let ret = self.expand_let_parts(bindings, body, names, name, scope)
+            .map(|(bindings, body)| share(named, bindings, body));
+        Some(match name {
+            Some(name) => ret.map(|named| share(rest, Node::Sym(name), named)),
+            None => ret,
+        })
+    }
+
+    // Each value sees the variables before it.
+    fn expand_let_star(&mut self, rest: &Node, scope: &Rc<Scope>) -> Option<EvalResult<Node>> {
+        let (bindings, body) = match *rest {
+            Node::Cell(ref bindings, ref body) => (bindings, body),
+            _ => return None,
+        };
+        let pairs = match list(bindings) {
+            Some(pairs) => pairs,
+            None => return None,
+        };
+        let mut parts = Vec::new();
+        for b in pairs.iter() {
+            match (car_ref(b), cdr_ref(b)) {
+                (Ok(&Node::Sym(name)), Ok(&Node::Cell(ref v, ref end))) if **end == Node::Nil => {
+                    parts.push((name, v))
+                }
+                _ => return None,
+            }
+        }
+        let inner = Scope::new(scope);
+        let mut expanded = Vec::new();
+        for (name, value) in parts {
+            match self.expand_in(value, &inner) {
+                Ok(value) => expanded.push(rlist(Node::Sym(name), value)),
+                Err(e) => return Some(Err(e)),
+            }
+            inner.bind(name, Binding::Variable);
+        }
+        let bindings = expanded.into_iter().rev().fold(Node::Nil, |rest, b| rcell(b, rest));
+        Some(self.expand_list(body, &inner).map(|body| rcell(bindings, body)))
+    }
+
+    fn expand_let_parts(&mut self,
+                        bindings: &Node,
+                        body: &Node,
+                        names: Vec<Symbol>,
+                        name: Option<Symbol>,
+                        scope: &Rc<Scope>)
+                        -> EvalResult<(Node, Node)> {
+        // Values are expanded in the enclosing scope.
+        let bindings = try!(self.expand_bindings(bindings, scope));
+        let inner = match name {
+            Some(name) => {
+                let named = Scope::new(scope);
+                named.bind(name, Binding::Variable);
+                Scope::new(&named)
+            }
+            None => Scope::new(scope),
+        };
+        for n in names {
+            inner.bind(n, Binding::Variable);
+        }
+        let body = try!(self.expand_list(body, &inner));
+        Ok((bindings, body))
+    }
+
+    fn expand_bindings(&mut self, bindings: &Node, scope: &Rc<Scope>) -> EvalResult<Node> {
+        match *bindings {
+            Node::Cell(ref b, ref rest) => {
+                let value = match **b {
+                    Node::Cell(ref name, ref value) => {
+                        let v = try!(self.expand_list(value, scope));
+                        share(b, (**name).clone(), v)
+                    }
+                    _ => (**b).clone(),
+                };
+                let rest = try!(self.expand_bindings(rest, scope));
+                Ok(share(bindings, value, rest))
+            }
+            _ => Ok(bindings.clone()),
+        }
+    }
+
+    fn define_syntax(&mut self, rest: &Node, scope: &Rc<Scope>) -> EvalResult<()> {
+        let args = try!(list(rest).ok_or(invalid("define-syntax")));
+        if args.len() != 2 {
+            return Err(invalid("define-syntax"));
+        }
+        let name = try!(self.syntax_name(args[0], "define-syntax"));
+        let m = Rc::new(try!(self.syntax_rules(name, args[1], scope)));
+        if Rc::ptr_eq(scope, &self.top) {
+            self.env.register(name, Node::Prim(Prim::Syntax(m.clone())));
+        }
+        scope.bind(name, Binding::Macro(m));
+        Ok(())
+    }
+
+    // `(let-syntax ((name rules) ...) body ...)` is `(progn body ...)` with
+    // the macros defined in the body.
+    fn let_syntax(&mut self, rest: &Node, scope: &Rc<Scope>) -> EvalResult<Node> {
+        let (bindings, body) = match *rest {
+            Node::Cell(ref bindings, ref body) => (bindings, body),
+            _ => return Err(invalid("let-syntax")),
+        };
+        let inner = Scope::new(scope);
+        for b in try!(list(bindings).ok_or(invalid("let-syntax"))) {
+            let b = try!(list(b).ok_or(invalid("let-syntax")));
+            if b.len() != 2 {
+                return Err(invalid("let-syntax"));
+            }
+            let name = try!(self.syntax_name(b[0], "let-syntax"));
+            let m = try!(self.syntax_rules(name, b[1], scope));
+            inner.bind(name, Binding::Macro(Rc::new(m)));
+        }
+        let body = try!(self.expand_list(body, &inner));
+        Ok(rcell(rsym("progn"), body))
+    }
+
+    fn syntax_name(&self, name: &Node, form: &str) -> EvalResult<Symbol> {
+        match *name {
+            Node::Sym(s) => {
+                if self.env.find(s).as_ref().map_or(false, is_special_form) {
+                    return Err(EvalError::SpecialFormRebind(s.to_string()));
+                }
+                Ok(s)
+            }
+            _ => Err(invalid(form)),
+        }
+    }
+
+    // `(syntax-rules (literal ...) (pattern template) ...)`, optionally with
+    // an identifier to use instead of `...` before the literals.
+    fn syntax_rules(&self, name: Symbol, spec: &Node, scope: &Rc<Scope>) -> EvalResult<Macro> {
+        let spec = try!(list(spec).ok_or(invalid("syntax-rules")));
+        match spec.first() {
+            Some(&&Node::Sym(s)) => {
+                match self.meaning(s, scope) {
+                    Meaning::Free(s) if s == "syntax-rules" => (),
+                    _ => return Err(invalid("syntax-rules")),
+                }
+            }
+            _ => return Err(invalid("syntax-rules")),
+        }
+        let (ellipsis, rest) = match spec.get(1) {
+            Some(&&Node::Sym(s)) => (s, &spec[2..]),
+            _ => (Symbol::intern("..."), &spec[1..]),
+        };
+        let literals = try!(rest.first()
+            .and_then(|l| list(l))
+            .and_then(|l| syms(&l))
+            .ok_or(invalid("syntax-rules")));
+        let mut rules = Vec::new();
+        for r in rest[1..].iter() {
+            match list(r) {
+                Some(ref r) if r.len() == 2 => {
+                    match *r[0] {
+                        Node::Cell(_, ref pattern) => {
+                            rules.push(((**pattern).clone(), r[1].clone()))
+                        }
+                        _ => return Err(invalid("syntax-rules")),
+                    }
+                }
+                _ => return Err(invalid("syntax-rules")),
+            }
+        }
+        Ok(Macro {
+            name: name,
+            ellipsis: ellipsis,
+            literals: literals,
+            rules: rules,
+            scope: scope.clone(),
+        })
+    }
+
+    // What the use of `m` with `args` expands to.
+    fn transcribe(&mut self, m: &Macro, args: &Node, scope: &Rc<Scope>) -> EvalResult<Node> {
+        for &(ref pattern, ref template) in m.rules.iter() {
+            let binds = &mut HashMap::new();
+            if self.match_pattern(m, pattern, args, scope, binds) {
+                EXPANSIONS.with(|n| n.set(n.get() + 1));
+                return self.instantiate(m, template, binds, Some(m.ellipsis), &mut HashMap::new());
+            }
+        }
+        Err(invalid(&m.name))
+    }
+
+    fn match_pattern(&self,
+                     m: &Macro,
+                     pattern: &Node,
+                     form: &Node,
+                     scope: &Rc<Scope>,
+                     binds: &mut Bindings)
+                     -> bool {
+        match *pattern {
+            Node::Sym(s) if s == "_" => true,
+            Node::Sym(s) if m.literals.contains(&s) => {
+                match *form {
+                    Node::Sym(f) => self.same_identifier(f, scope, s, &m.scope),
+                    _ => false,
+                }
+            }
+            Node::Sym(s) => {
+                binds.insert(s, Match::One(form.clone()));
+                true
+            }
+            Node::Cell(..) => {
+                let (patterns, forms) = match (list(pattern), list(form)) {
+                    (Some(p), Some(f)) => (p, f),
+                    _ => return false,
+                };
+                let at = patterns.iter().position(|p| **p == Node::Sym(m.ellipsis));
+                let (before, repeated, after) = match at {
+                    Some(0) => return false,
+                    Some(i) => (&patterns[..i - 1], Some(patterns[i - 1]), &patterns[i + 1..]),
+                    None => (&patterns[..], None, &patterns[..0]),
+                };
+                if forms.len() < before.len() + after.len() ||
+                   (repeated.is_none() && forms.len() != before.len()) {
+                    return false;
+                }
+                let n = forms.len() - before.len() - after.len();
+                let (head, forms) = forms.split_at(before.len());
+                let (middle, tail) = forms.split_at(n);
+                for (p, f) in before.iter().zip(head.iter()).chain(after.iter().zip(tail.iter())) {
+                    if !self.match_pattern(m, p, f, scope, binds) {
+                        return false;
+                    }
+                }
+                if let Some(repeated) = repeated {
+                    let mut matches = Vec::new();
+                    for f in middle.iter() {
+                        let sub = &mut HashMap::new();
+                        if !self.match_pattern(m, repeated, f, scope, sub) {
+                            return false;
+                        }
+                        matches.push(sub.clone());
+                    }
+                    for var in self.pattern_vars(m, repeated) {
+                        let items = matches.iter().map(|b| b[&var].clone()).collect();
+                        binds.insert(var, Match::Many(items));
+                    }
+                }
+                true
+            }
+            Node::Prim(_) => false,
+            _ => *pattern == *form,
+        }
+    }
+
+    fn pattern_vars(&self, m: &Macro, pattern: &Node) -> Vec<Symbol> {
+        match *pattern {
+            Node::Sym(s) if s != "_" && s != m.ellipsis && !m.literals.contains(&s) => vec![s],
+            Node::Cell(ref car, ref cdr) => {
+                let mut vars = self.pattern_vars(m, car);
+                vars.extend(self.pattern_vars(m, cdr));
+                vars
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // `ellipsis` is `None` inside `(... template)`, where it is an ordinary
+    // identifier.
+    fn instantiate(&mut self,
+                   m: &Macro,
+                   template: &Node,
+                   binds: &Bindings,
+                   ellipsis: Option<Symbol>,
+                   renamed: &mut HashMap<Symbol, Symbol>)
+                   -> EvalResult<Node> {
+        match *template {
+            Node::Sym(s) => {
+                match binds.get(&s) {
+                    Some(&Match::One(ref v)) => Ok(v.clone()),
+                    Some(&Match::Many(_)) => Err(invalid(&m.name)),
+                    None => Ok(Node::Sym(self.rename(m, s, renamed))),
+                }
+            }
+            Node::Cell(ref car, ref cdr) => {
+                if let (Some(e), &Node::Sym(s)) = (ellipsis, &**car) {
+                    if s == e {
+                        let escaped = try!(car_ref(cdr).map_err(|_| invalid(&m.name)));
+                        return self.instantiate(m, escaped, binds, None, renamed);
+                    }
+                }
+                // `car` followed by as many ellipses as it is repeated in.
+                let mut depth = 0;
+                let mut rest: &Node = cdr;
+                while let (Some(e), &Node::Cell(ref next, ref after)) = (ellipsis, rest) {
+                    if **next != Node::Sym(e) {
+                        break;
+                    }
+                    depth += 1;
+                    rest = after;
+                }
+                let rest = try!(self.instantiate(m, rest, binds, ellipsis, renamed));
+                if depth == 0 {
+                    let car = try!(self.instantiate(m, car, binds, ellipsis, renamed));
+                    return Ok(rcell(car, rest));
+                }
+                let items = &mut Vec::new();
+                try!(self.instantiate_many(m, car, binds, depth, renamed, items));
+                Ok(items.drain(..).rev().fold(rest, |list, v| rcell(v, list)))
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    fn instantiate_many(&mut self,
+                        m: &Macro,
+                        template: &Node,
+                        binds: &Bindings,
+                        depth: usize,
+                        renamed: &mut HashMap<Symbol, Symbol>,
+                        items: &mut Vec<Node>)
+                        -> EvalResult<()> {
+        let vars: Vec<(Symbol, &Vec<Match>)> = self.pattern_vars(m, template)
+            .into_iter()
+            .filter_map(|v| match binds.get(&v) {
+                Some(&Match::Many(ref matches)) => Some((v, matches)),
+                _ => None,
+            })
+            .collect();
+        let len = match vars.first() {
+            Some(&(_, matches)) => matches.len(),
+            None => return Err(invalid(&m.name)),
+        };
+        if vars.iter().any(|&(_, matches)| matches.len() != len) {
+            return Err(invalid(&m.name));
+        }
+        for i in 0..len {
+            let mut binds = binds.clone();
+            for &(v, matches) in vars.iter() {
+                binds.insert(v, matches[i].clone());
+            }
+            if depth == 1 {
+                items.push(try!(self.instantiate(m, template, &binds, Some(m.ellipsis), renamed)));
+            } else {
+                try!(self.instantiate_many(m, template, &binds, depth - 1, renamed, items));
+            }
+        }
+        Ok(())
+    }
+
+    // The same identifier is renamed the same way throughout an expansion.
+    fn rename(&mut self, m: &Macro, name: Symbol, renamed: &mut HashMap<Symbol, Symbol>) -> Symbol {
+        if let Some(&s) = renamed.get(&name) {
+            return s;
+        }
+        let s = Symbol::intern(&format!("{}#{}", name, EXPANSIONS.with(|n| n.get())));
+        self.aliases.insert(s, (name, m.scope.clone()));
+        renamed.insert(name, s);
+        s
+    }
+}
@@ -0,0 +1,108 @@
+// A precise mark and sweep collector for cells allocated by compiled code.
+//
+// Compiled code registers the address of every stack slot that may hold a
+// heap pointer with `rlisp_gc_root`. Those slots form a shadow stack which,
+// together with the operands of the allocation in progress, is the root set.
+// Cells that were not allocated here (quoted constants live in globals) are
+// never freed and, since they can only point at other constants, not traced.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashSet;
+use std::env;
+use runtime::{Word, Cell, TAG_CELL, tag};
+
+const INITIAL_THRESHOLD: usize = 1024;
+
+struct Heap {
+    objects: HashSet<usize>,
+    roots: Vec<*mut Word>,
+    allocated: usize, // since the last collection
+    threshold: usize,
+    stress: bool,
+}
+
+thread_local!(static HEAP: RefCell<Heap> = RefCell::new(Heap::new()));
+
+impl Heap {
+    fn new() -> Heap {
+        Heap {
+            objects: HashSet::new(),
+            roots: Vec::new(),
+            allocated: 0,
+            threshold: INITIAL_THRESHOLD,
+            stress: env::var("RLISP_GC_STRESS").is_ok(),
+        }
+    }
+
+    fn alloc(&mut self, car: Word, cdr: Word) -> Word {
+        if self.stress || self.allocated >= self.threshold {
+            self.collect(&[car, cdr]);
+        }
+        let p = Box::into_raw(Box::new(Cell { car: car, cdr: cdr })) as usize;
+        self.objects.insert(p);
+        self.allocated += 1;
+        p as Word
+    }
+
+    fn collect(&mut self, extra_roots: &[Word]) {
+        let mut marked = HashSet::new();
+        let mut stack = extra_roots.to_vec();
+        for &slot in self.roots.iter() {
+            stack.push(unsafe { *slot });
+        }
+
+        while let Some(v) = stack.pop() {
+            let p = v as usize;
+            if tag(v) != TAG_CELL || !self.objects.contains(&p) || !marked.insert(p) {
+                continue;
+            }
+            let cell = unsafe { &*(p as *const Cell) };
+            stack.push(cell.car);
+            stack.push(cell.cdr);
+        }
+
+        let dead: Vec<usize> = self.objects.difference(&marked).cloned().collect();
+        for p in dead {
+            self.objects.remove(&p);
+            unsafe { drop(Box::from_raw(p as *mut Cell)) };
+        }
+        self.allocated = 0;
+        self.threshold = cmp::max(INITIAL_THRESHOLD, self.objects.len() * 2);
+    }
+}
+
+pub fn alloc_cell(car: Word, cdr: Word) -> Word {
+    HEAP.with(|heap| heap.borrow_mut().alloc(car, cdr))
+}
+
+// In stress mode every allocation collects first, which shakes out missing
+// roots quickly. It can also be enabled with `RLISP_GC_STRESS`.
+pub fn set_stress(stress: bool) {
+    HEAP.with(|heap| heap.borrow_mut().stress = stress);
+}
+
+pub fn collect() {
+    HEAP.with(|heap| heap.borrow_mut().collect(&[]));
+}
+
+pub fn live_objects() -> usize {
+    HEAP.with(|heap| heap.borrow().objects.len())
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_gc_root(slot: *mut Word) {
+    HEAP.with(|heap| heap.borrow_mut().roots.push(slot));
+}
+
+// Returns the current shadow stack depth, which the caller hands back to
+// `rlisp_gc_leave` to drop the roots it registered.
+#[no_mangle]
+pub extern "C" fn rlisp_gc_enter() -> Word {
+    HEAP.with(|heap| heap.borrow().roots.len() as Word)
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_gc_leave(depth: Word) {
+    HEAP.with(|heap| heap.borrow_mut().roots.truncate(depth as usize));
+}
@@ -2,10 +2,18 @@ pub mod parser;
 pub mod evaluator;
 pub mod printer;
 pub mod node;
+pub mod symbol;
 pub mod env;
 pub mod primitives;
 pub mod error;
+#[cfg(feature = "llvm")]
 pub mod codegen;
+pub mod runtime;
+pub mod gc;
+pub mod ccodegen;
+pub mod bytecode;
+pub mod resolver;
+pub mod expander;
 
 use std::rc::Rc;
 use node::{Node, Prim, prim};
@@ -16,26 +24,145 @@ fn register_symbols(env: &mut Env<Node>) {
     env.register("+", prim(Prim::Proc(Rc::new(primitives::prim_add))));
     env.register("-", prim(Prim::Proc(Rc::new(primitives::prim_sub))));
     env.register("*", prim(Prim::Proc(Rc::new(primitives::prim_mul))));
+    env.register("/", prim(Prim::Proc(Rc::new(primitives::prim_div))));
     env.register("=", prim(Prim::Proc(Rc::new(primitives::prim_eq))));
-    env.register("define", prim(Prim::Proc(Rc::new(primitives::prim_define))));
-    env.register("progn", prim(Prim::Proc(Rc::new(primitives::prim_progn))));
-    env.register("quote", prim(Prim::Proc(Rc::new(primitives::prim_quote))));
-    env.register("if", prim(Prim::Proc(Rc::new(primitives::prim_if))));
-    env.register("lambda", prim(Prim::Proc(Rc::new(primitives::prim_lambda))));
-    env.register("let", prim(Prim::Proc(Rc::new(primitives::prim_let))));
+    env.register("<", prim(Prim::Proc(Rc::new(primitives::prim_lt))));
+    env.register(">", prim(Prim::Proc(Rc::new(primitives::prim_gt))));
+    env.register("<=", prim(Prim::Proc(Rc::new(primitives::prim_lte))));
+    env.register(">=", prim(Prim::Proc(Rc::new(primitives::prim_gte))));
+    env.register("cons", prim(Prim::Proc(Rc::new(primitives::prim_cons))));
+    env.register("car", prim(Prim::Proc(Rc::new(primitives::prim_car))));
+    env.register("cdr", prim(Prim::Proc(Rc::new(primitives::prim_cdr))));
+    env.register("null?", prim(Prim::Proc(Rc::new(primitives::prim_nullp))));
+    env.register("define", prim(Prim::Special(Rc::new(primitives::prim_define))));
+    env.register("progn", prim(Prim::Special(Rc::new(primitives::prim_progn))));
+    env.register("quote", prim(Prim::Special(Rc::new(primitives::prim_quote))));
+    env.register("quasiquote", prim(Prim::Special(Rc::new(primitives::prim_quasiquote))));
+    env.register("if", prim(Prim::Special(Rc::new(primitives::prim_if))));
+    env.register("lambda", prim(Prim::Special(Rc::new(primitives::prim_lambda))));
+    env.register("let", prim(Prim::Special(Rc::new(primitives::prim_let))));
+    env.register("let*", prim(Prim::Special(Rc::new(primitives::prim_let_star))));
+    env.register("defmacro", prim(Prim::Special(Rc::new(primitives::prim_defmacro))));
+    env.register("cond", prim(Prim::Special(Rc::new(primitives::prim_cond))));
+    env.register("case", prim(Prim::Special(Rc::new(primitives::prim_case))));
+    env.register("and", prim(Prim::Special(Rc::new(primitives::prim_and))));
+    env.register("or", prim(Prim::Special(Rc::new(primitives::prim_or))));
+    env.register("when", prim(Prim::Special(Rc::new(primitives::prim_when))));
+    env.register("unless", prim(Prim::Special(Rc::new(primitives::prim_unless))));
+    env.register("not", prim(Prim::Proc(Rc::new(primitives::prim_not))));
+    env.register("do", prim(Prim::Special(Rc::new(primitives::prim_do))));
+    env.register("while", prim(Prim::Special(Rc::new(primitives::prim_while))));
+    env.register("call/cc", prim(Prim::Control(Rc::new(primitives::prim_call_cc))));
+    env.register("call-with-current-continuation",
+                 prim(Prim::Control(Rc::new(primitives::prim_call_cc))));
+    env.register("call/ec", prim(Prim::Control(Rc::new(primitives::prim_call_ec))));
+    env.register("catch", prim(Prim::Special(Rc::new(primitives::prim_catch))));
+    env.register("throw", prim(Prim::Proc(Rc::new(primitives::prim_throw))));
+    env.register("unwind-protect",
+                 prim(Prim::Special(Rc::new(primitives::prim_unwind_protect))));
+    env.register("dynamic-wind", prim(Prim::Control(Rc::new(primitives::prim_dynamic_wind))));
+    env.register("with-exception-handler",
+                 prim(Prim::Control(Rc::new(primitives::prim_with_exception_handler))));
+    env.register("raise", prim(Prim::Proc(Rc::new(primitives::prim_raise))));
+    env.register("raise-continuable",
+                 prim(Prim::Control(Rc::new(primitives::prim_raise_continuable))));
+    env.register("error", prim(Prim::Proc(Rc::new(primitives::prim_error))));
+    env.register("guard", prim(Prim::Special(Rc::new(primitives::prim_guard))));
+    env.register("error-object?", prim(Prim::Proc(Rc::new(primitives::prim_error_object_p))));
+    env.register("error-object-message",
+                 prim(Prim::Proc(Rc::new(primitives::prim_error_object_message))));
+    env.register("error-object-irritants",
+                 prim(Prim::Proc(Rc::new(primitives::prim_error_object_irritants))));
+    env.register("macroexpand-1", prim(Prim::Proc(Rc::new(primitives::prim_macroexpand_1))));
+    env.register("macroexpand", prim(Prim::Proc(Rc::new(primitives::prim_macroexpand))));
+    env.register("macroexpand-all",
+                 prim(Prim::Proc(Rc::new(primitives::prim_macroexpand_all))));
 }
 
 fn init(env: &mut Env<Node>) {
     register_symbols(env);
 }
 
+#[cfg(feature = "llvm")]
 pub fn run<T: Into<String>>(input: T) -> RResult<Node, RLispError> {
+    run_with_opt_level(input, codegen::OptLevel::O0)
+}
+
+#[cfg(feature = "llvm")]
+pub fn run_with_opt_level<T: Into<String>>(input: T,
+                                           level: codegen::OptLevel)
+                                           -> RResult<Node, RLispError> {
+    let vm = &mut codegen::VM::new();
+    vm.set_opt_level(level);
+    run_with_vm(input, vm)
+}
+
+#[cfg(feature = "llvm")]
+// Compiles with a `vm` the caller has set up (target, optimization level),
+// which is left holding the module afterwards.
+pub fn run_with_vm<T: Into<String>>(input: T, vm: &mut codegen::VM) -> RResult<Node, RLispError> {
+    let ast = try!(parser::parse(input).map_err(|v| RLispError::ParseError(v)));
+    execute(vm, &ast)
+}
+
+// Evaluates `input` with the interpreter only.
+pub fn interpret<T: Into<String>>(input: T) -> RResult<Node, RLispError> {
+    let ast = try!(parser::parse(input).map_err(|v| RLispError::ParseError(v)));
+    interpret_ast(&ast)
+}
+
+// Evaluates `input` with `bytecode::Machine`, which gives the same results as
+// `interpret`, faster.
+pub fn interpret_bytecode<T: Into<String>>(input: T) -> RResult<Node, RLispError> {
+    let ast = try!(parser::parse(input).map_err(|v| RLispError::ParseError(v)));
     let renv = &mut env::Env::new();
     init(renv);
+    let ast = try!(expander::expand(renv, &ast).map_err(|v| RLispError::EvalError(v)));
+    bytecode::Machine::new(renv.clone()).eval(&ast).map_err(|v| RLispError::EvalError(v))
+}
 
-    let ast = try!(parser::parse(input).map_err(|v| RLispError::ParseError(v)));
+// An interpreter session, where what one input defines is seen by the next.
+pub struct Interpreter {
+    env: Env<Node>,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let mut env = Env::new();
+        init(&mut env);
+        Interpreter { env: env }
+    }
+
+    pub fn eval<T: Into<String>>(&mut self, input: T) -> RResult<Node, RLispError> {
+        let ast = try!(parser::parse(input).map_err(|v| RLispError::ParseError(v)));
+        let ast = try!(expander::expand(&mut self.env, &ast).map_err(|v| RLispError::EvalError(v)));
+        interpret_expanded(&mut self.env, &ast)
+    }
 
-    codegen::VM::new().run(&ast);
+    pub fn env(&mut self) -> &mut Env<Node> {
+        &mut self.env
+    }
+}
+
+fn interpret_ast(ast: &Node) -> RResult<Node, RLispError> {
+    let renv = &mut env::Env::new();
+    init(renv);
+    let ast = try!(expander::expand(renv, ast).map_err(|v| RLispError::EvalError(v)));
+    interpret_expanded(renv, &ast)
+}
 
+fn interpret_expanded(renv: &mut Env<Node>, ast: &Node) -> RResult<Node, RLispError> {
+    let ast = resolver::resolve(renv, ast);
     evaluator::eval(renv, &ast).map_err(|v| RLispError::EvalError(v))
 }
+
+#[cfg(feature = "llvm")]
+fn execute(vm: &mut codegen::VM, ast: &Node) -> RResult<Node, RLispError> {
+    let renv = &mut env::Env::new();
+    init(renv);
+    let ast = try!(expander::expand(renv, ast).map_err(|v| RLispError::EvalError(v)));
+    // The compiler handles only a subset of the language, so the interpreter
+    // below still produces the result when compilation is not supported.
+    let _ = vm.run(&ast);
+    interpret_expanded(renv, &ast)
+}
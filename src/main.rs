@@ -1,15 +1,187 @@
 extern crate rlisp;
 
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use rlisp::ccodegen;
+#[cfg(feature = "llvm")]
+use rlisp::codegen::{OptLevel, Target, VM};
+use rlisp::node::Node;
+use rlisp::error::{RResult, RLispError};
+use rlisp::parser::parse;
+use rlisp::printer::pretty;
+
+// Writes out.c and the rlisp.h it includes, for `cc -std=c99 out.c`.
+fn write_c(source: &str) -> Result<(), String> {
+    let ast = try!(parse(source).map_err(|e| e.to_string()));
+    let c = try!(ccodegen::compile(&ast).map_err(|e| e.to_string()));
+    try!(File::create("out.c")
+        .and_then(|mut f| f.write_all(c.as_bytes()))
+        .map_err(|e| e.to_string()));
+    File::create("rlisp.h")
+        .and_then(|mut f| f.write_all(ccodegen::RUNTIME_HEADER.as_bytes()))
+        .map_err(|e| e.to_string())
+}
+
+// Reads one input per line. `:expand FORM` and `:expand-1 FORM` print what
+// `macroexpand-all` and `macroexpand-1` make of `FORM` instead of running it.
+fn repl() {
+    let session = &mut rlisp::Interpreter::new();
+    let stdin = io::stdin();
+    print!("> ");
+    let _ = io::stdout().flush();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let line = line.trim();
+        let input = if line.starts_with(":expand-1 ") {
+            format!("(macroexpand-1 '{})", &line[":expand-1 ".len()..])
+        } else if line.starts_with(":expand ") {
+            format!("(macroexpand-all '{})", &line[":expand ".len()..])
+        } else {
+            line.to_string()
+        };
+        if !input.is_empty() {
+            match session.eval(input) {
+                Ok(result) => println!("{}", pretty(&result)),
+                Err(e) => println!("{}", e),
+            }
+        }
+        print!("> ");
+        let _ = io::stdout().flush();
+    }
+    println!("");
+}
+
+fn print(ret: RResult<Node, RLispError>) {
+    match ret {
+        Ok(result) => rlisp::printer::lprint(result),
+        Err(v) => println!("{:?}", v),
+    }
+}
+
+// What the LLVM backend is asked for on the command line.
+#[cfg(feature = "llvm")]
+struct Native {
+    level: OptLevel,
+    target: Option<Target>,
+    emit_object: bool,
+}
+
+#[cfg(feature = "llvm")]
+impl Native {
+    fn new() -> Native {
+        Native {
+            level: OptLevel::O0,
+            target: None,
+            emit_object: false,
+        }
+    }
+
+    fn parse_flag(&mut self, arg: &str) -> bool {
+        if let Some(l) = OptLevel::from_flag(arg) {
+            self.level = l;
+            return true;
+        }
+        let mut t = self.target.clone().unwrap_or_else(Target::native);
+        if t.parse_flag(arg) {
+            self.target = Some(t);
+            return true;
+        }
+        if arg == "-c" {
+            self.emit_object = true;
+            return true;
+        }
+        false
+    }
+
+    fn run(&self, source: &str) {
+        let vm = &mut VM::new();
+        vm.set_opt_level(self.level);
+        if let Some(ref t) = self.target {
+            if let Err(e) = vm.set_target(t) {
+                return println!("{}", e);
+            }
+        }
+        print(rlisp::run_with_vm(source, vm));
+        if self.emit_object {
+            if let Err(e) = vm.emit_object("out.o") {
+                println!("{}", e);
+            }
+        }
+    }
+}
+
+// Without the `llvm` feature programs are only interpreted.
+#[cfg(not(feature = "llvm"))]
+struct Native;
+
+#[cfg(not(feature = "llvm"))]
+impl Native {
+    fn new() -> Native {
+        Native
+    }
+
+    fn parse_flag(&mut self, _: &str) -> bool {
+        false
+    }
+
+    fn run(&self, source: &str) {
+        print(rlisp::interpret(source))
+    }
+}
+
 fn main() {
+    let mut native = Native::new();
+    let mut emit_c = false;
+    let mut bytecode = false;
+    let mut input = None;
+    for arg in env::args().skip(1) {
+        if native.parse_flag(&arg) {
+            continue;
+        }
+        match arg.as_ref() {
+            "--emit-c" => emit_c = true,
+            "--bytecode" => bytecode = true,
+            "--repl" => return repl(),
+            // No DWARF yet, see README.md.
+            "-g" => return println!("-g: debug info is not supported"),
+            _ => input = Some(arg),
+        }
+    }
+
     // let expr = "(progn (define x (+ 1 2)) (+ x 2))";
     // let expr = "(let ((x 10)
     //               (f (lambda (x) (+ x 10))))
     //             (f x))";
     // let expr = "(let ((a 10)) (+ 10 a))";
     let expr = "((lambda (f1 f2) (f2 (f1 10) (f1 20))) (lambda (x) x) (lambda (x y) (+ x y)))";
+    let input = input.unwrap_or(expr.to_string());
 
-    match rlisp::run(expr) {
-        Ok(result) => rlisp::printer::lprint(result),
-        Err(v) => println!("{:?}", v),
+    // An argument naming a file is read as the program.
+    let source = if Path::new(&input).is_file() {
+        let mut source = String::new();
+        if let Err(e) = File::open(&input).and_then(|mut f| f.read_to_string(&mut source)) {
+            return println!("{}: {}", input, e);
+        }
+        source
+    } else {
+        input
+    };
+
+    // --bytecode evaluates with `rlisp::bytecode` and compiles nothing.
+    if bytecode {
+        print(rlisp::interpret_bytecode(source.clone()));
+    } else {
+        native.run(&source);
+    }
+
+    if emit_c {
+        if let Err(e) = write_c(&source) {
+            println!("{}", e);
+        }
     }
 }
@@ -1,23 +1,55 @@
 use std::fmt;
 use std::rc::Rc;
 use env::Env;
+use symbol::Symbol;
+use bytecode;
+use expander;
 use error::EvalError;
-use evaluator::EvalResult;
+use evaluator;
+use evaluator::{EvalResult, Step};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Node {
     Int(i32),
-    Sym(String),
+    Sym(Symbol),
     Prim(Prim),
     Bool(Bool),
     Cell(Rc<Node>, Rc<Node>),
     Nil,
+    // Variable references `resolver` has found a place for, frames out and
+    // index in the frame or a global slot (see `Env`).
+    Local(usize, usize),
+    Global(usize),
+    // What a handler or a `guard` gets for an error, including one made by
+    // `error`.
+    Condition(Rc<EvalError>),
 }
 
 #[derive(Clone)]
 pub enum Prim {
+    // Called with the list of its evaluated arguments.
     Proc(Rc<Fn(&mut Env<Node>, &Node) -> EvalResult<Node>>),
+    // `if`, `define` and the like, which can only be called by name and
+    // can't be redefined. Called with the unevaluated arguments, and leave
+    // evaluating them to `evaluator::eval`.
+    Special(Rc<Fn(&mut Env<Node>, &Node) -> EvalResult<Step>>),
+    // A procedure that leaves calling another one, like `call/cc` does, to
+    // whatever called it.
+    Control(Rc<Fn(&mut Env<Node>, &Node) -> EvalResult<Step>>),
     Lambda(Env<Node>, Rc<Node>, Rc<Node>),
+    // Made by `defmacro`: applied to the unevaluated arguments of a call,
+    // and what it returns is evaluated in place of the call.
+    Macro(Env<Node>, Rc<Node>, Rc<Node>),
+    // A `syntax-rules` macro, which `expander` has already expanded every
+    // call of.
+    Syntax(Rc<expander::Macro>),
+    // A lambda created by `bytecode::Machine`.
+    Closure(Rc<bytecode::Closure>),
+    // Made by `call/cc`: called with a value, it goes back to returning
+    // that from the `call/cc`.
+    Continuation(Rc<evaluator::Continuation>),
+    // A continuation captured by `bytecode::Machine`.
+    Resume(Rc<bytecode::Continuation>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -63,6 +95,14 @@ pub fn car_ref(cell: &Node) -> EvalResult<&Node> {
     }
 }
 
+pub fn cdr_ref(cell: &Node) -> EvalResult<&Node> {
+    if let &Node::Cell(_, ref cdr) = cell {
+        Ok(cdr)
+    } else {
+        Err(EvalError::WrongTypeArg)
+    }
+}
+
 pub fn rcdr(cell: &Node) -> EvalResult<Node> {
     if let Node::Cell(_, ref cdr) = *cell {
         Ok((**cdr).clone())
@@ -71,9 +111,44 @@ pub fn rcdr(cell: &Node) -> EvalResult<Node> {
     }
 }
 
+// `(name x)` for the quoting forms the reader makes of `'x`, `` `x ``, `,x`
+// and `,@x`.
+pub fn quote_form(node: &Node) -> Option<(&'static str, &Node)> {
+    if let Node::Cell(ref car, ref cdr) = *node {
+        if let (&Node::Sym(name), &Node::Cell(ref x, ref end)) = (&**car, &**cdr) {
+            let name = name.as_str();
+            match name {
+                "quote" | "quasiquote" | "unquote" | "unquote-splicing" if **end == Node::Nil => {
+                    return Some((name, x))
+                }
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+// A copy of the proper list `list` with `tail` as the cdr of its last cell.
+pub fn rappend(list: &Node, tail: Node) -> EvalResult<Node> {
+    match *list {
+        Node::Cell(ref car, ref cdr) => Ok(rcell((**car).clone(), try!(rappend(cdr, tail)))),
+        Node::Nil => Ok(tail),
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+// Whether `node` is the symbol `name`, for the keywords of a form like
+// `else` in `cond`.
+pub fn is_sym(node: &Node, name: &str) -> bool {
+    match *node {
+        Node::Sym(s) => s == name,
+        _ => false,
+    }
+}
+
 pub fn sym_to_str(sym: &Node) -> EvalResult<&str> {
-    if let &Node::Sym(ref name) = sym {
-        Ok(name)
+    if let &Node::Sym(name) = sym {
+        Ok(name.as_str())
     } else {
         Err(EvalError::WrongTypeArg)
     }
@@ -107,11 +182,11 @@ pub fn rlist(car: Node, cdr: Node) -> Node {
 }
 
 pub fn rquote(v: Node) -> Node {
-    rcell(Node::Sym("quote".to_string()), rcell(v, Node::Nil))
+    rcell(rsym("quote"), rcell(v, Node::Nil))
 }
 
 pub fn rsym<T: Into<String>>(s: T) -> Node {
-    Node::Sym(s.into())
+    Node::Sym(Symbol::from(s.into()))
 }
 
 pub fn prim(v: Prim) -> Node {
@@ -23,8 +23,7 @@ impl<'a> Lexer<'a> {
 
     pub fn next_no_whitespace(&mut self) -> Option<char> {
         self.comsume_whitespace();
-        self.pos += 1;
-        self.input.next()
+        self.next()
     }
 
     pub fn peek(&mut self) -> Option<char> {
@@ -38,17 +37,27 @@ impl<'a> Lexer<'a> {
 
     fn comsume_whitespace(&mut self) {
         while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
-            self.pos += 1;
-            self.input.next();
+            self.next();
         }
     }
 }
 
 pub type ParseResult = RResult<Node, ParseError>;
 
-fn read_quote(lexer: &mut Lexer) -> ParseResult {
+// `'x`, `` `x ``, `,x` and `,@x` read as `(quote x)`, `(quasiquote x)`,
+// `(unquote x)` and `(unquote-splicing x)`.
+fn read_quote(lexer: &mut Lexer, name: &str) -> ParseResult {
     let v = try!(read(lexer));
-    Ok(node::rcell(Node::Sym("quote".to_string()), node::rcell(v, Node::Nil)))
+    Ok(node::rcell(node::rsym(name), node::rcell(v, Node::Nil)))
+}
+
+fn read_unquote(lexer: &mut Lexer) -> ParseResult {
+    if lexer.peek() == Some('@') {
+        lexer.next();
+        read_quote(lexer, "unquote-splicing")
+    } else {
+        read_quote(lexer, "unquote")
+    }
 }
 
 fn read_list(lexer: &mut Lexer) -> ParseResult {
@@ -79,7 +88,9 @@ fn read_int(c: char, lexer: &mut Lexer) -> ParseResult {
         lexer.next();
     }
 
-    Ok(Node::Int(i32::from_str_radix(&v, radix).unwrap()))
+    i32::from_str_radix(&v, radix)
+        .map(Node::Int)
+        .map_err(|_| ParseError::OutOfRange(lexer.pos))
 }
 
 fn read_number(lexer: &mut Lexer, c: char) -> ParseResult {
@@ -98,12 +109,13 @@ fn read_symbol(lexer: &mut Lexer, c: char) -> ParseResult {
         lexer.next();
     }
 
-    Ok(Node::Sym(v.to_owned()))
+    Ok(node::rsym(v.as_str()))
 }
 
 fn is_ident(c: char) -> bool {
     match c {
-        'a'...'z' | 'A'...'Z' | '0'...'9' | '=' | '<' | '>' | '+' | '-' | '/' | '%' => true,
+        'a'...'z' | 'A'...'Z' | '0'...'9' => true,
+        '=' | '<' | '>' | '+' | '-' | '/' | '%' | '*' | '?' | '!' | '&' | '.' | '_' => true,
         _ => false,
     }
 }
@@ -123,9 +135,14 @@ fn read(lexer: &mut Lexer) -> ParseResult {
         Some(c) => {
             match c {
                 '(' => read_list(lexer),
-                '\'' => read_quote(lexer),
+                '\'' => read_quote(lexer, "quote"),
+                '`' => read_quote(lexer, "quasiquote"),
+                ',' => read_unquote(lexer),
                 '#' => read_hash_symbol(lexer),
                 '0'...'9' => read_number(lexer, c),
+                '-' if lexer.peek().map(|n| n.is_digit(10)).unwrap_or(false) => {
+                    read_number(lexer, c)
+                }
                 _ => read_symbol(lexer, c),
             }
         }
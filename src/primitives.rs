@@ -1,69 +1,464 @@
 use std::rc::Rc;
-use node::{Prim, Node, Bool, rint, rcar, rcdar, rcddar, rcdr, rsym, rcell, rquote, rbool};
+use node::{Prim, Node, Bool, rint, rcar, rcdar, rcddar, rcdr, rsym, rcell, rlist, rquote, rbool,
+           rappend, car_ref, cdr_ref, quote_form, is_sym, sym_to_str};
 use env::Env;
+use symbol::Symbol;
 use evaluator::*;
+use expander::Expander;
 use error::EvalError;
 
-pub fn prim_let(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let let_args = try!(rcar(args));
-    let body = try!(rcdar(args));
-    let (aargs, vargs) = transform(&let_args);
-    let lambda = Node::Prim(Prim::Lambda(renv.clone(), Rc::new(vargs), Rc::new(body)));
-    eval(renv, &rcell(rquote(lambda), aargs))
+pub fn prim_let(renv: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    if let Node::Sym(name) = *try!(car_ref(args)) {
+        return named_let(renv, name, try!(cdr_ref(args)));
+    }
+    let (aargs, vargs) = try!(car_ref(args).and_then(|b| transform("let", b)));
+    let body = rcell(rsym("progn"), try!(rcdr(args)));
+    let lambda = Node::Prim(Prim::Lambda(renv.capture(), Rc::new(vargs), Rc::new(body)));
+    Ok(Step::Eval(rcell(rquote(lambda), aargs)))
+}
+
+// `(let name ((var init)...) body...)` calls a procedure `name`, which the
+// body can call again, with the inits.
+fn named_let(renv: &mut Env<Node>, name: Symbol, args: &Node) -> EvalResult<Step> {
+    let (aargs, vargs) = try!(car_ref(args).and_then(|b| transform("let", b)));
+    let body = rcell(rsym("progn"), try!(rcdr(args)));
+    let f = named_let_proc(renv.capture(), name, Rc::new(vargs), Rc::new(body));
+    Ok(Step::Eval(rcell(rquote(f), aargs)))
+}
+
+// A lambda can't see itself in the environment it copies, so each call makes
+// a new one whose environment has a frame for `name` outside the parameters.
+fn named_let_proc(env: Env<Node>, name: Symbol, params: Rc<Node>, body: Rc<Node>) -> Node {
+    let f = move |_: &mut Env<Node>, args: &Node| -> EvalResult<Step> {
+        let inner = &mut env.clone();
+        inner.push_local_scope();
+        inner.register(name, named_let_proc(env.clone(), name, params.clone(), body.clone()));
+        let lambda = Node::Prim(Prim::Lambda(inner.capture(), params.clone(), body.clone()));
+        Ok(Step::Apply(lambda, args.clone()))
+    };
+    Node::Prim(Prim::Control(Rc::new(f)))
+}
+
+// The values and the names of the bindings of `form`.
+fn transform(form: &str, node: &Node) -> EvalResult<(Node, Node)> {
+    let bindings = try!(let_bindings(form, node));
+    Ok(bindings.iter().rev().fold((Node::Nil, Node::Nil), |(values, names), &(name, value)| {
+        (rcell(value.clone(), values), rcell(Node::Sym(name), names))
+    }))
 }
 
-fn transform(node: &Node) -> (Node, Node) {
-    match node {
-        &Node::Cell(ref v, ref r) => {
-            let nv = rcar(v).unwrap();
-            let nr = rcdar(v).unwrap();
-            let (ar, vr) = transform(r);
-            (rcell(nr, ar), rcell(nv, vr))
+// Each binding must be `(name value)`.
+fn let_bindings<'a>(form: &str, node: &'a Node) -> EvalResult<Vec<(Symbol, &'a Node)>> {
+    let invalid = || EvalError::InvalidSyntax(form.to_string());
+    let mut ret = Vec::new();
+    for b in try!(elements(node).map_err(|_| invalid())) {
+        match *b {
+            Node::Cell(ref name, ref rest) => {
+                match (&**name, &**rest) {
+                    (&Node::Sym(name), &Node::Cell(ref value, ref end)) if **end == Node::Nil => {
+                        ret.push((name, &**value))
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(invalid()),
         }
-        _ => (Node::Nil, Node::Nil),
     }
+    Ok(ret)
+}
+
+// The values of `let*` are evaluated in order, each with the variables
+// before it bound, in one frame.
+pub fn prim_let_star(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let bindings = try!(car_ref(args).and_then(|b| let_bindings("let*", b)));
+    let bindings = bindings.into_iter().map(|(name, value)| (name, value.clone())).collect();
+    let body = try!(rcdr(args));
+    Ok(Step::Scope(Vec::new(), Box::new(let_star_body(Rc::new(bindings), 0, body))))
+}
+
+fn let_star_body(bindings: Rc<Vec<(Symbol, Node)>>, i: usize, body: Node) -> Step {
+    let value = match bindings.get(i) {
+        Some(&(_, ref value)) => value.clone(),
+        None => return progn(&body, Node::Nil),
+    };
+    Step::then(value, move |renv, v| {
+        renv.register(bindings[i].0, v);
+        Ok(let_star_body(bindings.clone(), i + 1, body.clone()))
+    })
 }
 
-pub fn prim_lambda(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+pub fn prim_lambda(renv: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
     let lambda_args = try!(rcar(args));
     let body = rcell(rsym("progn"), try!(rcdr(args)));
 
-    Ok(Node::Prim(Prim::Lambda(renv.clone(), Rc::new(lambda_args), Rc::new(body))))
+    Ok(Step::Value(Node::Prim(Prim::Lambda(renv.capture(), Rc::new(lambda_args), Rc::new(body)))))
+}
+
+pub fn prim_if(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let cond = try!(rcar(args));
+    let args = args.clone();
+    Ok(Step::then(cond, move |_, cond| {
+        let clause = if cond == Node::Bool(Bool::False) {
+            rcddar(&args)
+        } else {
+            rcdar(&args)
+        };
+        clause.map(Step::Eval)
+    }))
 }
 
-pub fn prim_if(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let cond = try!(rcar(args).and_then(|ref v| eval(renv, v)));
-    let clause = if cond == Node::Bool(Bool::False) {
-        rcddar(args)
-    } else {
-        rcdar(args)
+// Only `#f` is false, as in `prim_if`.
+fn is_true(v: &Node) -> bool {
+    *v != Node::Bool(Bool::False)
+}
+
+// The elements of the proper list `node`.
+fn elements(mut node: &Node) -> EvalResult<Vec<&Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push(&**car);
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Ok(ret),
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+pub fn prim_and(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    try!(elements(args));
+    Ok(and_or(args, true))
+}
+
+pub fn prim_or(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    try!(elements(args));
+    Ok(and_or(args, false))
+}
+
+// `and` stops at the first false value and `or` at the first true one.
+fn and_or(forms: &Node, and: bool) -> Step {
+    match *forms {
+        Node::Cell(ref car, ref cdr) if **cdr == Node::Nil => Step::Eval((**car).clone()),
+        Node::Cell(ref car, ref cdr) => {
+            let rest = (**cdr).clone();
+            Step::then((**car).clone(), move |_, v| {
+                Ok(if is_true(&v) == and {
+                    and_or(&rest, and)
+                } else {
+                    Step::Value(v)
+                })
+            })
+        }
+        _ => Step::Value(rbool(and)),
+    }
+}
+
+// `when` and `unless` return `()` when the body isn't run.
+pub fn prim_when(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    when(args, true)
+}
+
+pub fn prim_unless(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    when(args, false)
+}
+
+fn when(args: &Node, run_if: bool) -> EvalResult<Step> {
+    let test = try!(rcar(args));
+    let body = try!(rcdr(args));
+    Ok(Step::then(test, move |_, v| {
+        Ok(if is_true(&v) == run_if {
+            progn(&body, Node::Nil)
+        } else {
+            Step::Value(Node::Nil)
+        })
+    }))
+}
+
+pub fn prim_not(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    single_arg(args).map(|v| rbool(!is_true(v)))
+}
+
+// A clause is `(test body...)`, `(test => receiver)`, which calls the
+// receiver with the value of the test, or `(else body...)`. A clause without
+// a body returns the value of its test, and `()` is returned when no clause
+// applies.
+pub fn prim_cond(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    try!(elements(args));
+    cond(args, Node::Nil)
+}
+
+// `otherwise` is evaluated when no clause applies.
+fn cond(clauses: &Node, otherwise: Node) -> EvalResult<Step> {
+    let (clause, rest) = match *clauses {
+        Node::Cell(ref clause, ref rest) => (clause, (**rest).clone()),
+        _ => return Ok(Step::Eval(otherwise)),
+    };
+    let (test, body) = match **clause {
+        Node::Cell(ref test, ref body) => ((**test).clone(), (**body).clone()),
+        _ => return Err(EvalError::WrongTypeArg),
     };
-    clause.and_then(|ref v| eval(renv, v))
+    if is_sym(&test, "else") {
+        return clause_body(rbool(true), &body);
+    }
+    Ok(Step::then(test, move |_, v| {
+        if is_true(&v) {
+            clause_body(v, &body)
+        } else {
+            cond(&rest, otherwise.clone())
+        }
+    }))
 }
 
-pub fn prim_progn(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    match *args {
+// `(case key ((datum...) body...) ... (else body...))`, where the first
+// clause with a datum equal to the key applies. `=>` calls a receiver with
+// the key.
+pub fn prim_case(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let key = try!(rcar(args));
+    let clauses = try!(rcdr(args));
+    Ok(Step::then(key, move |_, key| {
+        for clause in try!(elements(&clauses)) {
+            let (datums, body) = match *clause {
+                Node::Cell(ref datums, ref body) => (datums, body),
+                _ => return Err(EvalError::WrongTypeArg),
+            };
+            if is_sym(datums, "else") || try!(elements(datums)).into_iter().any(|d| *d == key) {
+                return clause_body(key, body);
+            }
+        }
+        Ok(Step::Value(Node::Nil))
+    }))
+}
+
+// The result of a `cond` or `case` clause that applies, `v` being the value
+// of its test or the key.
+fn clause_body(v: Node, body: &Node) -> EvalResult<Step> {
+    match *body {
+        Node::Nil => Ok(Step::Value(v)),
+        Node::Cell(ref car, ref cdr) if is_sym(car, "=>") => {
+            let receiver = match **cdr {
+                Node::Cell(ref f, ref end) if **end == Node::Nil => (**f).clone(),
+                _ => return Err(EvalError::WrongTypeArg),
+            };
+            Ok(Step::then(receiver, move |_, f| Ok(Step::Apply(f, rcell(v.clone(), Node::Nil)))))
+        }
+        _ => Ok(progn(body, Node::Nil)),
+    }
+}
+
+// A `do` loop, whose variables are in a frame of their own.
+struct DoLoop {
+    vars: Vec<Symbol>,
+    inits: Vec<Node>,
+    steps: Vec<Option<Node>>,
+    test: Node,
+    result: Node,
+    body: Node,
+}
+
+// `(do ((var init step)...) (test result...) body...)` runs the body and
+// then updates the variables with their steps until the test is true.
+pub fn prim_do(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let mut vars = Vec::new();
+    let mut inits = Vec::new();
+    let mut steps = Vec::new();
+    for spec in try!(car_ref(args).and_then(elements)) {
+        let spec = try!(elements(spec));
+        match (spec.first(), spec.len()) {
+            (Some(&&Node::Sym(var)), 2) |
+            (Some(&&Node::Sym(var)), 3) => {
+                vars.push(var);
+                inits.push(spec[1].clone());
+                steps.push(spec.get(2).map(|&s| s.clone()));
+            }
+            _ => return Err(EvalError::WrongTypeArg),
+        }
+    }
+    let (test, result) = match *try!(cdr_ref(args).and_then(car_ref)) {
+        Node::Cell(ref test, ref result) => ((**test).clone(), (**result).clone()),
+        _ => return Err(EvalError::WrongTypeArg),
+    };
+    let body = try!(cdr_ref(args).and_then(cdr_ref));
+
+    let lp = DoLoop {
+        vars: vars,
+        inits: inits,
+        steps: steps,
+        test: test,
+        result: result,
+        body: rcell(rsym("progn"), body.clone()),
+    };
+    Ok(do_inits(Rc::new(lp), Vec::new()))
+}
+
+// The inits are evaluated in the enclosing frame, before the loop's exists.
+fn do_inits(lp: Rc<DoLoop>, values: Vec<(Symbol, Node)>) -> Step {
+    let init = match lp.inits.get(values.len()) {
+        Some(init) => init.clone(),
+        None => return Step::Scope(values, Box::new(do_loop(lp))),
+    };
+    Step::then(init, move |_, v| {
+        let mut values = values.clone();
+        let var = lp.vars[values.len()];
+        values.push((var, v));
+        Ok(do_inits(lp.clone(), values))
+    })
+}
+
+fn do_loop(lp: Rc<DoLoop>) -> Step {
+    Step::then(lp.test.clone(), move |_, v| {
+        if is_true(&v) {
+            return Ok(progn(&lp.result, Node::Nil));
+        }
+        let lp = lp.clone();
+        Ok(Step::then(lp.body.clone(), move |_, _| Ok(do_steps(lp.clone(), 0, Vec::new()))))
+    })
+}
+
+// Evaluates the steps from the `from`th variable on, and then updates the
+// variables in place, so every step sees the values before any is updated.
+fn do_steps(lp: Rc<DoLoop>, from: usize, values: Vec<(Symbol, Node)>) -> Step {
+    let i = match (from..lp.vars.len()).find(|&i| lp.steps[i].is_some()) {
+        Some(i) => i,
+        None => return do_loop(lp),
+    };
+    let step = lp.steps[i].clone().unwrap();
+    Step::then(step, move |renv, v| {
+        let mut values = values.clone();
+        values.push((lp.vars[i], v));
+        if lp.steps[i + 1..].iter().any(Option::is_some) {
+            return Ok(do_steps(lp.clone(), i + 1, values));
+        }
+        for (var, v) in values {
+            renv.register(var, v);
+        }
+        Ok(do_loop(lp.clone()))
+    })
+}
+
+// `(while test body...)` returns `()`.
+pub fn prim_while(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let test = try!(rcar(args));
+    let body = rcell(rsym("progn"), try!(rcdr(args)));
+    Ok(while_loop(test, body))
+}
+
+fn while_loop(test: Node, body: Node) -> Step {
+    Step::then(test.clone(), move |_, v| {
+        if !is_true(&v) {
+            return Ok(Step::Value(Node::Nil));
+        }
+        let (test, body) = (test.clone(), body.clone());
+        Ok(Step::then(body.clone(), move |_, _| Ok(while_loop(test.clone(), body.clone()))))
+    })
+}
+
+pub fn prim_progn(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    Ok(progn(args, Node::Nil))
+}
+
+// The value of the last of `forms` that isn't `()`, or `last` if none is.
+// Only a form whose value is the result either way is evaluated as a tail
+// call.
+fn progn(forms: &Node, last: Node) -> Step {
+    match *forms {
+        Node::Cell(ref car, ref cdr) if **cdr == Node::Nil && last == Node::Nil => {
+            Step::Eval((**car).clone())
+        }
+        Node::Cell(ref car, ref cdr) => {
+            let rest = (**cdr).clone();
+            Step::then((**car).clone(), move |_, v| {
+                Ok(progn(&rest, if v == Node::Nil { last.clone() } else { v }))
+            })
+        }
+        _ => Step::Value(last),
+    }
+}
+
+pub fn prim_quote(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    rcar(args).map(Step::Value)
+}
+
+pub fn prim_quasiquote(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    quasiquote(try!(car_ref(args)), 0).map(Step::Eval)
+}
+
+// A form that builds `template` with `cons`, evaluating what is unquoted.
+// `depth` counts the `quasiquote`s inside the one being evaluated, each of
+// which needs one more `unquote` before anything is evaluated.
+fn quasiquote(template: &Node, depth: usize) -> EvalResult<Node> {
+    match quote_form(template) {
+        Some(("unquote", x)) if depth == 0 => return Ok(x.clone()),
+        Some(("unquote-splicing", _)) if depth == 0 => return Err(EvalError::WrongTypeArg),
+        Some((name, x)) if name != "quote" => {
+            let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+            let x = try!(quasiquote(x, depth));
+            return Ok(call(prim_cons, rquote(rsym(name)), call(prim_cons, x, rquote(Node::Nil))));
+        }
+        _ => (),
+    }
+    match *template {
         Node::Cell(ref car, ref cdr) => {
-            let ret = try!(eval(renv, car));
-            let rest = try!(prim_progn(renv, cdr));
-            Ok(if rest == Node::Nil { ret } else { rest })
+            if let (Some(("unquote-splicing", x)), 0) = (quote_form(car), depth) {
+                return Ok(call(append, x.clone(), try!(quasiquote(cdr, depth))));
+            }
+            Ok(call(prim_cons, try!(quasiquote(car, depth)), try!(quasiquote(cdr, depth))))
         }
-        ref x => Ok(x.clone()),
+        _ => Ok(rquote(template.clone())),
+    }
+}
+
+// A call of `f` with two arguments, which doesn't depend on what any name
+// means where it is evaluated.
+fn call(f: fn(&mut Env<Node>, &Node) -> EvalResult<Node>, a: Node, b: Node) -> Node {
+    rcell(rquote(Node::Prim(Prim::Proc(Rc::new(f)))), rlist(a, b))
+}
+
+fn append(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    rappend(try!(car_ref(args)), try!(rcdar(args)))
+}
+
+pub fn prim_defmacro(renv: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let name = try!(car_ref(args).and_then(sym_to_str));
+    if renv.find(name).as_ref().map_or(false, is_special_form) {
+        return Err(EvalError::SpecialFormRebind(name.to_string()));
     }
+    let params = try!(rcdar(args));
+    let body = rcell(rsym("progn"), try!(cdr_ref(args).and_then(cdr_ref)).clone());
+    let mac = Node::Prim(Prim::Macro(renv.capture(), Rc::new(params), Rc::new(body)));
+    renv.register(name, mac);
+    Ok(Step::Value(rsym(name)))
+}
+
+// `(macroexpand-1 form)` and the like take the form as a value, usually
+// quoted, and expand macros defined at the time of the call.
+pub fn prim_macroexpand_1(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let form = try!(single_arg(args));
+    let expansion = try!(Expander::new(renv).macroexpand_1(form));
+    Ok(expansion.unwrap_or(form.clone()))
 }
 
-pub fn prim_quote(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    rcar(args)
+pub fn prim_macroexpand(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let form = try!(single_arg(args));
+    Expander::new(renv).macroexpand(form)
 }
 
-pub fn prim_define(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+pub fn prim_macroexpand_all(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let form = try!(single_arg(args));
+    Expander::new(renv).macroexpand_all(form)
+}
+
+pub fn prim_define(renv: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
     match *args {
         Node::Cell(ref car, ref cdr) => {
-            if let Node::Sym(ref s) = **car {
-                let ccdr = try!(rcar(cdr));
-                let ret = try!(eval(renv, &ccdr));
-                renv.register(s.to_string(), ret.clone());
-                return Ok(ret);
+            if let Node::Sym(s) = **car {
+                if renv.find(s).as_ref().map_or(false, is_special_form) {
+                    return Err(EvalError::SpecialFormRebind(s.to_string()));
+                }
+                let value = try!(rcar(cdr));
+                return Ok(Step::then(value, move |renv, v| {
+                    renv.register(s, v.clone());
+                    Ok(Step::Value(v))
+                }));
             }
         }
         _ => (),
@@ -71,62 +466,224 @@ pub fn prim_define(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
     Err(EvalError::E)
 }
 
-pub fn prim_eq(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ref eargs = try!(eval_list(renv, args));
-    let ref car = try!(rcar(eargs));
-    let ref cdr = try!(rcdr(eargs));
+// `(call/cc f)` calls `f` with the continuation of the call, a procedure
+// which makes the `call/cc` return its argument, as many times as it is
+// called.
+pub fn prim_call_cc(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    single_arg(args).map(|f| Step::CallCC(f.clone()))
+}
+
+// `(call/ec f)` calls `f` with an escape procedure, which makes the
+// `call/ec` return its argument, but only until it has returned.
+pub fn prim_call_ec(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let f = try!(single_arg(args));
+    let (id, k) = escape_procedure();
+    Ok(Step::Catch(Catcher::Escape(id), Box::new(Step::Apply(f.clone(), rcell(k, Node::Nil)))))
+}
+
+// `(catch tag body...)` evaluates the body, unless a `throw` to `tag` in it
+// makes it return the value thrown.
+pub fn prim_catch(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let body = try!(rcdr(args));
+    Ok(Step::then(try!(rcar(args)), move |_, tag| {
+        Ok(Step::Catch(Catcher::Tag(tag), Box::new(progn(&body, Node::Nil))))
+    }))
+}
+
+// `(unwind-protect body cleanup...)` evaluates the body and then the
+// cleanup forms, also when the body is left by an error, a `throw` or an
+// escape.
+pub fn prim_unwind_protect(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let cleanup = rcell(rsym("progn"), try!(rcdr(args)));
+    Ok(Step::Protect(cleanup, Box::new(Step::Eval(try!(rcar(args))))))
+}
+
+// `(dynamic-wind before thunk after)` calls `before`, `thunk` and `after`
+// the same way, and returns what `thunk` does.
+pub fn prim_dynamic_wind(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let v = try!(elements(args));
+    if v.len() != 3 {
+        return Err(EvalError::InvalidArgNumber);
+    }
+    Ok(Step::Wind(v[0].clone(), v[1].clone(), v[2].clone()))
+}
+
+// `(with-exception-handler handler thunk)` calls `thunk`, and `handler`
+// with what is raised in it, where it is raised.
+pub fn prim_with_exception_handler(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let v = try!(elements(args));
+    if v.len() != 2 {
+        return Err(EvalError::InvalidArgNumber);
+    }
+    Ok(Step::Handle(v[0].clone(), v[1].clone()))
+}
+
+// A handler `raise` calls mustn't return.
+pub fn prim_raise(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    single_arg(args).and_then(|v| Err(unhandled(v.clone())))
+}
+
+pub fn prim_raise_continuable(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    single_arg(args).map(|v| Step::RaiseContinuable(v.clone()))
+}
+
+// `(error message irritant...)` raises a condition of the message and the
+// irritants.
+pub fn prim_error(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    match *args {
+        Node::Cell(ref message, ref irritants) => {
+            Err(EvalError::Error((**message).clone(), (**irritants).clone()))
+        }
+        _ => Err(EvalError::InvalidArgNumber),
+    }
+}
+
+// `(guard (var clause...) body...)` evaluates the body, or if something is
+// raised in it, the clauses as `cond` does with `var` bound to it. When no
+// clause applies, it is raised again from the `guard`, continuably if it was
+// by `raise-continuable`.
+pub fn prim_guard(_: &mut Env<Node>, args: &Node) -> EvalResult<Step> {
+    let (var, clauses) = match *try!(car_ref(args)) {
+        Node::Cell(ref var, ref clauses) => (try!(sym_to_str(var)), (**clauses).clone()),
+        _ => return Err(EvalError::WrongTypeArg),
+    };
+    try!(elements(&clauses));
+    let var = Symbol::from(var);
+    let body = try!(rcdr(args));
+    // What `evaluator::guarded` made.
+    let f = move |_: &mut Env<Node>, raised: Node| -> EvalResult<Step> {
+        let again = rcell(rquote(try!(rcdr(&raised))), Node::Nil);
+        Ok(Step::Scope(vec![(var, try!(rcar(&raised)))], Box::new(try!(cond(&clauses, again)))))
+    };
+    Ok(Step::Guard(Rc::new(f), Box::new(progn(&body, Node::Nil))))
+}
+
+pub fn prim_error_object_p(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    single_arg(args).map(|v| {
+        rbool(match *v {
+            Node::Condition(_) => true,
+            _ => false,
+        })
+    })
+}
+
+pub fn prim_error_object_message(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    match *try!(single_arg(args)) {
+        Node::Condition(ref e) => Ok(e.message()),
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+pub fn prim_error_object_irritants(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    match *try!(single_arg(args)) {
+        Node::Condition(ref e) => Ok(e.irritants()),
+        _ => Err(EvalError::WrongTypeArg),
+    }
+}
+
+pub fn prim_throw(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let (tag, rest) = match *args {
+        Node::Cell(ref tag, ref rest) => (tag, rest),
+        _ => return Err(EvalError::InvalidArgNumber),
+    };
+    let v = try!(single_arg(rest));
+    Err(EvalError::Throw((**tag).clone(), v.clone()))
+}
+
+pub fn prim_cons(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    match *args {
+        Node::Cell(ref car, ref cdr) => {
+            match **cdr {
+                Node::Cell(ref cadr, ref cddr) if **cddr == Node::Nil => {
+                    Ok(rcell((**car).clone(), (**cadr).clone()))
+                }
+                _ => Err(EvalError::InvalidArgNumber),
+            }
+        }
+        _ => Err(EvalError::InvalidArgNumber),
+    }
+}
+
+pub fn prim_car(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    single_arg(args).and_then(rcar)
+}
+
+pub fn prim_cdr(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    single_arg(args).and_then(rcdr)
+}
+
+pub fn prim_nullp(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let arg = try!(single_arg(args));
+    Ok(rbool(*arg == Node::Nil))
+}
+
+fn single_arg(args: &Node) -> EvalResult<&Node> {
+    match *args {
+        Node::Cell(ref car, ref cdr) if **cdr == Node::Nil => Ok(car),
+        _ => Err(EvalError::InvalidArgNumber),
+    }
+}
+
+pub fn prim_eq(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args));
+    let ref cdr = try!(rcdr(args));
     let ref fun = |x, y| x == y;
     let ret = try!(do_cmp(fun, car, cdr));
     Ok(rbool(ret))
 }
 
-pub fn prim_lt(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ref eargs = try!(eval_list(renv, args));
-    let ref car = try!(rcar(eargs));
-    let ref cdr = try!(rcdr(eargs));
+pub fn prim_lt(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args));
+    let ref cdr = try!(rcdr(args));
     let ret = try!(do_cmp(&|x, y| x < y, car, cdr));
     Ok(rbool(ret))
 }
 
-pub fn prim_lte(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ref eargs = try!(eval_list(renv, args));
-    let ref car = try!(rcar(eargs));
-    let ref cdr = try!(rcdr(eargs));
+pub fn prim_lte(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args));
+    let ref cdr = try!(rcdr(args));
     let ret = try!(do_cmp(&|x, y| x <= y, car, cdr));
     Ok(rbool(ret))
 }
 
-pub fn prim_gt(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ref eargs = try!(eval_list(renv, args));
-    let ref car = try!(rcar(eargs));
-    let ref cdr = try!(rcdr(eargs));
+pub fn prim_gt(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args));
+    let ref cdr = try!(rcdr(args));
     let ret = try!(do_cmp(&|x, y| x > y, car, cdr));
     Ok(rbool(ret))
 }
 
-pub fn prim_gte(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ref eargs = try!(eval_list(renv, args));
-    let ref car = try!(rcar(eargs));
-    let ref cdr = try!(rcdr(eargs));
+pub fn prim_gte(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args));
+    let ref cdr = try!(rcdr(args));
     let ret = try!(do_cmp(&|x, y| x >= y, car, cdr));
     Ok(rbool(ret))
 }
 
-pub fn prim_mul(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ret = try!(eval_list(renv, args).and_then(|ref v| do_mul(v)));
-    Ok(rint(ret))
+pub fn prim_mul(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    do_mul(args).map(rint)
+}
+
+pub fn prim_div(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args).map_err(|_| EvalError::InvalidArgNumber));
+    let ref cdr = try!(rcdr(args));
+    match *cdr {
+        Node::Nil => Ok(rint(try!(do_div(&rint(1), car)))), // (/ x) is (/ 1 x)
+        _ => Ok(rint(try!(do_div(car, cdr)))),
+    }
 }
 
-pub fn prim_sub(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ref eargs = try!(eval_list(renv, args));
-    let ref car = try!(rcar(eargs));
-    let ref cdr = try!(rcdr(eargs));
-    Ok(rint(try!(do_sub(car, cdr))))
+pub fn prim_sub(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    let ref car = try!(rcar(args).map_err(|_| EvalError::InvalidArgNumber));
+    let ref cdr = try!(rcdr(args));
+    match *cdr {
+        Node::Nil => Ok(rint(try!(do_sub(&rint(0), car)))), // (- x) is (- 0 x)
+        _ => Ok(rint(try!(do_sub(car, cdr)))),
+    }
 }
 
-pub fn prim_add(renv: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
-    let ret = try!(eval_list(renv, args).and_then(|ref v| do_add(v)));
-    Ok(rint(ret))
+pub fn prim_add(_: &mut Env<Node>, args: &Node) -> EvalResult<Node> {
+    do_add(args).map(rint)
 }
 
 pub fn do_cmp<F>(f: &F, l: &Node, r: &Node) -> EvalResult<bool>
@@ -143,12 +700,7 @@ pub fn do_cmp<F>(f: &F, l: &Node, r: &Node) -> EvalResult<bool>
 }
 
 fn do_mul(lst: &Node) -> EvalResult<i32> {
-    match *lst {
-        Node::Cell(ref car, ref cdr) => Ok(try!(do_mul(car)) * try!(do_mul(cdr))),
-        Node::Int(k) => Ok(k),
-        Node::Nil => Ok(1),
-        _ => Err(EvalError::WrongTypeArg),
-    }
+    fold(1, lst, i32::checked_mul)
 }
 
 fn do_sub(base: &Node, rest: &Node) -> EvalResult<i32> {
@@ -157,17 +709,40 @@ fn do_sub(base: &Node, rest: &Node) -> EvalResult<i32> {
             let ref re = Node::Int(try!(do_sub(n, v1)));
             do_sub(re, v2)
         }
-        (&Node::Int(v1), &Node::Int(v2)) => Ok(v1 - v2),
+        (&Node::Int(v1), &Node::Int(v2)) => v1.checked_sub(v2).ok_or(EvalError::Overflow),
+        (&Node::Int(v), &Node::Nil) => Ok(v),
+        (_, _) => Err(EvalError::WrongTypeArg),
+    }
+}
+
+// Truncates toward zero like C and LLVM's `sdiv`, so both backends agree.
+fn do_div(base: &Node, rest: &Node) -> EvalResult<i32> {
+    match (base, rest) {
+        (ref n @ &Node::Int(_), &Node::Cell(ref v1, ref v2)) => {
+            let ref re = Node::Int(try!(do_div(n, v1)));
+            do_div(re, v2)
+        }
+        (&Node::Int(_), &Node::Int(0)) => Err(EvalError::DivisionByZero),
+        (&Node::Int(v1), &Node::Int(v2)) => v1.checked_div(v2).ok_or(EvalError::Overflow),
         (&Node::Int(v), &Node::Nil) => Ok(v),
         (_, _) => Err(EvalError::WrongTypeArg),
     }
 }
 
 fn do_add(lst: &Node) -> EvalResult<i32> {
+    fold(0, lst, i32::checked_add)
+}
+
+// Folds from the left, as compiled code does, so an overflow is found at the
+// same argument. Lists among the arguments are folded into it too.
+fn fold(acc: i32, lst: &Node, op: fn(i32, i32) -> Option<i32>) -> EvalResult<i32> {
     match *lst {
-        Node::Cell(ref car, ref cdr) => Ok(try!(do_add(car)) + try!(do_add(cdr))),
-        Node::Int(k) => Ok(k),
-        Node::Nil => Ok(0),
+        Node::Cell(ref car, ref cdr) => {
+            let acc = try!(fold(acc, car, op));
+            fold(acc, cdr, op)
+        }
+        Node::Int(k) => op(acc, k).ok_or(EvalError::Overflow),
+        Node::Nil => Ok(acc),
         _ => Err(EvalError::WrongTypeArg),
     }
 }
@@ -1,4 +1,6 @@
-use node::Node;
+use node::{Node, Bool, quote_form};
+
+const WIDTH: usize = 80;
 
 pub fn lprint(result: Node) {
     match result {
@@ -6,3 +8,101 @@ pub fn lprint(result: Node) {
         x => println!("{:?}", x),
     }
 }
+
+// `node` as it would be read back, with lists that don't fit in a line
+// broken one element per line.
+pub fn pretty(node: &Node) -> String {
+    let out = &mut String::new();
+    write_pretty(out, node, 0);
+    out.clone()
+}
+
+fn write_pretty(out: &mut String, node: &Node, indent: usize) {
+    let flat = &mut String::new();
+    write_flat(flat, node);
+    let elements = match *node {
+        Node::Cell(..) if indent + flat.len() > WIDTH && quote_form(node).is_none() => {
+            elements(node)
+        }
+        _ => None,
+    };
+    match elements {
+        Some(ref elements) if elements.len() > 1 => {
+            out.push('(');
+            write_pretty(out, elements[0], indent + 1);
+            for e in &elements[1..] {
+                out.push('\n');
+                push_spaces(out, indent + 2);
+                write_pretty(out, e, indent + 2);
+            }
+            out.push(')');
+        }
+        _ => out.push_str(flat),
+    }
+}
+
+fn push_spaces(out: &mut String, n: usize) {
+    for _ in 0..n {
+        out.push(' ');
+    }
+}
+
+// The elements of a proper list.
+fn elements(mut node: &Node) -> Option<Vec<&Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push(&**car);
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Some(ret),
+        _ => None,
+    }
+}
+
+fn write_flat(out: &mut String, node: &Node) {
+    if let Some((name, x)) = quote_form(node) {
+        out.push_str(match name {
+            "quote" => "'",
+            "quasiquote" => "`",
+            "unquote" => ",",
+            _ => ",@",
+        });
+        return write_flat(out, x);
+    }
+    match *node {
+        Node::Int(v) => out.push_str(&v.to_string()),
+        Node::Sym(s) => out.push_str(&s),
+        Node::Bool(Bool::True) => out.push_str("#t"),
+        Node::Bool(Bool::False) => out.push_str("#f"),
+        Node::Nil => out.push_str("()"),
+        Node::Prim(_) => out.push_str("#<procedure>"),
+        Node::Condition(ref e) => {
+            out.push_str("#<error ");
+            write_flat(out, &e.message());
+            let mut rest = e.irritants();
+            while let Node::Cell(ref car, ref cdr) = rest.clone() {
+                out.push(' ');
+                write_flat(out, car);
+                rest = (**cdr).clone();
+            }
+            out.push('>');
+        }
+        Node::Cell(ref car, ref cdr) => {
+            out.push('(');
+            write_flat(out, car);
+            let mut rest = &**cdr;
+            while let Node::Cell(ref car, ref cdr) = *rest {
+                out.push(' ');
+                write_flat(out, car);
+                rest = cdr;
+            }
+            if *rest != Node::Nil {
+                out.push_str(" . ");
+                write_flat(out, rest);
+            }
+            out.push(')');
+        }
+        Node::Local(..) | Node::Global(_) => out.push_str(&format!("{:?}", node)),
+    }
+}
@@ -0,0 +1,406 @@
+// Rewrites the variable references of a program before `evaluator::eval`
+// runs it: a parameter of an enclosing lambda or `let` becomes
+// `Node::Local(depth, index)` and anything else global becomes
+// `Node::Global(slot)`, so reading it is an index into `Env` instead of a
+// search by name.
+//
+// Frames are numbered the way `evaluator` fills them, one per lambda
+// application (which is also how `prim_let` runs), with the parameters in
+// order. A variable a `define` adds to a frame has no fixed index, as whether
+// the `define` runs depends on the program, so references to it after the
+// `define` keep looking the name up.
+//
+// A call to a macro is left alone, since what its arguments are isn't known
+// before it is expanded, and so is the rest of a frame it is called in, as
+// the expansion may `define` anything. Macros are the names some `defmacro`
+// in the program defines.
+
+use node::*;
+use env::Env;
+
+struct Frame {
+    params: Vec<String>,
+    defined: Vec<String>,
+    calls_macro: bool,
+}
+
+impl Frame {
+    // A name given twice keeps its first index, like `Env::register`.
+    fn new(names: Vec<&str>) -> Frame {
+        let mut params: Vec<String> = Vec::new();
+        for n in names {
+            if !params.iter().any(|p| p == n) {
+                params.push(n.to_string());
+            }
+        }
+        Frame {
+            params: params,
+            defined: Vec::new(),
+            calls_macro: false,
+        }
+    }
+}
+
+struct Resolver<'a> {
+    env: &'a Env<Node>,
+    frames: Vec<Frame>,
+    macros: Vec<String>,
+}
+
+// `ast` is a whole program, run in `env` with no local frames.
+pub fn resolve(env: &Env<Node>, ast: &Node) -> Node {
+    let macros = &mut Vec::new();
+    collect_macros(ast, macros);
+    let resolver = &mut Resolver {
+        env: env,
+        frames: Vec::new(),
+        macros: macros.clone(),
+    };
+    resolver.resolve(ast)
+}
+
+fn collect_macros(ast: &Node, macros: &mut Vec<String>) {
+    if let Node::Cell(ref car, ref cdr) = *ast {
+        if let (&Node::Sym(f), Ok(name)) = (&**car, car_ref(cdr).and_then(sym_to_str)) {
+            if f == "defmacro" {
+                macros.push(name.to_string());
+            }
+        }
+        collect_macros(car, macros);
+        collect_macros(cdr, macros);
+    }
+}
+
+fn list<'a>(mut node: &'a Node) -> Option<Vec<&'a Node>> {
+    let mut ret = Vec::new();
+    while let Node::Cell(ref car, ref cdr) = *node {
+        ret.push(&**car);
+        node = cdr;
+    }
+    match *node {
+        Node::Nil => Some(ret),
+        _ => None,
+    }
+}
+
+// The name and value of a `(name value)` binding of `let` or `let*`.
+fn binding<'a>(b: &'a Node) -> Option<(&'a str, &'a Node)> {
+    match list(b) {
+        Some(ref parts) if parts.len() == 2 => sym_to_str(parts[0]).ok().map(|n| (n, parts[1])),
+        _ => None,
+    }
+}
+
+fn syms<'a>(nodes: &[&'a Node]) -> Option<Vec<&'a str>> {
+    nodes.iter().map(|n| sym_to_str(n).ok()).collect()
+}
+
+impl<'a> Resolver<'a> {
+    fn lookup(&self, name: &str) -> Option<Node> {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if let Some(i) = frame.params.iter().position(|p| p == name) {
+                return Some(Node::Local(depth, i));
+            }
+            if frame.calls_macro || frame.defined.iter().any(|d| d == name) {
+                return Some(rsym(name));
+            }
+        }
+        None
+    }
+
+    fn resolve(&mut self, ast: &Node) -> Node {
+        match *ast {
+            Node::Sym(ref name) => {
+                self.lookup(name).unwrap_or_else(|| Node::Global(self.env.global_slot(name)))
+            }
+            Node::Cell(ref car, ref cdr) => {
+                let special = match **car {
+                    Node::Sym(ref name) if self.lookup(name).is_none() => {
+                        match name.as_ref() {
+                            "quote" | "defmacro" => return ast.clone(),
+                            _ if self.macros.iter().any(|m| **m == **name) => {
+                                if let Some(frame) = self.frames.last_mut() {
+                                    frame.calls_macro = true;
+                                }
+                                return ast.clone();
+                            }
+                            "quasiquote" => {
+                                car_ref(cdr)
+                                    .ok()
+                                    .map(|t| rlist(self.resolve_template(t, 0), rnil()))
+                            }
+                            "lambda" => self.resolve_lambda(cdr),
+                            "let" => self.resolve_let(cdr),
+                            "let*" => self.resolve_let_star(cdr),
+                            "do" => self.resolve_do(cdr),
+                            "define" => self.resolve_define(cdr),
+                            "guard" => self.resolve_guard(cdr),
+                            "cond" => {
+                                list(cdr).map(|clauses| self.resolve_clauses(&clauses, false))
+                            }
+                            "case" => {
+                                match (car_ref(cdr), cdr_ref(cdr).ok().and_then(list)) {
+                                    (Ok(key), Some(clauses)) => {
+                                        let key = self.resolve(key);
+                                        Some(rcell(key, self.resolve_clauses(&clauses, true)))
+                                    }
+                                    _ => None,
+                                }
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                let rest = match special {
+                    Some(rest) => rest,
+                    None => self.resolve_list(cdr),
+                };
+                rcell(self.resolve(car), rest)
+            }
+            _ => ast.clone(),
+        }
+    }
+
+    fn resolve_list(&mut self, node: &Node) -> Node {
+        match *node {
+            Node::Cell(ref car, ref cdr) => rcell(self.resolve(car), self.resolve_list(cdr)),
+            _ => node.clone(),
+        }
+    }
+
+    // Only what is unquoted is evaluated, `depth` being the number of
+    // `quasiquote`s it is nested in besides the outermost.
+    fn resolve_template(&mut self, template: &Node, depth: usize) -> Node {
+        match quote_form(template) {
+            Some((name, x)) if depth == 0 && name.starts_with("unquote") => {
+                rlist(rsym(name), self.resolve(x))
+            }
+            Some((name, x)) if name != "quote" => {
+                let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+                rlist(rsym(name), self.resolve_template(x, depth))
+            }
+            _ => {
+                match *template {
+                    Node::Cell(ref car, ref cdr) => {
+                        rcell(self.resolve_template(car, depth), self.resolve_template(cdr, depth))
+                    }
+                    _ => template.clone(),
+                }
+            }
+        }
+    }
+
+    // The forms below return `None` when malformed, leaving them to fail
+    // the way `primitives` reports it.
+    fn resolve_lambda(&mut self, rest: &Node) -> Option<Node> {
+        let (params, body) = match *rest {
+            Node::Cell(ref params, ref body) => (params, body),
+            _ => return None,
+        };
+        let names = match list(params).and_then(|p| syms(&p)) {
+            Some(names) => names,
+            None => return None,
+        };
+        self.frames.push(Frame::new(names));
+        let body = self.resolve_list(body);
+        self.frames.pop();
+        Some(rcell((**params).clone(), body))
+    }
+
+    // A named `let` is a frame with the name, in which each call of it makes
+    // one with the variables.
+    fn resolve_let(&mut self, rest: &Node) -> Option<Node> {
+        let (name, rest) = match *rest {
+            Node::Cell(ref car, ref cdr) => {
+                match **car {
+                    Node::Sym(name) => (Some(name), &**cdr),
+                    _ => (None, rest),
+                }
+            }
+            _ => (None, rest),
+        };
+        let (bindings, body) = match *rest {
+            Node::Cell(ref bindings, ref body) => (bindings, body),
+            _ => return None,
+        };
+        let pairs = match list(bindings) {
+            Some(pairs) => pairs,
+            None => return None,
+        };
+        let mut names = Vec::new();
+        let mut values = Vec::new();
+        for b in pairs {
+            match binding(b) {
+                Some((name, value)) => {
+                    names.push(name);
+                    values.push(value);
+                }
+                None => return None,
+            }
+        }
+
+        // Evaluated in the enclosing frame, before the new one exists.
+        let values: Vec<Node> = values.iter().map(|v| self.resolve(v)).collect();
+        let bindings = names.iter()
+            .zip(values.into_iter())
+            .rev()
+            .fold(Node::Nil, |rest, (n, v)| rcell(rlist(rsym(*n), v), rest));
+        if let Some(name) = name {
+            self.frames.push(Frame::new(vec![name.as_str()]));
+        }
+        self.frames.push(Frame::new(names));
+        let body = self.resolve_list(body);
+        self.frames.pop();
+        match name {
+            Some(name) => {
+                self.frames.pop();
+                Some(rcell(Node::Sym(name), rcell(bindings, body)))
+            }
+            None => Some(rcell(bindings, body)),
+        }
+    }
+
+    // The frame of a `let*` gets each variable after its value is resolved.
+    fn resolve_let_star(&mut self, rest: &Node) -> Option<Node> {
+        let (bindings, body) = match *rest {
+            Node::Cell(ref bindings, ref body) => (bindings, body),
+            _ => return None,
+        };
+        let pairs = match list(bindings) {
+            Some(pairs) => pairs,
+            None => return None,
+        };
+        let mut parts = Vec::new();
+        for b in pairs {
+            match binding(b) {
+                Some(part) => parts.push(part),
+                None => return None,
+            }
+        }
+
+        self.frames.push(Frame::new(Vec::new()));
+        let mut resolved = Vec::new();
+        for (name, value) in parts {
+            resolved.push(rlist(rsym(name), self.resolve(value)));
+            // After a `define` in a value, the index of a new variable
+            // depends on whether it ran.
+            let frame = self.frames.last_mut().unwrap();
+            if frame.params.iter().any(|p| p == name) {
+                continue;
+            }
+            if frame.defined.is_empty() {
+                frame.params.push(name.to_string());
+            } else {
+                frame.defined.push(name.to_string());
+            }
+        }
+        let body = self.resolve_list(body);
+        self.frames.pop();
+        let bindings = resolved.into_iter().rev().fold(Node::Nil, |rest, b| rcell(b, rest));
+        Some(rcell(bindings, body))
+    }
+
+    // The variables of a `do` are one frame, in which the steps, the test
+    // and the body are evaluated.
+    fn resolve_do(&mut self, rest: &Node) -> Option<Node> {
+        let (specs, rest) = match *rest {
+            Node::Cell(ref specs, ref rest) => (specs, rest),
+            _ => return None,
+        };
+        let specs = match list(specs) {
+            Some(specs) => specs,
+            None => return None,
+        };
+        let mut names = Vec::new();
+        let mut inits = Vec::new();
+        for spec in specs.iter() {
+            match list(spec) {
+                Some(ref spec) if spec.len() == 2 || spec.len() == 3 => {
+                    match sym_to_str(spec[0]) {
+                        Ok(name) => names.push(name),
+                        Err(_) => return None,
+                    }
+                    inits.push(self.resolve(spec[1]));
+                }
+                _ => return None,
+            }
+        }
+
+        self.frames.push(Frame::new(names));
+        let specs = specs.iter()
+            .zip(inits.into_iter())
+            .map(|(spec, init)| {
+                let step = self.resolve_list(cdr_ref(spec).and_then(cdr_ref).unwrap());
+                rcell(car_ref(spec).unwrap().clone(), rcell(init, step))
+            })
+            .collect::<Vec<_>>();
+        let rest = self.resolve_list(rest);
+        self.frames.pop();
+        let specs = specs.into_iter().rev().fold(Node::Nil, |rest, s| rcell(s, rest));
+        Some(rcell(specs, rest))
+    }
+
+    // The clauses of `cond`, or of `case` when `datums`, leaving `else`, `=>`
+    // and the datums alone.
+    fn resolve_clauses(&mut self, clauses: &[&Node], datums: bool) -> Node {
+        let mut ret = Vec::new();
+        for clause in clauses {
+            ret.push(match **clause {
+                Node::Cell(ref test, ref body) => {
+                    let test = if datums || is_sym(test, "else") {
+                        (**test).clone()
+                    } else {
+                        self.resolve(test)
+                    };
+                    let body = match **body {
+                        Node::Cell(ref arrow, ref rest) if is_sym(arrow, "=>") => {
+                            rcell((**arrow).clone(), self.resolve_list(rest))
+                        }
+                        _ => self.resolve_list(body),
+                    };
+                    rcell(test, body)
+                }
+                _ => (*clause).clone(),
+            });
+        }
+        ret.into_iter().rev().fold(Node::Nil, |rest, c| rcell(c, rest))
+    }
+
+    // The clauses of a `guard` are evaluated in a frame of its variable,
+    // after the body has been left.
+    fn resolve_guard(&mut self, rest: &Node) -> Option<Node> {
+        let (var, clauses, body) = match *rest {
+            Node::Cell(ref spec, ref body) => {
+                match **spec {
+                    Node::Cell(ref var, ref clauses) => (var, clauses, body),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        let (name, clauses) = match (sym_to_str(var), list(clauses)) {
+            (Ok(name), Some(clauses)) => (name, clauses),
+            _ => return None,
+        };
+        let body = self.resolve_list(body);
+        self.frames.push(Frame::new(vec![name]));
+        let clauses = self.resolve_clauses(&clauses, false);
+        self.frames.pop();
+        Some(rcell(rcell((**var).clone(), clauses), body))
+    }
+
+    fn resolve_define(&mut self, rest: &Node) -> Option<Node> {
+        let (name, value) = match (car_ref(rest).and_then(sym_to_str), cdr_ref(rest)) {
+            (Ok(name), Ok(value)) => (name, value),
+            _ => return None,
+        };
+        let value = self.resolve_list(value);
+        if let Some(frame) = self.frames.last_mut() {
+            if !frame.params.iter().any(|p| p == name) {
+                frame.defined.push(name.to_string());
+            }
+        }
+        Some(rcell(rsym(name), value))
+    }
+}
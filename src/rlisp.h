@@ -0,0 +1,221 @@
+/* Runtime for the C99 code written by `ccodegen`.
+ *
+ * Values use the same tagged words as src/runtime.rs, and `rl_print` prints
+ * them the way `printer::lprint` does. There is no collector: cells and
+ * symbols live until the program exits.
+ */
+
+#ifndef RLISP_H
+#define RLISP_H
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+typedef intptr_t rl_word;
+
+#define RL_TAG_MASK 3
+#define RL_TAG_CELL 0
+#define RL_TAG_FIXNUM 1
+#define RL_TAG_SYMBOL 2
+
+#define RL_NIL ((rl_word)3)
+#define RL_FALSE ((rl_word)7)
+#define RL_TRUE ((rl_word)11)
+
+/* Like the compiled code, fixnums are 32 bit unless a word leaves less than
+ * that next to the tag. */
+#if INTPTR_MAX > INT32_MAX
+#define RL_FIXNUM_MIN ((long long)INT32_MIN)
+#define RL_FIXNUM_MAX ((long long)INT32_MAX)
+#else
+#define RL_FIXNUM_MIN (-(1LL << 29))
+#define RL_FIXNUM_MAX ((1LL << 29) - 1)
+#endif
+
+#define RL_FIX(n) ((rl_word)(n) * 4 + RL_TAG_FIXNUM)
+
+typedef struct rl_cell {
+    rl_word car;
+    rl_word cdr;
+} rl_cell;
+
+typedef struct rl_symbol {
+    struct rl_symbol *next;
+    char name[];
+} rl_symbol;
+
+/* Errors are reported on stderr, out of the way of what the program prints,
+ * and the process ends, as in src/runtime.rs. Fixnums and immediates are
+ * shown as the interpreter prints them, anything else as the raw word. */
+static inline void rl_describe(rl_word v)
+{
+    if ((v & RL_TAG_MASK) == RL_TAG_FIXNUM)
+        fprintf(stderr, "%lld", (long long)((v - RL_TAG_FIXNUM) / 4));
+    else if (v == RL_NIL)
+        fprintf(stderr, "()");
+    else if (v == RL_TRUE)
+        fprintf(stderr, "#t");
+    else if (v == RL_FALSE)
+        fprintf(stderr, "#f");
+    else
+        fprintf(stderr, "%#llx", (unsigned long long)v);
+}
+
+static inline void rl_type_error(const char *op, rl_word v)
+{
+    fprintf(stderr, "rlisp: wrong type argument to %s: ", op);
+    rl_describe(v);
+    fprintf(stderr, "\n");
+    abort();
+}
+
+/* `n` is the out of range result, or the dividend of a division by zero. */
+static inline void rl_arith_error(const char *what, const char *op, long long n)
+{
+    fprintf(stderr, "rlisp: %s in %s: %lld\n", what, op, n);
+    abort();
+}
+
+static inline long long rl_fixnum(const char *op, rl_word v)
+{
+    if ((v & RL_TAG_MASK) != RL_TAG_FIXNUM)
+        rl_type_error(op, v);
+    return (v - RL_TAG_FIXNUM) / 4;
+}
+
+static inline rl_word rl_fixnum_result(const char *op, long long n)
+{
+    if (n < RL_FIXNUM_MIN || n > RL_FIXNUM_MAX)
+        rl_arith_error("overflow", op, n);
+    return RL_FIX(n);
+}
+
+static inline rl_word rl_make_fixnum(long long n)
+{
+    return rl_fixnum_result("literal", n);
+}
+
+static inline rl_word rl_add(rl_word a, rl_word b)
+{
+    return rl_fixnum_result("+", rl_fixnum("+", a) + rl_fixnum("+", b));
+}
+
+static inline rl_word rl_sub(rl_word a, rl_word b)
+{
+    return rl_fixnum_result("-", rl_fixnum("-", a) - rl_fixnum("-", b));
+}
+
+static inline rl_word rl_mul(rl_word a, rl_word b)
+{
+    return rl_fixnum_result("*", rl_fixnum("*", a) * rl_fixnum("*", b));
+}
+
+/* C99 division truncates toward zero, like `primitives::do_div`. */
+static inline rl_word rl_div(rl_word a, rl_word b)
+{
+    long long n = rl_fixnum("/", a);
+    long long d = rl_fixnum("/", b);
+    if (d == 0)
+        rl_arith_error("division by zero", "/", n);
+    return rl_fixnum_result("/", n / d);
+}
+
+static inline rl_cell *rl_as_cell(const char *op, rl_word v)
+{
+    if ((v & RL_TAG_MASK) != RL_TAG_CELL || v == 0)
+        rl_type_error(op, v);
+    return (rl_cell *)v;
+}
+
+static inline rl_word rl_cons(rl_word car, rl_word cdr)
+{
+    rl_cell *c = malloc(sizeof(rl_cell));
+    if (c == NULL)
+        abort();
+    c->car = car;
+    c->cdr = cdr;
+    return (rl_word)c;
+}
+
+static inline rl_word rl_car(rl_word v)
+{
+    return rl_as_cell("car", v)->car;
+}
+
+static inline rl_word rl_cdr(rl_word v)
+{
+    return rl_as_cell("cdr", v)->cdr;
+}
+
+static inline rl_word rl_is_null(rl_word v)
+{
+    return v == RL_NIL ? RL_TRUE : RL_FALSE;
+}
+
+/* Symbols with the same name are the same word. */
+static inline rl_word rl_intern(const char *name)
+{
+    static rl_symbol *symbols = NULL;
+    rl_symbol *s;
+    for (s = symbols; s != NULL; s = s->next) {
+        if (strcmp(s->name, name) == 0)
+            return (rl_word)s | RL_TAG_SYMBOL;
+    }
+    s = malloc(sizeof(rl_symbol) + strlen(name) + 1);
+    if (s == NULL)
+        abort();
+    strcpy(s->name, name);
+    s->next = symbols;
+    symbols = s;
+    return (rl_word)s | RL_TAG_SYMBOL;
+}
+
+static inline void rl_write(rl_word v)
+{
+    switch (v & RL_TAG_MASK) {
+    case RL_TAG_FIXNUM:
+        printf("Int(%lld)", rl_fixnum("print", v));
+        break;
+    case RL_TAG_SYMBOL: {
+        /* Quoted as the interpreter prints it. */
+        const char *c = ((rl_symbol *)(v & ~(rl_word)RL_TAG_MASK))->name;
+        printf("Sym(\"");
+        for (; *c != '\0'; c++) {
+            if (*c == '"' || *c == '\\')
+                putchar('\\');
+            putchar(*c);
+        }
+        printf("\")");
+        break;
+    }
+    case RL_TAG_CELL:
+        printf("Cell(");
+        rl_write(((rl_cell *)v)->car);
+        printf(", ");
+        rl_write(((rl_cell *)v)->cdr);
+        printf(")");
+        break;
+    default:
+        if (v == RL_NIL)
+            printf("Nil");
+        else if (v == RL_TRUE)
+            printf("Bool(True)");
+        else if (v == RL_FALSE)
+            printf("Bool(False)");
+        else
+            rl_type_error("print", v);
+    }
+}
+
+static inline void rl_print(rl_word v)
+{
+    if ((v & RL_TAG_MASK) == RL_TAG_FIXNUM)
+        printf("%lld", rl_fixnum("print", v));
+    else
+        rl_write(v);
+    printf("\n");
+}
+
+#endif
@@ -0,0 +1,130 @@
+// Runtime support for code compiled by `codegen`.
+//
+// Every runtime value is a single machine word whose low two bits are a tag:
+//
+//   ...xx01  fixnum, the integer is stored in the upper bits
+//   ...xx00  pointer to a `Cell`, either from `gc` or a quoted constant
+//   ...xx10  pointer to a NUL terminated symbol name
+//   ...xx11  immediate constant (nil, #t, #f)
+//
+// Cells and symbol names are at least 4 byte aligned so their pointers
+// always have the two tag bits clear.
+
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::process;
+use std::rc::Rc;
+use node::{Node, rint, rsym, rnil, rtrue, rfalse};
+use gc;
+
+pub type Word = isize;
+
+pub const TAG_MASK: Word = 3;
+pub const TAG_CELL: Word = 0;
+pub const TAG_FIXNUM: Word = 1;
+pub const TAG_SYMBOL: Word = 2;
+pub const TAG_IMMEDIATE: Word = 3;
+
+pub const NIL: Word = 0b0011;
+pub const FALSE: Word = 0b0111;
+pub const TRUE: Word = 0b1011;
+
+#[repr(C)]
+pub struct Cell {
+    pub car: Word,
+    pub cdr: Word,
+}
+
+pub fn fixnum(v: i32) -> Word {
+    ((v as Word) << 2) | TAG_FIXNUM
+}
+
+pub fn tag(v: Word) -> Word {
+    v & TAG_MASK
+}
+
+// Compiled code has nowhere to return an error to, so it is reported on
+// stderr, out of the way of what the program prints, and the process ends.
+fn type_error(op: &str, v: Word) -> ! {
+    let _ = writeln!(io::stderr(), "rlisp: wrong type argument to {}: {}", op, describe(v));
+    process::abort()
+}
+
+// Fixnums and immediates as the interpreter would print them, anything else
+// as the raw word.
+fn describe(v: Word) -> String {
+    match tag(v) {
+        TAG_FIXNUM => ::printer::pretty(&to_node(v)),
+        TAG_IMMEDIATE if v == NIL || v == TRUE || v == FALSE => ::printer::pretty(&to_node(v)),
+        _ => format!("{:#x}", v),
+    }
+}
+
+fn as_cell<'a>(op: &str, v: Word) -> &'a Cell {
+    if tag(v) != TAG_CELL || v == 0 {
+        type_error(op, v);
+    }
+    unsafe { &*(v as *const Cell) }
+}
+
+// Converts a runtime value back into the interpreter's representation.
+pub fn to_node(v: Word) -> Node {
+    match tag(v) {
+        TAG_FIXNUM => rint((v >> 2) as i32),
+        TAG_SYMBOL => {
+            let name = unsafe { CStr::from_ptr((v & !TAG_MASK) as *const c_char) };
+            rsym(name.to_string_lossy().into_owned())
+        }
+        TAG_CELL => {
+            let cell = as_cell("to_node", v);
+            Node::Cell(Rc::new(to_node(cell.car)), Rc::new(to_node(cell.cdr)))
+        }
+        _ => {
+            match v {
+                NIL => rnil(),
+                TRUE => rtrue(),
+                FALSE => rfalse(),
+                _ => type_error("to_node", v),
+            }
+        }
+    }
+}
+
+// Addresses of everything compiled code may call, for a JIT to resolve
+// declarations against.
+pub fn functions() -> Vec<(&'static str, usize)> {
+    vec![("rlisp_cons", rlisp_cons as *const () as usize),
+         ("rlisp_car", rlisp_car as *const () as usize),
+         ("rlisp_cdr", rlisp_cdr as *const () as usize),
+         ("rlisp_is_null", rlisp_is_null as *const () as usize),
+         ("rlisp_print", rlisp_print as *const () as usize),
+         ("rlisp_gc_root", gc::rlisp_gc_root as *const () as usize),
+         ("rlisp_gc_enter", gc::rlisp_gc_enter as *const () as usize),
+         ("rlisp_gc_leave", gc::rlisp_gc_leave as *const () as usize)]
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_cons(car: Word, cdr: Word) -> Word {
+    gc::alloc_cell(car, cdr)
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_car(v: Word) -> Word {
+    as_cell("car", v).car
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_cdr(v: Word) -> Word {
+    as_cell("cdr", v).cdr
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_is_null(v: Word) -> Word {
+    if v == NIL { TRUE } else { FALSE }
+}
+
+#[no_mangle]
+pub extern "C" fn rlisp_print(v: Word) {
+    ::printer::lprint(to_node(v));
+}
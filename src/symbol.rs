@@ -0,0 +1,100 @@
+// Interned symbol names. Every distinct name is stored once per thread and
+// a `Symbol` is its index, so symbols are copied and compared as integers.
+// Names live until the program exits.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+// The table is per thread, so a `Symbol` must not leave the thread it was
+// made on (neither can the `Rc`s in `Node`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32, PhantomData<*const ()>);
+
+#[derive(Default)]
+struct Interner {
+    index: HashMap<&'static str, u32>,
+    names: Vec<&'static str>,
+}
+
+thread_local!(static SYMBOLS: RefCell<Interner> = RefCell::new(Interner::default()));
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        SYMBOLS.with(|s| {
+            let mut s = s.borrow_mut();
+            if let Some(&i) = s.index.get(name) {
+                return Symbol(i, PhantomData);
+            }
+            let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+            let i = s.names.len() as u32;
+            s.names.push(name);
+            s.index.insert(name, i);
+            Symbol(i, PhantomData)
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        SYMBOLS.with(|s| s.borrow().names[self.0 as usize])
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> From<&'a str> for Symbol {
+    fn from(name: &'a str) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl<'a> From<&'a String> for Symbol {
+    fn from(name: &'a String) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol::intern(&name)
+    }
+}
+
+impl<'a> From<&'a Symbol> for Symbol {
+    fn from(sym: &'a Symbol) -> Symbol {
+        *sym
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Symbol {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+// Prints like the `String` it replaced, so `Node`'s `Debug` output is
+// unchanged.
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
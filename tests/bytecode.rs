@@ -0,0 +1,135 @@
+extern crate rlisp;
+
+mod common;
+
+use std::time::Instant;
+use rlisp::{interpret, interpret_bytecode};
+use rlisp::bytecode::{Machine, Op};
+use rlisp::env::Env;
+use rlisp::node::*;
+use rlisp::parser::parse;
+use rlisp::error::EvalError;
+use common::eval_err;
+
+#[test]
+fn test_bytecode_run() {
+    assert_eq!(interpret_bytecode("(+ 1 2)"), Ok(rint(3)));
+    assert_eq!(interpret_bytecode("(- 100 50 10 10 10 10 10)"), Ok(rint(0)));
+    assert_eq!(interpret_bytecode("(let ((a 10)) (+ 10 a))"), Ok(rint(20)));
+    assert_eq!(interpret_bytecode("((lambda (x) x) 20)"), Ok(rint(20)));
+    assert_eq!(interpret_bytecode("((if #t (lambda (x) x) 20) 20)"), Ok(rint(20)));
+    assert_eq!(interpret_bytecode("((if #f 3 (lambda (x) x)) 20)"), Ok(rint(20)));
+    assert_eq!(interpret_bytecode("(let ((a (lambda (x) x))) (a 20))"), Ok(rint(20)));
+    assert_eq!(interpret_bytecode("(let ((c 10)) (let ((f (lambda (x) (+ x c)))) (let ((a (lambda (y) (f y)))) (a 20))))"),
+               Ok(rint(30)));
+    assert_eq!(interpret_bytecode("((lambda (f1 f2) (f2 (f1 10) (f1 20))) (lambda (x) x) (lambda (x y) (+ x y)))"),
+               Ok(rint(30)));
+    assert_eq!(interpret_bytecode("((if #t + -) 1 2)"), Ok(rint(3)));
+    assert_eq!(interpret_bytecode("(cons 1 (cdr '(2 3)))"), Ok(rcell(rint(1), rcell(rint(3), rnil()))));
+    assert_eq!(interpret_bytecode("(null? (cdr '(1)))"), Ok(rtrue()));
+    assert_eq!(interpret_bytecode("(= 1 1 2)"), Ok(rfalse()));
+}
+
+#[test]
+fn test_bytecode_matches_interpreter_quirks() {
+    // `progn` returns its last value that isn't `()`.
+    assert_eq!(interpret_bytecode("(progn 1 '() '())"), Ok(rint(1)));
+    // Lists passed to `+` are summed.
+    assert_eq!(interpret_bytecode("(+ '(1 2) 3)"), interpret("(+ '(1 2) 3)"));
+    assert_eq!(interpret_bytecode("(- 5)"), Ok(rint(-5)));
+    assert_eq!(interpret_bytecode("(/ 2)"), Ok(rint(0)));
+    assert_eq!(interpret_bytecode("(let ((x 1) (x 2)) x)"), Ok(rint(2)));
+    assert_eq!(interpret_bytecode("((lambda (x) (define x 5) x) 1)"), Ok(rint(5)));
+    // Closures copy the variables of enclosing lambdas when created.
+    assert_eq!(interpret_bytecode("(let ((x 1)) (let ((f (lambda () x))) (progn (define x 2) (f))))"),
+               Ok(rint(1)));
+}
+
+#[test]
+fn test_bytecode_errors() {
+    assert_eq!(interpret_bytecode("x"), eval_err(EvalError::UnknowSymbol("x".to_string())));
+    assert_eq!(interpret_bytecode("(1 2)"), eval_err(EvalError::UnknowSymbol("Int(1)".to_string())));
+    assert_eq!(interpret_bytecode("((lambda (x) x))"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(interpret_bytecode("(/ 1 0)"), eval_err(EvalError::DivisionByZero));
+    assert_eq!(interpret_bytecode("(* 65536 65536)"), eval_err(EvalError::Overflow));
+    // Arithmetic folds from the left, as compiled code does.
+    for input in ["(+ 2147483647 1 -1)", "(* 65536 65536 0)"].iter() {
+        assert_eq!(interpret(*input), eval_err(EvalError::Overflow));
+        assert_eq!(interpret_bytecode(*input), eval_err(EvalError::Overflow));
+    }
+    assert_eq!(interpret_bytecode("(car 1)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(interpret_bytecode("(car 1 2)"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(interpret_bytecode("(if #f 1)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(interpret_bytecode("(define 1 2)"), eval_err(EvalError::E));
+    assert_eq!(interpret_bytecode("((if #t if +) 1 2)"),
+               eval_err(EvalError::SpecialFormValue("if".to_string())));
+    assert_eq!(interpret_bytecode("(if #f (define let 1) 2)"),
+               eval_err(EvalError::SpecialFormRebind("let".to_string())));
+}
+
+#[test]
+fn test_bytecode_globals_are_shared() {
+    assert_eq!(interpret_bytecode("(progn (define f (lambda () x)) (define x 1) (f))"),
+               Ok(rint(1)));
+    assert_eq!(interpret_bytecode("(progn
+  (define sum (lambda (n) (if (= n 0) 0 (+ n (sum (- n 1))))))
+  (sum 100))"),
+               Ok(rint(5050)));
+    // A redefined primitive isn't compiled to its opcode.
+    assert_eq!(interpret_bytecode("(progn (define + -) (+ 3 2))"), Ok(rint(1)));
+}
+
+#[test]
+fn test_bytecode_tail_calls() {
+    // Deep enough to overflow the Rust stack if calls used it.
+    assert_eq!(interpret_bytecode("(progn
+  (define loop (lambda (i acc) (if (= i 0) acc (loop (- i 1) (+ acc 1)))))
+  (loop 1000000 0))"),
+               Ok(rint(1000000)));
+}
+
+#[test]
+fn test_bytecode_resolves_slots() {
+    let machine = &mut Machine::new(Env::new());
+    let proto = machine.compile(&parse("(let ((a 1) (b 2)) (cons b a))").unwrap()).unwrap();
+    assert_eq!(proto.code,
+               vec![Op::Const(0),
+                    Op::Const(1),
+                    Op::SetLocal(1),
+                    Op::SetLocal(0),
+                    Op::Local(1),
+                    Op::Local(0),
+                    Op::Cons,
+                    Op::Return]);
+}
+
+// Loops written with self-application, which `evaluator` can run too.
+const BENCHMARK: &'static str = "
+(let ((outer (lambda (self i acc)
+               (if (= i 0)
+                   acc
+                   (self self
+                         (- i 1)
+                         (+ acc
+                            (let ((inner (lambda (self j acc)
+                                           (if (= j 0)
+                                               acc
+                                               (self self (- j 1) (+ acc (car (cons j '()))))))))
+                              (inner inner 100 0))))))))
+  (outer outer 100 0))";
+
+// Run with `cargo test --release -- --ignored`.
+#[test]
+#[ignore]
+fn bench_bytecode_speedup() {
+    let start = Instant::now();
+    let expected = interpret(BENCHMARK);
+    let tree = start.elapsed();
+    let start = Instant::now();
+    let actual = interpret_bytecode(BENCHMARK);
+    let bytecode = start.elapsed();
+
+    assert_eq!(actual, expected);
+    println!("evaluator: {:?}, bytecode: {:?}", tree, bytecode);
+    assert!(bytecode * 5 < tree);
+}
@@ -0,0 +1,140 @@
+extern crate rlisp;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use rlisp::ccodegen;
+use rlisp::parser::parse;
+use rlisp::node::*;
+use rlisp::error::{CompileError, EvalError, RLispError};
+
+fn compile(input: &str) -> Result<String, CompileError> {
+    ccodegen::compile(&parse(input).unwrap())
+}
+
+#[test]
+fn test_compile_to_c() {
+    let c = compile("(- 10 2 3)").unwrap();
+    assert!(c.contains("#include \"rlisp.h\""));
+    assert!(c.contains("rl_word t1 = rl_sub(RL_FIX(10), RL_FIX(2));"));
+    assert!(c.contains("rl_word t2 = rl_sub(t1, RL_FIX(3));"));
+    assert!(c.contains("rl_print(rlisp_main());"));
+
+    let c = compile("(progn (define x 1) x)").unwrap();
+    assert!(c.contains("static rl_word g1_x = RL_NIL;"));
+
+    let c = compile("'(1 a)").unwrap();
+    assert!(c.contains("rl_cons(RL_FIX(1), rl_cons(rl_intern(\"a\"), RL_NIL))"));
+
+    // Out of the range every target can tag at compile time.
+    let c = compile("2147483647").unwrap();
+    assert!(c.contains("rl_make_fixnum(2147483647LL)"));
+}
+
+// Builds `input` in `dir` with the system C compiler and returns the
+// executable, or `None` where there is no `cc`.
+fn build_with_cc(dir: &str, input: &str) -> Option<PathBuf> {
+    let dir = env::temp_dir().join(dir);
+    fs::create_dir_all(&dir).unwrap();
+    File::create(dir.join("rlisp.h"))
+        .unwrap()
+        .write_all(ccodegen::RUNTIME_HEADER.as_bytes())
+        .unwrap();
+    let source = dir.join("out.c");
+    let exe = dir.join("out");
+    File::create(&source).unwrap().write_all(compile(input).unwrap().as_bytes()).unwrap();
+
+    let status = match Command::new("cc")
+        .arg("-std=c99")
+        .arg(&source)
+        .arg("-o")
+        .arg(&exe)
+        .status() {
+        Ok(status) => status,
+        Err(_) => return None,
+    };
+    assert!(status.success(), "{}: cc failed", input);
+    Some(exe)
+}
+
+// What the program built from `input` prints, or `None` where there is no `cc`.
+fn run_with_cc(dir: &str, input: &str) -> Option<String> {
+    build_with_cc(dir, input).map(|exe| {
+        let output = Command::new(&exe).output().unwrap();
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    })
+}
+
+fn interpreted(input: &str) -> String {
+    match rlisp::interpret(input).unwrap() {
+        Node::Int(v) => v.to_string(),
+        v => format!("{:?}", v),
+    }
+}
+
+// Checks each corpus program prints what the interpreter does, or is not
+// compiled where the interpreter finds it malformed.
+#[test]
+fn test_corpus_with_cc() {
+    let mut paths: Vec<_> = fs::read_dir("tests/corpus")
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+
+    for path in paths.iter() {
+        let mut input = String::new();
+        File::open(path).unwrap().read_to_string(&mut input).unwrap();
+        if let Err(RLispError::EvalError(EvalError::InvalidSyntax(_))) = rlisp::interpret(&*input) {
+            assert!(compile(&input).is_err(), "{}", path.display());
+            continue;
+        }
+        let output = match run_with_cc("rlisp-test-ccodegen", &input) {
+            Some(output) => output,
+            None => return,
+        };
+        assert_eq!(output, interpreted(&input), "{}", path.display());
+    }
+}
+
+// Symbol names may hold characters a C string literal has to escape.
+#[test]
+fn test_quoted_symbols_with_cc() {
+    let c = compile("'(\"a \\b c??= \u{e9})").unwrap();
+    assert!(c.contains(r#"rl_intern("\"a")"#));
+    assert!(c.contains(r#"rl_intern("\\b")"#));
+    assert!(c.contains(r#"rl_intern("c\?\?=")"#));
+    assert!(c.contains(r#"rl_intern("\303\251")"#));
+
+    for input in ["(car '(\"a b))", "'(\\b \"\\)", "'(c??= \u{e9})"].iter() {
+        let output = match run_with_cc("rlisp-test-ccodegen-symbols", input) {
+            Some(output) => output,
+            None => return,
+        };
+        assert_eq!(output, interpreted(input), "{}", input);
+    }
+}
+
+// Runtime errors end the program with the operation and the value on stderr,
+// as src/runtime.rs reports them for the JIT.
+#[test]
+fn test_runtime_errors_with_cc() {
+    let cases = [("(car 1)", "rlisp: wrong type argument to car: 1"),
+                 ("(cdr '())", "rlisp: wrong type argument to cdr: ()"),
+                 ("(+ 1 '())", "rlisp: wrong type argument to +: ()"),
+                 ("(* 65536 65536)", "rlisp: overflow in *: 4294967296"),
+                 ("(/ 7 0)", "rlisp: division by zero in /: 7")];
+    for &(input, message) in cases.iter() {
+        let exe = match build_with_cc("rlisp-test-ccodegen-errors", input) {
+            Some(exe) => exe,
+            None => return,
+        };
+        let output = Command::new(&exe).output().unwrap();
+        assert!(!output.status.success(), "{}", input);
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "", "{}", input);
+        assert_eq!(String::from_utf8(output.stderr).unwrap().trim(), message, "{}", input);
+    }
+}
@@ -0,0 +1,152 @@
+#![cfg(feature = "llvm")]
+
+extern crate rlisp;
+
+use std::env;
+use std::fs;
+use rlisp::codegen::{VM, OptLevel, Target};
+use rlisp::parser::parse;
+use rlisp::node::*;
+use rlisp::error::CompileError;
+
+fn compile(input: &str) -> Result<(), CompileError> {
+    VM::new().run(&parse(input).unwrap())
+}
+
+#[test]
+fn test_compile_unsupported_forms() {
+    assert_eq!(compile("(foo 1)"), Err(CompileError::UnknowSymbol(rsym("foo"))));
+    assert_eq!(compile("(if #t 1 2)"), Err(CompileError::UnknowSymbol(rsym("if"))));
+    assert_eq!(compile("(lambda (x) x)"),
+               Err(CompileError::NotSupported(rcell(rsym("lambda"),
+                                                    rlist(rcell(rsym("x"), rnil()), rsym("x"))))));
+    assert_eq!(compile("((lambda (x) x) 1 2)"),
+               Err(CompileError::InvalidArgNumber(rlist(rint(1), rint(2)))));
+    assert_eq!(compile("(let (a) a)"), Err(CompileError::WrongTypeArg(rsym("a"))));
+    assert_eq!(compile("(-)"), Err(CompileError::InvalidArgNumber(rcell(rsym("-"), rnil()))));
+}
+
+#[test]
+fn test_compile_arith() {
+    assert_eq!(compile("(+)"), Ok(()));
+    assert_eq!(compile("(* 2 3 4)"), Ok(()));
+    assert_eq!(compile("(- 10 2 3)"), Ok(()));
+    assert_eq!(compile("(- 5)"), Ok(()));
+    assert_eq!(compile("(/ -6 3)"), Ok(()));
+}
+
+#[test]
+fn test_compile_lists() {
+    assert_eq!(compile("'(1 a #t)"), Ok(()));
+    assert_eq!(compile("(cons 1 (cons 2 '()))"), Ok(()));
+    assert_eq!(compile("(let ((l '(1 2))) (+ (car l) (car (cdr l))))"), Ok(()));
+    assert_eq!(compile("(null? '())"), Ok(()));
+    assert_eq!(compile("#t"), Ok(()));
+    assert_eq!(compile("(car 1 2)"),
+               Err(CompileError::InvalidArgNumber(rcell(rsym("car"), rlist(rint(1), rint(2))))));
+}
+
+#[test]
+fn test_compile_with_opt_levels() {
+    let ast = parse("(let ((a 10) (f (lambda (x) (* x 2)))) (f (+ a 1)))").unwrap();
+    for level in vec![OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+        let vm = &mut VM::new();
+        vm.set_opt_level(level);
+        assert_eq!(vm.run(&ast), Ok(()));
+    }
+}
+
+// `b` is bound after the branch `+` makes to check for overflow, and `x` is
+// in the inlined lambda, but their slots are still in the entry block, so
+// -O1 and up turn them into registers. `a` isn't a constant and stays rooted.
+#[test]
+fn test_variables_are_promoted() {
+    let ast = parse("(let ((a (+ 1 2)) (b 5)) ((lambda (x) (cons x b)) 7))").unwrap();
+    for level in vec![OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+        let vm = &mut VM::new();
+        vm.set_opt_level(level);
+        assert_eq!(vm.run(&ast), Ok(()));
+        let ir = vm.ir();
+        assert!(ir.contains("%a = alloca"), "{}", ir);
+        assert_eq!(ir.contains("%b = alloca"), level == OptLevel::O0, "{}", ir);
+        assert_eq!(ir.contains("%x = alloca"), level == OptLevel::O0, "{}", ir);
+    }
+}
+
+#[test]
+fn test_opt_level_from_flag() {
+    assert_eq!(OptLevel::from_flag("-O0"), Some(OptLevel::O0));
+    assert_eq!(OptLevel::from_flag("-O3"), Some(OptLevel::O3));
+    assert_eq!(OptLevel::from_flag("-O4"), None);
+    assert_eq!(OptLevel::from_flag("(+ 1 2)"), None);
+}
+
+#[test]
+fn test_compile_session() {
+    let vm = VM::new();
+    assert!(vm.compile(&parse("(define x (+ 1 2))").unwrap()).is_ok());
+    assert!(vm.compile(&parse("(foo x)").unwrap()).is_err());
+    assert!(vm.compile(&parse("(define f (lambda (y) (* x y)))").unwrap()).is_ok());
+    assert!(vm.find_function("rlisp_toplevel0").is_some());
+    assert!(vm.find_function("rlisp_toplevel1").is_some());
+    assert!(vm.find_function("rlisp_toplevel2").is_none());
+
+    assert_eq!(vm.jit(&parse("(f 10)").unwrap()), Ok(rint(30)));
+    assert_eq!(vm.jit(&parse("(progn (define x 5) (cons x '(1)))").unwrap()),
+               Ok(rlist(rint(5), rint(1))));
+    assert_eq!(vm.jit(&parse("(f x)").unwrap()), Ok(rint(25)));
+    assert!(vm.run(&parse("x").unwrap()).is_ok());
+}
+
+// What a failed `compile` defined and added to the module is taken out again.
+#[test]
+fn test_failed_compile_is_rolled_back() {
+    let vm = VM::new();
+    assert!(vm.compile(&parse("(progn (define x (car '(1 a))) (foo x))").unwrap()).is_err());
+    assert!(vm.find_function("rlisp_toplevel0").is_none());
+    assert_eq!(vm.compile(&parse("x").unwrap()),
+               Err(CompileError::UnknowSymbol(rsym("x"))));
+    assert_eq!(vm.jit(&parse("(progn (define x (car '(2 a))) x)").unwrap()), Ok(rint(2)));
+}
+
+#[test]
+fn test_target_flags() {
+    let mut t = Target::new("x86_64-unknown-linux-gnu");
+    assert!(t.parse_flag("--target=aarch64-unknown-linux-gnu"));
+    assert!(t.parse_flag("--cpu=cortex-a53"));
+    assert!(t.parse_flag("--features=+neon"));
+    assert!(!t.parse_flag("--opt=2"));
+    assert!(!t.parse_flag("-O2"));
+    assert_eq!(t,
+               Target {
+                   triple: "aarch64-unknown-linux-gnu".to_string(),
+                   cpu: "cortex-a53".to_string(),
+                   features: "+neon".to_string(),
+               });
+}
+
+#[test]
+fn test_compile_for_target() {
+    let vm = &mut VM::new();
+    assert!(vm.set_target(&Target::new("no-such-target")).is_err());
+    assert_eq!(vm.set_target(&Target::new("aarch64-unknown-linux-gnu")), Ok(()));
+    assert!(vm.run(&parse("(cons (* 3 4) '(a))").unwrap()).is_ok());
+    assert!(vm.set_target(&Target::native()).is_err());
+    assert!(vm.jit(&parse("1").unwrap()).is_err());
+
+    let path = env::temp_dir().join("rlisp-test-aarch64.o");
+    assert_eq!(vm.emit_object(path.to_str().unwrap()), Ok(()));
+    assert!(fs::metadata(&path).unwrap().len() > 0);
+    fs::remove_file(&path).unwrap();
+
+    // 32 bit words leave 30 bit fixnums.
+    let vm = &mut VM::new();
+    assert_eq!(vm.set_target(&Target::new("i686-unknown-linux-gnu")), Ok(()));
+    assert!(vm.compile(&parse("(+ 1 536870911)").unwrap()).is_ok());
+    assert_eq!(vm.compile(&parse("(+ 1 536870912)").unwrap()),
+               Err(CompileError::NotSupported(rint(536870912))));
+
+    let vm = &mut VM::new();
+    assert_eq!(vm.set_target(&Target::native()), Ok(()));
+    assert_eq!(vm.jit(&parse("(* 6 7)").unwrap()), Ok(rint(42)));
+}
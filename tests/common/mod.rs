@@ -0,0 +1,17 @@
+// Helpers shared by the test crates, which each use some of them.
+#![allow(dead_code)]
+
+use rlisp::{interpret, interpret_bytecode};
+use rlisp::node::Node;
+use rlisp::error::{EvalError, RLispError};
+
+// Evaluates `input` with both evaluators, which have to agree.
+pub fn both(input: &str) -> Result<Node, RLispError> {
+    let ret = interpret(input);
+    assert_eq!(interpret_bytecode(input), ret, "{}", input);
+    ret
+}
+
+pub fn eval_err(e: EvalError) -> Result<Node, RLispError> {
+    Err(RLispError::EvalError(e))
+}
@@ -0,0 +1,282 @@
+extern crate rlisp;
+
+mod common;
+
+use rlisp::{interpret, interpret_bytecode, Interpreter};
+use rlisp::node::*;
+use rlisp::error::{EvalError, RLispError};
+use common::{both, eval_err};
+
+#[test]
+fn test_call_cc_escape() {
+    assert_eq!(both("(+ 1 (call/cc (lambda (k) (+ 10 (k 5)))))"), Ok(rint(6)));
+    assert_eq!(both("(+ 1 (call/cc (lambda (k) 2)))"), Ok(rint(3)));
+    assert_eq!(both("(call-with-current-continuation (lambda (k) (k 1) 2))"), Ok(rint(1)));
+    // Out of a loop, and of the calls in between.
+    assert_eq!(both("(call/cc (lambda (return)
+                       (let loop ((l '(1 2 0 4)))
+                         (cond ((null? l) 'none)
+                               ((= (car l) 0) (return (cdr l)))
+                               (else (+ 1 (loop (cdr l))))))))"),
+               Ok(rcell(rint(4), rnil())));
+    assert_eq!(both("(call/cc (lambda (k) (do ((i 0 (+ i 1))) (#f) (if (= i 5) (k i) i))))"),
+               Ok(rint(5)));
+}
+
+#[test]
+fn test_call_cc_reentry() {
+    // The continuation is called again after the `call/cc` has returned,
+    // with the count so far.
+    assert_eq!(both("(let ((r (call/cc (lambda (k) (cons 0 k)))))
+                       (if (= (car r) 3) 'done ((cdr r) (cons (+ (car r) 1) (cdr r)))))"),
+               Ok(rsym("done")));
+    // Arguments evaluated before the `call/cc` are kept each time.
+    assert_eq!(both("((lambda (p)
+                        (if (= (car (cdr p)) 3)
+                            (+ (car p) (car (cdr p)))
+                            ((cdr (cdr p)) (cons (+ (car (cdr p)) 1) (cdr (cdr p))))))
+                      (cons 10 (call/cc (lambda (k) (cons 0 k)))))"),
+               Ok(rint(13)));
+    assert_eq!(both("(let* ((a 1) (r (call/cc (lambda (k) (cons a k)))))
+                       (if (= (car r) 4) (* a (car r)) ((cdr r) (cons (+ (car r) 1) (cdr r)))))"),
+               Ok(rint(4)));
+    // Globals defined since the `call/cc` are still there.
+    assert_eq!(both("(progn
+                       (define r (call/cc (lambda (c) (cons c '()))))
+                       (if (null? (cdr r))
+                           (progn (define x 42) ((car r) (cons 0 '(1))))
+                           x))"),
+               Ok(rint(42)));
+}
+
+#[test]
+fn test_call_cc_errors() {
+    assert_eq!(both("(call/cc)"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(call/cc (lambda (k) (k 1 2)))"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(call/cc (lambda (k) (k)))"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(call/cc 1)"), eval_err(EvalError::UnknowSymbol("Int(1)".to_string())));
+}
+
+#[test]
+fn test_deep_recursion() {
+    // Neither evaluator keeps calls on the Rust stack.
+    assert_eq!(both("(let f ((n 10000)) (if (= n 0) 0 (+ 1 (f (- n 1)))))"), Ok(rint(10000)));
+    assert_eq!(both("(call/cc (lambda (k)
+                       (let f ((n 10000)) (if (= n 0) (k 'bottom) (+ 1 (f (- n 1)))))))"),
+               Ok(rsym("bottom")));
+}
+
+#[test]
+fn test_session_after_error() {
+    // The frames `eval` was in when it failed are left, along with their
+    // variables.
+    let session = &mut Interpreter::new();
+    assert_eq!(session.eval("(define x 1)"), Ok(rint(1)));
+    assert_eq!(session.eval("(let* ((y 2)) ((lambda (z) (car z)) y))"),
+               eval_err(EvalError::WrongTypeArg));
+    assert_eq!(session.env().find("y"), None);
+    assert_eq!(session.env().find("z"), None);
+    assert_eq!(session.eval("(define w 3)"), Ok(rint(3)));
+    assert_eq!(session.eval("(+ x w)"), Ok(rint(4)));
+}
+
+#[test]
+fn test_call_ec() {
+    assert_eq!(both("(+ 1 (call/ec (lambda (k) (+ 10 (k 5)))))"), Ok(rint(6)));
+    assert_eq!(both("(+ 1 (call/ec (lambda (k) 2)))"), Ok(rint(3)));
+    assert_eq!(both("(call/ec (lambda (return)
+                       (let loop ((l '(1 2 0 4)))
+                         (cond ((null? l) 'none)
+                               ((= (car l) 0) (return (cdr l)))
+                               (else (+ 1 (loop (cdr l))))))))"),
+               Ok(rcell(rint(4), rnil())));
+    assert_eq!(both("(call/ec (lambda (k) (do ((i 0 (+ i 1))) (#f) (if (= i 5) (k i) i))))"),
+               Ok(rint(5)));
+    assert_eq!(both("(call/ec (lambda (k)
+                       (let loop ((i 0)) (if (= i 100000) (k i) (loop (+ i 1))))))"),
+               Ok(rint(100000)));
+    // To the outer one, past the inner.
+    assert_eq!(both("(call/ec (lambda (outer) (+ 1 (call/ec (lambda (inner) (outer 7))))))"),
+               Ok(rint(7)));
+    // The variables of the caller are back.
+    assert_eq!(both("(let ((x 1)) (+ x (call/ec (lambda (k) (let ((x 10)) (k x))))))"),
+               Ok(rint(11)));
+}
+
+#[test]
+fn test_call_ec_errors() {
+    assert_eq!(both("(call/ec)"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(call/ec (lambda (k) (k 1 2)))"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(call/ec 1)"), eval_err(EvalError::UnknowSymbol("Int(1)".to_string())));
+    // An escape procedure can't be used once its `call/ec` has returned.
+    for ret in vec![interpret("((call/ec (lambda (k) k)) 1)"),
+                    interpret_bytecode("((call/ec (lambda (k) k)) 1)")] {
+        match ret {
+            Err(RLispError::EvalError(EvalError::Escape(_, ref v))) => assert_eq!(*v, rint(1)),
+            _ => panic!("{:?}", ret),
+        }
+    }
+}
+
+#[test]
+fn test_catch_throw() {
+    assert_eq!(both("(catch 'done (+ 1 (throw 'done 2)))"), Ok(rint(2)));
+    assert_eq!(both("(catch 'done 1 2)"), Ok(rint(2)));
+    assert_eq!(both("(catch 'done)"), Ok(rnil()));
+    // From a procedure called in the body.
+    assert_eq!(both("(let ((f (lambda (n) (if (= n 3) (throw 'found n) n))))
+                       (catch 'found (do ((i 0 (+ i 1))) (#f) (f i))))"),
+               Ok(rint(3)));
+    // The innermost `catch` of the tag gets it.
+    assert_eq!(both("(catch 'a (+ 1 (catch 'a (throw 'a 1))))"), Ok(rint(2)));
+    assert_eq!(both("(catch 'a (+ 1 (catch 'b (throw 'a 1))))"), Ok(rint(1)));
+    assert_eq!(both("(catch 1 (+ 1 (catch 2 (throw (+ 0 1) 5))))"), Ok(rint(5)));
+    // Lists are compared by identity.
+    assert_eq!(both("(let ((t (cons 1 2))) (catch t (throw t 3)))"), Ok(rint(3)));
+    assert_eq!(both("(catch (cons 1 2) (throw (cons 1 2) 3))"),
+               eval_err(EvalError::Throw(rcell(rint(1), rint(2)), rint(3))));
+    // A `catch` that has returned doesn't catch anything any more.
+    assert_eq!(both("(progn (catch 'a 1) (throw 'a 2))"),
+               eval_err(EvalError::Throw(rsym("a"), rint(2))));
+    assert_eq!(both("(throw 'a)"), eval_err(EvalError::InvalidArgNumber));
+}
+
+#[test]
+fn test_catch_with_call_cc() {
+    // Going back into a `catch` with a continuation brings it back.
+    assert_eq!(both("(let ((r (catch 'a
+                                 (let ((x (call/cc (lambda (k) k))))
+                                   (if (null? x) (throw 'a '()) (cons x '()))))))
+                       (if (null? r) 'caught ((car r) '())))"),
+               Ok(rsym("caught")));
+    // And going out of one drops it.
+    assert_eq!(both("(progn (call/cc (lambda (k) (catch 'a (k 1)))) (throw 'a 2))"),
+               eval_err(EvalError::Throw(rsym("a"), rint(2))));
+}
+
+#[test]
+fn test_session_after_throw() {
+    let session = &mut Interpreter::new();
+    assert_eq!(session.eval("(let ((y 2)) (throw 'a y))"),
+               eval_err(EvalError::Throw(rsym("a"), rint(2))));
+    assert_eq!(session.env().find("y"), None);
+    assert_eq!(session.eval("(catch 'a (let ((y 2)) (throw 'a y)))"), Ok(rint(2)));
+    assert_eq!(session.env().find("y"), None);
+}
+
+#[test]
+fn test_unwind_protect() {
+    assert_eq!(both("(unwind-protect 1 2 3)"), Ok(rint(1)));
+    assert_eq!(both("(unwind-protect 1)"), Ok(rint(1)));
+    // The cleanup runs after the body, however it is left; here it throws
+    // to show it did.
+    assert_eq!(both("(catch 'c (unwind-protect 1 (throw 'c 'cleaned)))"), Ok(rsym("cleaned")));
+    assert_eq!(both("(catch 'c (unwind-protect (car 1) (throw 'c 'cleaned)))"),
+               Ok(rsym("cleaned")));
+    assert_eq!(both("(catch 'c (catch 'a (unwind-protect (throw 'a 1) (throw 'c 'cleaned))))"),
+               Ok(rsym("cleaned")));
+    assert_eq!(both("(catch 'c
+                       (call/ec (lambda (k) (unwind-protect (k 1) (throw 'c 'cleaned)))))"),
+               Ok(rsym("cleaned")));
+    // Otherwise what left the body goes on.
+    assert_eq!(both("(unwind-protect (car 1) 2)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(catch 'a (+ 1 (unwind-protect (throw 'a 1) 2)))"), Ok(rint(1)));
+    assert_eq!(both("(call/ec (lambda (k) (+ 1 (unwind-protect (k 1) 2))))"), Ok(rint(1)));
+    // Each of the cleanups on the way runs.
+    assert_eq!(both("(catch 'c
+                       (unwind-protect
+                         (unwind-protect (throw 'a 1) (throw 'c 'inner))
+                         (throw 'c 'outer)))"),
+               Ok(rsym("outer")));
+    // In the scope of the `unwind-protect`.
+    assert_eq!(both("(catch 'c
+                       (let ((x 1))
+                         (unwind-protect (let ((x 2)) (car x)) (throw 'c x))))"),
+               Ok(rint(1)));
+}
+
+#[test]
+fn test_dynamic_wind() {
+    assert_eq!(both("(dynamic-wind (lambda () 1) (lambda () 2) (lambda () 3))"), Ok(rint(2)));
+    assert_eq!(both("(catch 'c (dynamic-wind (lambda () (throw 'c 'before))
+                                             (lambda () (throw 'c 'thunk))
+                                             (lambda () (throw 'c 'after))))"),
+               Ok(rsym("before")));
+    assert_eq!(both("(catch 'c (dynamic-wind (lambda () 1)
+                                             (lambda () 2)
+                                             (lambda () (throw 'c 'after))))"),
+               Ok(rsym("after")));
+    assert_eq!(both("(catch 'c (dynamic-wind (lambda () 1)
+                                             (lambda () (car 1))
+                                             (lambda () (throw 'c 'after))))"),
+               Ok(rsym("after")));
+    assert_eq!(both("(catch 'c
+                       (call/ec (lambda (k)
+                         (dynamic-wind (lambda () 1)
+                                       (lambda () (k 2))
+                                       (lambda () (throw 'c 'after))))))"),
+               Ok(rsym("after")));
+    assert_eq!(both("(dynamic-wind (lambda () 1) (lambda () (car 1)) (lambda () 3))"),
+               eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(let ((dw dynamic-wind)) (dw (lambda () 1) (lambda () 2) (lambda () 3)))"),
+               Ok(rint(2)));
+    assert_eq!(both("(dynamic-wind (lambda () 1) (lambda () 2))"),
+               eval_err(EvalError::InvalidArgNumber));
+}
+
+#[test]
+fn test_wind_with_continuations() {
+    // Leaving by a continuation runs the cleanups, innermost first.
+    assert_eq!(both("(catch 'after
+                       (call/cc (lambda (k)
+                         (dynamic-wind (lambda () 0)
+                                       (lambda () (k 2))
+                                       (lambda () (throw 'after 3))))))"),
+               Ok(rint(3)));
+    assert_eq!(both("(catch 'c (call/cc (lambda (k) (unwind-protect (k 1) (throw 'c 'cleaned)))))"),
+               Ok(rsym("cleaned")));
+    assert_eq!(both("(progn
+                       (define log '())
+                       (define k (call/cc (lambda (c) c)))
+                       (if (null? log)
+                           (unwind-protect
+                             (unwind-protect (k 0) (define log (cons 'inner log)))
+                             (define log (cons 'outer log)))
+                           log))"),
+               Ok(rlist(rsym("outer"), rsym("inner"))));
+    // Not when it stays inside.
+    assert_eq!(both("(catch 'c
+                       (dynamic-wind (lambda () 0)
+                                     (lambda () (+ 1 (call/cc (lambda (k) (k 1)))))
+                                     (lambda () 0)))"),
+               Ok(rint(2)));
+    // Going back in calls `before` again, then goes on in the thunk.
+    assert_eq!(both("(progn
+                       (define first #t)
+                       (define r (catch 'c
+                                   (dynamic-wind
+                                     (lambda () (if first 0 (throw 'c 'before-again)))
+                                     (lambda () (call/cc (lambda (k) k)))
+                                     (lambda () 0))))
+                       (define first #f)
+                       (case r ((before-again) r) (else (r 1))))"),
+               Ok(rsym("before-again")));
+    assert_eq!(both("(progn
+                       (define r (dynamic-wind (lambda () 0)
+                                               (lambda () (call/cc (lambda (k) k)))
+                                               (lambda () 0)))
+                       (case r ((5) 'resumed) (else (r 5))))"),
+               Ok(rsym("resumed")));
+    // And leaves the one it was in first.
+    assert_eq!(both("(progn
+                       (define r (dynamic-wind (lambda () 0)
+                                               (lambda () (call/cc (lambda (k) k)))
+                                               (lambda () 0)))
+                       (case r
+                         ((5) 'resumed)
+                         (else (catch 'c
+                                 (dynamic-wind (lambda () 0)
+                                               (lambda () (r 5))
+                                               (lambda () (throw 'c 'left)))))))"),
+               Ok(rsym("left")));
+}
@@ -0,0 +1,167 @@
+extern crate rlisp;
+
+mod common;
+
+use rlisp::interpret_bytecode;
+#[cfg(feature = "llvm")]
+use rlisp::run;
+use rlisp::node::*;
+use rlisp::parser::parse;
+use rlisp::error::EvalError;
+use common::{both, eval_err};
+
+#[test]
+fn test_and_or_not() {
+    assert_eq!(both("(and)"), Ok(rtrue()));
+    assert_eq!(both("(and 1 2)"), Ok(rint(2)));
+    assert_eq!(both("(and 1 #f (car 1))"), Ok(rfalse()));
+    assert_eq!(both("(or)"), Ok(rfalse()));
+    assert_eq!(both("(or #f '() (car 1))"), Ok(rnil()));
+    assert_eq!(both("(or #f #f)"), Ok(rfalse()));
+    assert_eq!(both("(or #f (car 1))"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(cons (not #f) (not 0))"), Ok(rcell(rtrue(), rfalse())));
+    assert_eq!(both("(not)"), eval_err(EvalError::InvalidArgNumber));
+}
+
+#[test]
+fn test_when_unless() {
+    assert_eq!(both("(when (= 1 1) 1 2)"), Ok(rint(2)));
+    assert_eq!(both("(when #f (car 1))"), Ok(rnil()));
+    assert_eq!(both("(unless #f 1 2)"), Ok(rint(2)));
+    assert_eq!(both("(unless 0 (car 1))"), Ok(rnil()));
+}
+
+#[test]
+fn test_cond() {
+    assert_eq!(both("(cond (#f 1) ((= 1 1) 2 3) (else 4))"), Ok(rint(3)));
+    assert_eq!(both("(cond (#f 1) (else (car 1)))"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(cond (#f 1) (2))"), Ok(rint(2)));
+    assert_eq!(both("(cond (#f 1))"), Ok(rnil()));
+    assert_eq!(both("(cond ((car '(5)) => (lambda (x) (+ x 1))) (else 0))"), Ok(rint(6)));
+    assert_eq!(both("(cond (#f 1) 2)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(cond (1 => car cdr))"), eval_err(EvalError::WrongTypeArg));
+}
+
+#[test]
+fn test_case() {
+    assert_eq!(both("(case (+ 1 1) ((1) 'one) ((2 3) 'two 'or-three) (else 'many))"),
+               Ok(rsym("or-three")));
+    assert_eq!(both("(case 'b ((a) 1) ((b c) 2))"), Ok(rint(2)));
+    assert_eq!(both("(case 5 ((1) 1) (else => (lambda (x) (* x 2))))"), Ok(rint(10)));
+    assert_eq!(both("(case 5 ((1) 1))"), Ok(rnil()));
+    assert_eq!(both("(case '(1) (((1)) 'list))"), Ok(rsym("list")));
+    assert_eq!(both("(case 1 (1 1))"), eval_err(EvalError::WrongTypeArg));
+    // Datums aren't evaluated, even where they name a variable.
+    assert_eq!(both("((lambda (a) (case 'a ((a) 'sym) (else a))) 1)"), Ok(rsym("sym")));
+}
+
+#[test]
+fn test_control_forms_are_special() {
+    assert_eq!(both("(and and)"), eval_err(EvalError::SpecialFormValue("and".to_string())));
+    assert_eq!(both("(define cond 1)"),
+               eval_err(EvalError::SpecialFormRebind("cond".to_string())));
+    #[cfg(feature = "llvm")]
+    assert_eq!(run("(cond (#f 1) (else (+ 1 2)))"), Ok(rint(3)));
+    assert_eq!(both("(progn (define-syntax my-or
+                      (syntax-rules () ((_ a b) (cond (a => (lambda (x) x)) (else b)))))
+                    ((lambda (x) (my-or #f x)) 7))"),
+               Ok(rint(7)));
+    assert_eq!(both("(macroexpand-all '(case x ((let) 1)))"),
+               Ok(parse("(case x ((let) 1))").unwrap()));
+}
+
+#[test]
+fn test_control_forms_in_tail_position() {
+    // Deep enough to overflow the Rust stack if `bytecode::Machine` didn't
+    // make these tail calls.
+    assert_eq!(interpret_bytecode("(progn
+  (define loop
+    (lambda (i)
+      (cond ((= i 0) 'done)
+            ((= i 1) (loop (- i 1)))
+            (else (and #t (or #f (when #t (unless #f (case 1 ((1) (loop (- i 1))))))))))))
+  (loop 1000000))"),
+               Ok(rsym("done")));
+}
+
+#[test]
+fn test_named_let() {
+    assert_eq!(both("(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))"),
+               Ok(parse("(2 1 0)").unwrap()));
+    // Not a tail call.
+    assert_eq!(both("(let sum ((n 10)) (if (= n 0) 0 (+ n (sum (- n 1)))))"), Ok(rint(55)));
+    // The inits don't see the name, and lambdas in the body do.
+    assert_eq!(both("((lambda (f) (let f ((x f)) (if (= x 0) 'done ((lambda () (f (- x 1)))))))
+                     2)"),
+               Ok(rsym("done")));
+    assert_eq!(both("(let f ((f 1)) f)"), Ok(rint(1)));
+    assert_eq!(both("(let loop ((i 0)) (loop))"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(let loop)"), eval_err(EvalError::InvalidSyntax("let".to_string())));
+}
+
+#[test]
+fn test_comparisons() {
+    assert_eq!(both("(< 1 2 3)"), Ok(rtrue()));
+    assert_eq!(both("(< 1 3 2)"), Ok(rfalse()));
+    assert_eq!(both("(> 3 2 1)"), Ok(rtrue()));
+    assert_eq!(both("(<= 1 1 2)"), Ok(rtrue()));
+    assert_eq!(both("(>= 1 2)"), Ok(rfalse()));
+    assert_eq!(both("(< 1 'a)"), eval_err(EvalError::WrongTypeArg));
+}
+
+#[test]
+fn test_do_while() {
+    assert_eq!(both("(do ((i 0 (+ i 1)) (acc '() (cons i acc))) ((= i 3) acc))"),
+               Ok(parse("(2 1 0)").unwrap()));
+    // Steps see the values before any is updated, and a variable without
+    // one keeps its value.
+    assert_eq!(both("(do ((a 1 b) (b 2 a) (c 0) (n 0 (+ n 1))) ((= n 3) (cons a (cons b c))))"),
+               Ok(rcell(rint(2), rcell(rint(1), rint(0)))));
+    assert_eq!(both("(do ((i 0 (+ i 1))) ((= i 2)))"), Ok(rnil()));
+    assert_eq!(both("(do ((i 0 (+ i 1)) (fs '() (cons (lambda () i) fs))) ((= i 2) ((car fs))))"),
+               Ok(rint(1)));
+    assert_eq!(both("(do ((i)) (#t))"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(do ((n 3 (- n 1)) (acc 0 (+ acc n))) ((= n 0) acc))"), Ok(rint(6)));
+    // `while` runs its body until the test fails, and is nil.
+    assert_eq!(both("(let ((i 0) (acc '()))
+                       (while (< i 3) (define acc (cons i acc)) (define i (+ i 1)))
+                       acc)"),
+               Ok(parse("(2 1 0)").unwrap()));
+    assert_eq!(both("(let ((n 3) (sum 0))
+                       (while (> n 0) (define sum (+ sum n)) (define n (- n 1)))
+                       sum)"),
+               Ok(rint(6)));
+    assert_eq!(both("(let ((n 3)) (while (> n 0) (define n (- n 1))))"), Ok(rnil()));
+    assert_eq!(both("(while #f (car 1))"), Ok(rnil()));
+}
+
+#[test]
+fn test_loops_in_constant_space() {
+    assert_eq!(both("(do ((i 0 (+ i 1))) ((= i 100000) i))"), Ok(rint(100000)));
+    assert_eq!(both("(let ((i 0)) (while (< i 100000) (define i (+ i 1))) i)"),
+               Ok(rint(100000)));
+    assert_eq!(both("(let loop ((i 0)) (cond ((= i 100000) i) (else (loop (+ i 1)))))"),
+               Ok(rint(100000)));
+}
+
+#[test]
+fn test_let_star() {
+    assert_eq!(both("(let ((x 1)) (define x (+ x 1)) (* x 10))"), Ok(rint(20)));
+    assert_eq!(both("(let* ((x 1) (y (+ x 1))) (cons x y))"), Ok(rcell(rint(1), rint(2))));
+    assert_eq!(both("(let* ((x 1) (x (+ x 1))) x)"), Ok(rint(2)));
+    assert_eq!(both("((lambda (x) (let* ((y x) (x 5)) (cons x y))) 1)"),
+               Ok(rcell(rint(5), rint(1))));
+    assert_eq!(both("(let* () 1 2)"), Ok(rint(2)));
+    assert_eq!(both("(let* ((f (lambda () 1)) (g (lambda () (f)))) (g))"), Ok(rint(1)));
+}
+
+#[test]
+fn test_malformed_let_bindings() {
+    for form in &["let", "let*"] {
+        let err = eval_err(EvalError::InvalidSyntax(form.to_string()));
+        assert_eq!(both(&format!("({} ((a)) 1)", form)), err);
+        assert_eq!(both(&format!("({} (1) 1)", form)), err);
+        assert_eq!(both(&format!("({} ((1 2)) 1)", form)), err);
+        assert_eq!(both(&format!("({} ((a 1 2)) a)", form)), err);
+    }
+}
@@ -0,0 +1 @@
+(car (cdr (cdr '(1 2 (3 4)))))
@@ -0,0 +1,4 @@
+(progn
+  (define x (+ 1 2))
+  (define y (* x x))
+  (- y x))
@@ -0,0 +1 @@
+(/ 100 5 2)
@@ -0,0 +1,3 @@
+((lambda (f1 f2) (f2 (f1 10) (f1 20)))
+ (lambda (x) x)
+ (lambda (x y) (+ x y)))
@@ -0,0 +1,3 @@
+(let ((a 10)
+      (b 3))
+  (- (* a b) (/ a b)))
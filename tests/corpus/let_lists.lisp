@@ -0,0 +1,2 @@
+(let ((xs (cons 1 (cons 2 '()))))
+  (cons (car (cdr xs)) (car xs)))
@@ -0,0 +1 @@
+(cons 1 (cons (+ 1 1) '(3 (4 a) b)))
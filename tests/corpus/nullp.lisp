@@ -0,0 +1 @@
+(cons (null? '()) (null? (cons 1 2)))
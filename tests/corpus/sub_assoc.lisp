@@ -0,0 +1 @@
+(- 10 2 3)
@@ -0,0 +1 @@
+(* -3 (- 4) (- 0 5))
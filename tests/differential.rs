@@ -0,0 +1,297 @@
+// Runs programs through the interpreter, the bytecode machine and the JIT
+// and checks they agree. Programs come from `tests/corpus` and from a random generator for
+// the subset the compiler supports. Where the interpreter returns an error
+// the compiled program has to trap, and the JIT runs in a child process so
+// that only ends that program.
+//
+// RLISP_FUZZ_SEED and RLISP_FUZZ_ITERATIONS override the generator's
+// defaults, e.g. to reproduce a failure reported for a given seed.
+
+#![cfg(feature = "llvm")]
+
+extern crate rlisp;
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+use rlisp::codegen::VM;
+use rlisp::error::{EvalError, RLispError};
+use rlisp::parser::parse;
+
+enum Outcome {
+    Agree,
+    // The compiler rejected the program, which is outside the supported
+    // subset.
+    Skipped,
+    Mismatch(String),
+}
+
+// What a program compiled by the JIT did.
+enum Jit {
+    Value(String),
+    Unsupported,
+    // Killed by a signal: an abort from the runtime or a trap in the
+    // compiled code, which is how it reports what the interpreter returns as
+    // an error.
+    Trap(String),
+}
+
+const PROGRAM_VAR: &'static str = "RLISP_DIFFERENTIAL_PROGRAM";
+const VALUE_PREFIX: &'static str = "jit value: ";
+
+// Compiles and runs the program in `PROGRAM_VAR` when this binary runs itself
+// in `jit`; on its own it does nothing.
+#[test]
+fn jit_child() {
+    if let Ok(input) = env::var(PROGRAM_VAR) {
+        match VM::new().jit(&parse(input).unwrap()) {
+            Ok(v) => println!("{}{:?}", VALUE_PREFIX, v),
+            Err(e) => println!("unsupported: {}", e),
+        }
+    }
+}
+
+// Runs `input` with the JIT in a child process, so a trap ends that program
+// and not the whole test run.
+fn jit(input: &str) -> Jit {
+    let output = Command::new(env::current_exe().unwrap())
+        .args(&["--exact", "jit_child", "--nocapture", "--test-threads=1"])
+        .env(PROGRAM_VAR, input)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if output.status.code().is_none() {
+        return Jit::Trap(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    assert!(output.status.success(),
+            "{}: the JIT child failed\n{}",
+            input,
+            String::from_utf8_lossy(&output.stderr));
+    // The harness may have started the line with the test's name.
+    match stdout.find(VALUE_PREFIX) {
+        Some(i) => {
+            let value = &stdout[i + VALUE_PREFIX.len()..];
+            Jit::Value(value.lines().next().unwrap_or("").to_string())
+        }
+        None => Jit::Unsupported,
+    }
+}
+
+fn differ(input: &str) -> Outcome {
+    let expected = rlisp::interpret(input);
+    // The two evaluators run the same language, so they agree on errors too.
+    let bytecode = rlisp::interpret_bytecode(input);
+    if bytecode != expected {
+        return Outcome::Mismatch(format!("{}\n  interpreter: {:?}\n  bytecode:    {:?}",
+                                         input,
+                                         expected,
+                                         bytecode));
+    }
+    let actual = jit(input);
+    match (&expected, &actual) {
+        // Malformed programs are refused by both.
+        (&Err(RLispError::EvalError(EvalError::InvalidSyntax(_))), &Jit::Unsupported) => {
+            Outcome::Agree
+        }
+        (_, &Jit::Unsupported) => Outcome::Skipped,
+        (&Ok(ref v), &Jit::Value(ref a)) if format!("{:?}", v) == *a => Outcome::Agree,
+        (&Err(_), &Jit::Trap(_)) => Outcome::Agree,
+        _ => {
+            let actual = match actual {
+                Jit::Value(v) => v,
+                Jit::Trap(e) => format!("trap ({})", e),
+                Jit::Unsupported => unreachable!(),
+            };
+            Outcome::Mismatch(format!("{}\n  interpreter: {:?}\n  jit:         {}",
+                                      input,
+                                      expected,
+                                      actual))
+        }
+    }
+}
+
+#[test]
+fn test_corpus() {
+    let mut mismatches = Vec::new();
+    let mut paths: Vec<_> = fs::read_dir("tests/corpus")
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+
+    for path in paths.iter() {
+        let mut input = String::new();
+        File::open(path).unwrap().read_to_string(&mut input).unwrap();
+        match differ(&input) {
+            Outcome::Agree => {}
+            Outcome::Skipped => {
+                mismatches.push(format!("{}: not compiled by the JIT", path.display()))
+            }
+            Outcome::Mismatch(m) => mismatches.push(format!("{}: {}", path.display(), m)),
+        }
+    }
+    assert!(mismatches.is_empty(), "\n{}", mismatches.join("\n"));
+}
+
+// Outside what the JIT compiles, the interpreter and the bytecode machine
+// still have to agree.
+#[test]
+fn test_evaluators_agree() {
+    let programs = ["(progn (define f (lambda (n) (if (= n 0) 0 (f (- n 1))))) (f 3))",
+                    "(progn (define f (lambda () x)) (define x 1) (f))",
+                    "(progn (define f (lambda () x)) (f))",
+                    "(let ((f (lambda (n) n))) (progn (define g (lambda () (f 2))) (g)))"];
+    for input in programs.iter() {
+        let expected = rlisp::interpret(*input);
+        assert_eq!(rlisp::interpret_bytecode(*input), expected, "{}", input);
+    }
+    assert_eq!(rlisp::interpret(programs[0]), Ok(rlisp::node::rint(0)));
+}
+
+// xorshift64*, so a seed reproduces the same programs everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545F4914F6CDD1D) >> 33) as usize % n
+    }
+}
+
+// Generates well-typed programs, so most of them get past both backends.
+struct Generator {
+    rng: Rng,
+    vars: Vec<String>,
+    fresh: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        Generator {
+            rng: Rng(seed | 1),
+            vars: Vec::new(),
+            fresh: 0,
+        }
+    }
+
+    fn fresh_var(&mut self) -> String {
+        self.fresh += 1;
+        format!("v{}", self.fresh)
+    }
+
+    fn literal(&mut self) -> String {
+        (self.rng.below(101) as i32 - 50).to_string()
+    }
+
+    fn int_expr(&mut self, depth: usize) -> String {
+        if depth == 0 || self.rng.below(4) == 0 {
+            return match self.vars.len() {
+                n if n > 0 && self.rng.below(2) == 0 => self.vars[self.rng.below(n)].clone(),
+                _ => self.literal(),
+            };
+        }
+
+        match self.rng.below(6) {
+            0 | 1 => {
+                let op = ["+", "-", "*", "/"][self.rng.below(4)];
+                let args: Vec<String> = (0..self.rng.below(3) + 1)
+                    .map(|_| self.int_expr(depth - 1))
+                    .collect();
+                format!("({} {})", op, args.join(" "))
+            }
+            2 => {
+                let val = self.int_expr(depth - 1);
+                let var = self.fresh_var();
+                self.vars.push(var.clone());
+                let body = self.int_expr(depth - 1);
+                self.vars.pop();
+                format!("(let (({} {})) {})", var, val, body)
+            }
+            3 => {
+                let (a, b) = (self.int_expr(depth - 1), self.int_expr(depth - 1));
+                let (x, y) = (self.fresh_var(), self.fresh_var());
+                self.vars.push(x.clone());
+                self.vars.push(y.clone());
+                let body = self.int_expr(depth - 1);
+                self.vars.pop();
+                self.vars.pop();
+                format!("((lambda ({} {}) {}) {} {})", x, y, body, a, b)
+            }
+            4 => {
+                let (i, l) = (self.int_expr(depth - 1), self.list_expr(depth - 1));
+                format!("(car (cons {} {}))", i, l)
+            }
+            _ => {
+                let l = self.list_expr(depth - 1);
+                let x = self.fresh_var();
+                format!("(let (({} (cons {} {}))) (car {}))", x, self.literal(), l, x)
+            }
+        }
+    }
+
+    fn list_expr(&mut self, depth: usize) -> String {
+        if depth == 0 || self.rng.below(3) == 0 {
+            let items: Vec<String> = (0..self.rng.below(4)).map(|_| self.literal()).collect();
+            return format!("'({})", items.join(" "));
+        }
+
+        match self.rng.below(3) {
+            0 | 1 => {
+                let (i, l) = (self.int_expr(depth - 1), self.list_expr(depth - 1));
+                format!("(cons {} {})", i, l)
+            }
+            _ => {
+                let (i, l) = (self.int_expr(depth - 1), self.list_expr(depth - 1));
+                format!("(cdr (cons {} {}))", i, l)
+            }
+        }
+    }
+
+    fn program(&mut self) -> String {
+        self.fresh = 0;
+        match self.rng.below(4) {
+            0 => self.list_expr(4),
+            1 => {
+                let val = self.int_expr(3);
+                let var = self.fresh_var();
+                self.vars.push(var.clone());
+                let body = self.int_expr(4);
+                self.vars.pop();
+                format!("(progn (define {} {}) {})", var, val, body)
+            }
+            _ => self.int_expr(5),
+        }
+    }
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+#[test]
+fn test_random_programs() {
+    let seed = env_or("RLISP_FUZZ_SEED", 0x726c697370);
+    let iterations = env_or("RLISP_FUZZ_ITERATIONS", 300);
+    let gen = &mut Generator::new(seed);
+
+    let mut agreed = 0;
+    let mut mismatches = Vec::new();
+    for _ in 0..iterations {
+        let input = gen.program();
+        match differ(&input) {
+            Outcome::Agree => agreed += 1,
+            Outcome::Skipped => {}
+            Outcome::Mismatch(m) => mismatches.push(m),
+        }
+    }
+    assert!(mismatches.is_empty(),
+            "seed {}:\n{}",
+            seed,
+            mismatches.join("\n"));
+    // Guards against the generator drifting out of the compiled subset.
+    assert!(agreed * 2 > iterations, "only {} of {} programs compiled", agreed, iterations);
+}
@@ -1,13 +1,15 @@
 extern crate rlisp;
 
+use std::rc::Rc;
+use rlisp::Interpreter;
 use rlisp::env::Env;
-use rlisp::node::{Node, rint};
+use rlisp::node::{Node, Prim, rint};
 
 #[test]
 fn test_register_and_get_int_value_when_local_is_empty() {
     let renv = &mut Env::new();
     renv.register("x", rint(1));
-    assert_eq!(*(renv.find("x").unwrap()), rint(1));
+    assert_eq!(renv.find("x").unwrap(), rint(1));
 }
 
 #[test]
@@ -24,8 +26,85 @@ fn test_push_and_pop_scope() {
     renv.push_local_scope();
 
     renv.register("x", rint(10));
-    assert_eq!(*(renv.find("x").unwrap()), rint(10));
+    assert_eq!(renv.find("x").unwrap(), rint(10));
 
     renv.pop_local_scope();
-    assert_eq!(*(renv.find("x").unwrap()), rint(1));
+    assert_eq!(renv.find("x").unwrap(), rint(1));
+}
+
+#[test]
+fn test_local_by_position() {
+    let renv = &mut Env::new();
+    renv.push_local_scope();
+    renv.register("x", rint(1));
+    renv.register("y", rint(2));
+    renv.push_local_scope();
+    renv.register("z", rint(3));
+    renv.register("x", rint(4));
+    assert_eq!(renv.local(0, 1), Some(&rint(4)));
+    assert_eq!(renv.local(1, 1), Some(&rint(2)));
+    assert_eq!(renv.local(2, 0), None);
+
+    // Registering a name again keeps its index.
+    renv.register("z", rint(5));
+    assert_eq!(renv.local(0, 0), Some(&rint(5)));
+}
+
+#[test]
+fn test_globals_are_shared_by_clones() {
+    let renv = &mut Env::new();
+    renv.register("x", rint(1));
+    let clone = &mut renv.clone();
+    renv.register("y", rint(2));
+
+    let slot = renv.global_slot("y");
+    assert_eq!(clone.global_slot("y"), slot);
+    assert_eq!(renv.global(slot), Some(rint(2)));
+    assert_eq!(clone.global(slot), Some(rint(2)));
+    assert_eq!(clone.global_name(slot), "y");
+
+    clone.register("x", rint(3));
+    assert_eq!(renv.find("x"), Some(rint(3)));
+    // Local variables aren't.
+    clone.push_local_scope();
+    clone.register("z", rint(4));
+    assert_eq!(renv.find("z"), None);
+}
+
+#[test]
+fn test_fork_has_globals_of_its_own() {
+    let renv = &mut Env::new();
+    renv.register("x", rint(1));
+    let fork = &mut renv.fork();
+    fork.register("x", rint(2));
+    fork.register("y", rint(3));
+    assert_eq!(fork.find("x"), Some(rint(2)));
+    assert_eq!(renv.find("x"), Some(rint(1)));
+    assert_eq!(renv.find("y"), None);
+}
+
+// What lambdas, macros and continuations stored in globals keep of their
+// environment doesn't keep the globals alive.
+#[test]
+fn test_globals_are_freed() {
+    let probe = Rc::new(());
+    {
+        let session = &mut Interpreter::new();
+        let p = probe.clone();
+        let f = move |_: &mut Env<Node>, _: &Node| {
+            let _ = &p;
+            Ok(rint(1))
+        };
+        session.env().register("probe", Node::Prim(Prim::Proc(Rc::new(f))));
+        for input in ["(define f (lambda () (probe)))",
+                      "(defmacro m () '(probe))",
+                      "(define g (let loop ((i 0)) (lambda () (loop i))))",
+                      "(define k (call/cc (lambda (c) c)))"]
+            .iter() {
+            session.eval(*input).unwrap();
+        }
+        assert_eq!(session.eval("(+ (f) (m))"), Ok(rint(2)));
+        assert_eq!(Rc::strong_count(&probe), 2);
+    }
+    assert_eq!(Rc::strong_count(&probe), 1);
 }
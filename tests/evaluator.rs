@@ -5,22 +5,28 @@ use rlisp::primitives::*;
 use rlisp::evaluator::eval;
 use rlisp::env::Env;
 use rlisp::node::*;
+use rlisp::error::EvalError;
 
 fn test_init(env: &mut Env<Node>) {
     env.register("+", Node::Prim(Prim::Proc(Rc::new(prim_add))));
     env.register("-", Node::Prim(Prim::Proc(Rc::new(prim_sub))));
     env.register("*", Node::Prim(Prim::Proc(Rc::new(prim_mul))));
+    env.register("/", Node::Prim(Prim::Proc(Rc::new(prim_div))));
     env.register("=", Node::Prim(Prim::Proc(Rc::new(prim_eq))));
     env.register("<", Node::Prim(Prim::Proc(Rc::new(prim_lt))));
     env.register(">", Node::Prim(Prim::Proc(Rc::new(prim_gt))));
     env.register("<=", Node::Prim(Prim::Proc(Rc::new(prim_lte))));
     env.register(">=", Node::Prim(Prim::Proc(Rc::new(prim_gte))));
-    env.register("if", Node::Prim(Prim::Proc(Rc::new(prim_if))));
-    env.register("quote", Node::Prim(Prim::Proc(Rc::new(prim_quote))));
-    env.register("lambda", Node::Prim(Prim::Proc(Rc::new(prim_lambda))));
-    env.register("progn", Node::Prim(Prim::Proc(Rc::new(prim_progn))));
-    env.register("define", Node::Prim(Prim::Proc(Rc::new(prim_define))));
-    env.register("let", Node::Prim(Prim::Proc(Rc::new(prim_let))));
+    env.register("if", Node::Prim(Prim::Special(Rc::new(prim_if))));
+    env.register("quote", Node::Prim(Prim::Special(Rc::new(prim_quote))));
+    env.register("lambda", Node::Prim(Prim::Special(Rc::new(prim_lambda))));
+    env.register("progn", Node::Prim(Prim::Special(Rc::new(prim_progn))));
+    env.register("define", Node::Prim(Prim::Special(Rc::new(prim_define))));
+    env.register("let", Node::Prim(Prim::Special(Rc::new(prim_let))));
+    env.register("cons", Node::Prim(Prim::Proc(Rc::new(prim_cons))));
+    env.register("car", Node::Prim(Prim::Proc(Rc::new(prim_car))));
+    env.register("cdr", Node::Prim(Prim::Proc(Rc::new(prim_cdr))));
+    env.register("null?", Node::Prim(Prim::Proc(Rc::new(prim_nullp))));
 }
 
 #[test]
@@ -80,10 +86,16 @@ fn test_eval_sub_prim() {
     let t2 = rcell(rsym("-"), rcell(rint(3), rlist(rint(2), rint(1))));
     // (- 3 (- 2 1))
     let t3 = rcell(rsym("-"), rlist(rint(3), rcell(rsym("-"), rlist(rint(2), rint(1)))));
+    // (- 5)
+    let t4 = rcell(rsym("-"), rcell(rint(5), rnil()));
+    // (-)
+    let t5 = rcell(rsym("-"), rnil());
 
     assert_eq!(eval(env, &t1), Ok(rint(1)));
     assert_eq!(eval(env, &t2), Ok(rint(0)));
     assert_eq!(eval(env, &t3), Ok(rint(2)));
+    assert_eq!(eval(env, &t4), Ok(rint(-5)));
+    assert_eq!(eval(env, &t5), Err(EvalError::InvalidArgNumber));
 }
 
 #[test]
@@ -102,6 +114,50 @@ fn test_eval_mul_prim() {
     assert_eq!(eval(env, &t3), Ok(rint(6)));
 }
 
+#[test]
+fn test_eval_div_prim() {
+    let env = &mut Env::new();
+    test_init(env);
+    // (/ 6 3)
+    let t1 = rcell(rsym("/"), rlist(rint(6), rint(3)));
+    // (/ -6 3)
+    let t2 = rcell(rsym("/"), rlist(rint(-6), rint(3)));
+    // (/ 12 3 2)
+    let t3 = rcell(rsym("/"), rcell(rint(12), rlist(rint(3), rint(2))));
+    // (/ -7 2)
+    let t4 = rcell(rsym("/"), rlist(rint(-7), rint(2)));
+    // (/ 1)
+    let t5 = rcell(rsym("/"), rcell(rint(1), rnil()));
+
+    assert_eq!(eval(env, &t1), Ok(rint(2)));
+    assert_eq!(eval(env, &t2), Ok(rint(-2)));
+    assert_eq!(eval(env, &t3), Ok(rint(2)));
+    assert_eq!(eval(env, &t4), Ok(rint(-3)));
+    assert_eq!(eval(env, &t5), Ok(rint(1)));
+}
+
+#[test]
+fn test_eval_arith_errors() {
+    let env = &mut Env::new();
+    test_init(env);
+    // (/ 1 0)
+    let t1 = rcell(rsym("/"), rlist(rint(1), rint(0)));
+    // (+ 2147483647 1)
+    let t2 = rcell(rsym("+"), rlist(rint(i32::max_value()), rint(1)));
+    // (- -2147483648 1)
+    let t3 = rcell(rsym("-"), rlist(rint(i32::min_value()), rint(1)));
+    // (* 65536 65536)
+    let t4 = rcell(rsym("*"), rlist(rint(65536), rint(65536)));
+    // (/ -2147483648 -1)
+    let t5 = rcell(rsym("/"), rlist(rint(i32::min_value()), rint(-1)));
+
+    assert_eq!(eval(env, &t1), Err(EvalError::DivisionByZero));
+    assert_eq!(eval(env, &t2), Err(EvalError::Overflow));
+    assert_eq!(eval(env, &t3), Err(EvalError::Overflow));
+    assert_eq!(eval(env, &t4), Err(EvalError::Overflow));
+    assert_eq!(eval(env, &t5), Err(EvalError::Overflow));
+}
+
 #[test]
 fn test_eval_cmp_prims() {
     let env = &mut Env::new();
@@ -151,8 +207,8 @@ fn test_eval_define_prim() {
     assert_eq!(eval(env, &t1), Ok(rint(1)));
     assert_eq!(eval(env, &t2), Ok(rint(3)));
 
-    assert_eq!(*(env.find("x").unwrap()), rint(1));
-    assert_eq!(*(env.find("y").unwrap()), rint(3));
+    assert_eq!(env.find("x").unwrap(), rint(1));
+    assert_eq!(env.find("y").unwrap(), rint(3));
 }
 
 #[test]
@@ -228,3 +284,53 @@ fn test_eval_let_prim() {
     assert_eq!(eval(env, &t2), Ok(rint(-1)));
 
 }
+
+#[test]
+fn test_eval_list_prims() {
+    let env = &mut Env::new();
+    test_init(env);
+    // (cons 1 2)
+    let t1 = rcell(rsym("cons"), rlist(rint(1), rint(2)));
+    // (car '(1 2))
+    let t2 = rlist(rsym("car"), rquote(rlist(rint(1), rint(2))));
+    // (cdr '(1 2))
+    let t3 = rlist(rsym("cdr"), rquote(rlist(rint(1), rint(2))));
+    // (null? '())
+    let t4 = rlist(rsym("null?"), rquote(rnil()));
+    // (null? 1)
+    let t5 = rlist(rsym("null?"), rint(1));
+    // (car 1)
+    let t6 = rlist(rsym("car"), rint(1));
+    // (cons 1)
+    let t7 = rlist(rsym("cons"), rint(1));
+
+    assert_eq!(eval(env, &t1), Ok(rcell(rint(1), rint(2))));
+    assert_eq!(eval(env, &t2), Ok(rint(1)));
+    assert_eq!(eval(env, &t3), Ok(rcell(rint(2), rnil())));
+    assert_eq!(eval(env, &t4), Ok(rtrue()));
+    assert_eq!(eval(env, &t5), Ok(rfalse()));
+    assert_eq!(eval(env, &t6), Err(EvalError::WrongTypeArg));
+    assert_eq!(eval(env, &t7), Err(EvalError::InvalidArgNumber));
+}
+
+#[test]
+fn test_eval_special_forms() {
+    let env = &mut Env::new();
+    test_init(env);
+    // ((if #t if +) 1 2)
+    let t1 = rcell(rcell(rsym("if"), rcell(rtrue(), rlist(rsym("if"), rsym("+")))),
+                   rlist(rint(1), rint(2)));
+    // (define if 1)
+    let t2 = rcell(rsym("define"), rlist(rsym("if"), rint(1)));
+    // ((lambda (if) (if 1)) car)
+    let t3 = rlist(rcell(rsym("lambda"),
+                         rlist(rcell(rsym("if"), rnil()),
+                               rlist(rsym("if"), rquote(rcell(rint(1), rnil()))))),
+                   rsym("car"));
+
+    assert_eq!(eval(env, &t1), Err(EvalError::SpecialFormValue("if".to_string())));
+    assert_eq!(eval(env, &t2), Err(EvalError::SpecialFormRebind("if".to_string())));
+    assert_eq!(eval(env, &rsym("quote")), Err(EvalError::SpecialFormValue("quote".to_string())));
+    // A parameter can still shadow one.
+    assert_eq!(eval(env, &t3), Ok(rint(1)));
+}
@@ -0,0 +1,190 @@
+extern crate rlisp;
+
+mod common;
+
+use std::rc::Rc;
+use rlisp::node::*;
+use rlisp::printer::pretty;
+use rlisp::error::EvalError;
+use common::{both, eval_err};
+
+fn list(items: Vec<Node>) -> Node {
+    items.into_iter().rev().fold(Node::Nil, |rest, v| rcell(v, rest))
+}
+
+#[test]
+fn test_guard() {
+    assert_eq!(both("(guard (e (#t 0)) 1 2)"), Ok(rint(2)));
+    assert_eq!(both("(guard (e ((= e 42) 'answer)) (raise 42))"), Ok(rsym("answer")));
+    assert_eq!(both("(guard (e ((= e 1) 'one) (else (cons 'other e))) (raise 2))"),
+               Ok(rcell(rsym("other"), rint(2))));
+    assert_eq!(both("(guard (e ((cdr e) => car)) (raise '(1 2)))"), Ok(rint(2)));
+    // From a procedure called in the body.
+    assert_eq!(both("(let ((f (lambda (n) (if (= n 3) (raise n) n))))
+                       (guard (e (#t (* e 10))) (do ((i 0 (+ i 1))) (#f) (f i))))"),
+               Ok(rint(30)));
+    // The variables of the `guard` are back.
+    assert_eq!(both("(let ((x 1)) (guard (e (#t (+ x e))) (let ((x 10)) (raise x))))"),
+               Ok(rint(11)));
+    // When no clause applies it is raised again, to the next `guard` out.
+    assert_eq!(both("(guard (e (#t (cons 'outer e))) (guard (e ((= e 1) 'one)) (raise 2)))"),
+               Ok(rcell(rsym("outer"), rint(2))));
+    assert_eq!(both("(guard (e ((= e 1) 'one)) (raise 2))"),
+               eval_err(EvalError::Raise(rint(2))));
+    // `throw`s and escapes go past it.
+    assert_eq!(both("(catch 'a (guard (e (#t 'guarded)) (throw 'a 1)))"), Ok(rint(1)));
+    assert_eq!(both("(call/ec (lambda (k) (guard (e (#t 'guarded)) (k 1))))"), Ok(rint(1)));
+    // Cleanups on the way run.
+    assert_eq!(both("(catch 'c
+                       (guard (e (#t 'guarded)) (unwind-protect (raise 1) (throw 'c 'cleaned))))"),
+               Ok(rsym("cleaned")));
+    assert_eq!(both("(guard (e (#t 'guarded)) (guard (e (#f 0)) (unwind-protect (raise 1) 2)))"),
+               Ok(rsym("guarded")));
+}
+
+#[test]
+fn test_error_objects() {
+    assert_eq!(both("(guard (e (#t (error-object-message e))) (error 'oops 1 2))"),
+               Ok(rsym("oops")));
+    assert_eq!(both("(guard (e (#t (error-object-irritants e))) (error 'oops 1 2))"),
+               Ok(list(vec![rint(1), rint(2)])));
+    assert_eq!(both("(guard (e (#t (error-object-irritants e))) (error 'oops))"), Ok(rnil()));
+    assert_eq!(both("(guard (e (#t (error-object? e))) (error 'oops))"), Ok(rtrue()));
+    assert_eq!(both("(guard (e (#t (error-object? e))) (raise 1))"), Ok(rfalse()));
+    // Errors of the evaluators are raised as conditions.
+    assert_eq!(both("(guard (e ((error-object? e) (error-object-message e))) (car 1))"),
+               Ok(rsym("wrong-type-argument")));
+    assert_eq!(both("(guard (e (#t (error-object-message e))) (/ 1 0))"),
+               Ok(rsym("division-by-zero")));
+    assert_eq!(both("(guard (e (#t (cons (error-object-message e) (error-object-irritants e))))
+                       (undefined-variable))"),
+               Ok(list(vec![rsym("unknown-symbol"), rsym("undefined-variable")])));
+    // And end with the error they were made of when nothing handles them.
+    assert_eq!(both("(guard (e (#f 0)) (car 1))"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(error 'oops 1)"),
+               eval_err(EvalError::Error(rsym("oops"), list(vec![rint(1)]))));
+    assert_eq!(both("(raise 'oops)"), eval_err(EvalError::Raise(rsym("oops"))));
+}
+
+#[test]
+fn test_with_exception_handler() {
+    assert_eq!(both("(with-exception-handler (lambda (c) (* c 2))
+                                             (lambda () (+ 1 (raise-continuable 20))))"),
+               Ok(rint(41)));
+    assert_eq!(both("(with-exception-handler (lambda (c) 0) (lambda () 1))"), Ok(rint(1)));
+    // The handler of a `raise` has to escape.
+    assert_eq!(both("(call/ec (lambda (k)
+                       (with-exception-handler (lambda (c) (k (cons 'handled c)))
+                                               (lambda () (raise 1)))))"),
+               Ok(rcell(rsym("handled"), rint(1))));
+    assert_eq!(both("(call/ec (lambda (k)
+                       (with-exception-handler (lambda (c) (k (error-object-message c)))
+                                               (lambda () (car 1)))))"),
+               Ok(rsym("wrong-type-argument")));
+    assert_eq!(both("(with-exception-handler (lambda (c) 0) (lambda () (raise 'oops)))"),
+               eval_err(EvalError::HandlerReturned(rsym("oops"))));
+    assert_eq!(both("(guard (e ((error-object? e) (error-object-message e)))
+                       (with-exception-handler (lambda (c) 0) (lambda () (raise 'oops))))"),
+               Ok(rsym("handler-returned")));
+    // It is called where the exception is raised, before anything is
+    // unwound.
+    assert_eq!(both("(with-exception-handler (lambda (c) (throw 'inner c))
+                                             (lambda () (catch 'inner (raise 5))))"),
+               Ok(rint(5)));
+    assert_eq!(both("(with-exception-handler (lambda (c) 10)
+                       (lambda () (unwind-protect (+ 1 (raise-continuable 1)) 2)))"),
+               Ok(rint(11)));
+    // With the handlers outside it in place.
+    assert_eq!(both("(with-exception-handler (lambda (c) (+ c 100))
+                       (lambda ()
+                         (with-exception-handler (lambda (c) (raise-continuable (+ c 10)))
+                                                 (lambda () (raise-continuable 1)))))"),
+               Ok(rint(111)));
+    assert_eq!(both("(with-exception-handler (lambda (c) (raise-continuable (+ c 1)))
+                                             (lambda () (raise-continuable 1)))"),
+               eval_err(EvalError::Raise(rint(2))));
+    assert_eq!(both("(guard (e (#t (cons 'outer e)))
+                       (with-exception-handler (lambda (c) (raise (cons 'again c)))
+                         (lambda () (guard (e ((= e 0) 'inner)) (raise 1)))))"),
+               Ok(rcell(rsym("outer"), rcell(rsym("again"), rint(1)))));
+    // A `guard` gets what is raised continuably too, and raises it again the
+    // same way when no clause applies.
+    assert_eq!(both("(guard (e (#t (cons 'caught e))) (+ 1 (raise-continuable 1)))"),
+               Ok(rcell(rsym("caught"), rint(1))));
+    assert_eq!(both("(with-exception-handler (lambda (e) 10)
+                       (lambda () (guard (e ((= e 2) 'two)) (raise-continuable 1))))"),
+               Ok(rint(10)));
+    assert_eq!(both("(with-exception-handler (lambda (e) (* e 10))
+                       (lambda ()
+                         (+ 1 (guard (e ((= e 2) 'two))
+                                (guard (e (#f 0)) (raise-continuable 3))))))"),
+               Ok(rint(31)));
+    assert_eq!(both("(with-exception-handler (lambda (e) 10)
+                       (lambda () (guard (e ((= e 2) 'two)) (raise 1))))"),
+               eval_err(EvalError::HandlerReturned(rint(1))));
+}
+
+#[test]
+fn test_guard_in_macro() {
+    // The variable of a `guard` in a template doesn't capture the caller's.
+    assert_eq!(both("(progn
+                       (define-syntax try
+                         (syntax-rules () ((_ body handler) (guard (e (#t handler)) body))))
+                       (let ((e 5)) (try (car 1) e)))"),
+               Ok(rint(5)));
+}
+
+#[test]
+fn test_exception_errors() {
+    assert_eq!(both("(with-exception-handler (lambda (c) c))"),
+               eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(raise)"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(raise-continuable 1 2)"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(error)"), eval_err(EvalError::InvalidArgNumber));
+    assert_eq!(both("(error-object-message 1)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(guard)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("(guard (1 (#t 0)) 2)"), eval_err(EvalError::WrongTypeArg));
+}
+
+#[test]
+fn test_condition_display() {
+    let e = EvalError::Error(rsym("oops"), list(vec![rint(1), rsym("x")]));
+    assert_eq!(e.to_string(), "Error: oops 1 x");
+    assert_eq!(pretty(&Node::Condition(Rc::new(e))), "#<error oops 1 x>");
+    assert_eq!(pretty(&Node::Condition(Rc::new(EvalError::WrongTypeArg))),
+               "#<error wrong-type-argument>");
+}
+
+// Handlers see an error once, however many cleanups it unwinds through.
+#[test]
+fn test_cleanups_with_handlers() {
+    assert_eq!(both("(with-exception-handler (lambda (e) (cons 'h e))
+                       (lambda () (unwind-protect (raise 'x) 'c)))"),
+               eval_err(EvalError::HandlerReturned(rsym("x"))));
+    let wrong_type = Node::Condition(Rc::new(EvalError::WrongTypeArg));
+    assert_eq!(both("(with-exception-handler (lambda (e) (cons 'h e))
+                       (lambda ()
+                         (unwind-protect
+                           (dynamic-wind (lambda () 0)
+                                         (lambda () (unwind-protect (car 1) 'c))
+                                         (lambda () 1))
+                           'd)))"),
+               eval_err(EvalError::HandlerReturned(wrong_type)));
+    assert_eq!(both("(guard (e (#t (cons 'caught e)))
+                       (unwind-protect (unwind-protect (raise 'x) 1) 2))"),
+               Ok(rcell(rsym("caught"), rsym("x"))));
+    assert_eq!(both("(guard (e ((error-object? e) (error-object-message e)))
+                       (with-exception-handler (lambda (e) (cons 'h e))
+                         (lambda () (unwind-protect (unwind-protect (raise 'x) 1) 2))))"),
+               Ok(rsym("handler-returned")));
+}
+
+// The example in README.md.
+#[test]
+fn test_readme_guard_example() {
+    let guard = "(guard (e ((error-object? e) (error-object-message e))
+                           (else (cons 'raised e)))";
+    assert_eq!(both(&format!("{} (/ 1 0))", guard)), Ok(rsym("division-by-zero")));
+    assert_eq!(both(&format!("{} (raise 'oops))", guard)),
+               Ok(rcell(rsym("raised"), rsym("oops"))));
+}
@@ -0,0 +1,122 @@
+extern crate rlisp;
+
+mod common;
+
+use rlisp::Interpreter;
+use rlisp::env::Env;
+use rlisp::expander::{expand, Expander};
+use rlisp::node::*;
+use rlisp::parser::parse;
+use rlisp::error::EvalError;
+use common::{both, eval_err};
+
+#[test]
+fn test_syntax_rules() {
+    assert_eq!(both("(progn
+  (define-syntax my-if (syntax-rules () ((_ c a b) (if c a b))))
+  ((lambda (x) (my-if (= x 1) 'one (car 1))) 1))"),
+               Ok(rsym("one")));
+    // Several rules, ellipses and literals.
+    assert_eq!(both("(progn
+  (define-syntax my-or
+    (syntax-rules ()
+      ((_) #f)
+      ((_ e) e)
+      ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+  (cons (my-or) (cons (my-or #f 2 (car 1)) '())))"),
+               Ok(rcell(rfalse(), rcell(rint(2), rnil()))));
+    assert_eq!(both("(progn
+  (define-syntax my-list
+    (syntax-rules ()
+      ((_ (a b) ...) '((b a) ... end))))
+  (my-list (1 2) (3 4)))"),
+               Ok(parse("((2 1) (4 3) end)").unwrap()));
+    assert_eq!(both("(progn
+  (define-syntax pick
+    (syntax-rules (left right)
+      ((_ left a b) a)
+      ((_ right a b) b)))
+  (pick right 1 2))"),
+               Ok(rint(2)));
+    assert_eq!(both("(progn
+  (define-syntax flat
+    (syntax-rules ()
+      ((_ (a ...) ...) '(a ... ...))))
+  (flat (1 2) () (3)))"),
+               Ok(parse("(1 2 3)").unwrap()));
+    assert_eq!(both("(let-syntax ((one (syntax-rules () ((_) 1)))) (+ (one) (one)))"),
+               Ok(rint(2)));
+}
+
+#[test]
+fn test_syntax_rules_hygiene() {
+    // `tmp` in the template doesn't capture the user's `tmp`.
+    assert_eq!(both("(progn
+  (define-syntax my-or2
+    (syntax-rules ()
+      ((_ a b) (let ((tmp a)) (if tmp tmp b)))))
+  (let ((tmp 5)) (my-or2 #f tmp)))"),
+               Ok(rint(5)));
+    // A macro's own name is shadowed by a variable.
+    assert_eq!(both("(progn
+  (define-syntax one (syntax-rules () ((_) 1)))
+  ((lambda (one) (one)) (lambda () 2)))"),
+               Ok(rint(2)));
+    // Introduced bindings are renamed, and quoted identifiers are not.
+    let ast = expand(&mut Env::new(),
+                     &parse("(progn (define-syntax m (syntax-rules () ((_ v) (lambda (x) '(x v)))))
+                                    (m y))")
+                         .unwrap())
+        .unwrap();
+    match rcddar(&ast) {
+        Ok(Node::Cell(_, ref rest)) => {
+            assert!(rcar(rest).unwrap() != rlist(rsym("x"), rnil()));
+            assert_eq!(rcdar(rest), Ok(rquote(rlist(rsym("x"), rsym("y")))));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_syntax_rules_errors() {
+    assert_eq!(both("(progn (define-syntax m (syntax-rules () ((_ a) a))) (m 1 2))"),
+               eval_err(EvalError::InvalidSyntax("m".to_string())));
+    assert_eq!(both("(progn (define-syntax m (syntax-rules () ((_ a) a))) m)"),
+               eval_err(EvalError::MacroValue("m".to_string())));
+    assert_eq!(both("(define-syntax if (syntax-rules () ((_ a) a)))"),
+               eval_err(EvalError::SpecialFormRebind("if".to_string())));
+    assert_eq!(both("(define-syntax m (lambda (x) x))"),
+               eval_err(EvalError::InvalidSyntax("syntax-rules".to_string())));
+}
+
+#[test]
+fn test_macroexpand() {
+    let when = "(defmacro my-when (c &rest body) `(if ,c (progn ,@body) ()))";
+    let unless = "(define-syntax my-unless
+                    (syntax-rules () ((_ c e ...) (my-when (not c) e ...))))";
+    assert_eq!(both(&format!("(progn {} (macroexpand-1 '(my-when a (my-when b 1))))", when)),
+               Ok(parse("(if a (progn (my-when b 1)) ())").unwrap()));
+    assert_eq!(both(&format!("(progn {} {} (macroexpand-1 '(my-unless a 1)))", when, unless)),
+               Ok(parse("(my-when (not a) 1)").unwrap()));
+    assert_eq!(both(&format!("(progn {} {} (macroexpand '(my-unless a 1)))", when, unless)),
+               Ok(parse("(if (not a) (progn 1) ())").unwrap()));
+    assert_eq!(both(&format!("(progn {} (macroexpand-all '(f (my-when a (my-when b 1)))))",
+                             when)),
+               Ok(parse("(f (if a (progn (if b (progn 1) ())) ()))").unwrap()));
+    // What isn't a macro call is returned as it is.
+    assert_eq!(both("(macroexpand-1 '(+ 1 2))"), Ok(parse("(+ 1 2)").unwrap()));
+    assert_eq!(both("(macroexpand 1)"), Ok(rint(1)));
+    assert_eq!(both("(macroexpand-1)"), eval_err(EvalError::InvalidArgNumber));
+
+    // The same from Rust, seeing the macros an `Interpreter` has defined.
+    let session = &mut Interpreter::new();
+    session.eval(when).unwrap();
+    let form = parse("(my-when a (my-when b 1))").unwrap();
+    assert_eq!(Expander::new(&mut Env::new()).macroexpand_1(&form), Ok(None));
+    assert_eq!(Expander::new(session.env()).macroexpand_1(&form),
+               Ok(Some(parse("(if a (progn (my-when b 1)) ())").unwrap())));
+    assert_eq!(Expander::new(session.env()).macroexpand_all(&form),
+               Ok(parse("(if a (progn (if b (progn 1) ())) ())").unwrap()));
+    assert_eq!(session.eval("(macroexpand-all '(my-when a (my-when b 1)))"),
+               Ok(parse("(if a (progn (if b (progn 1) ())) ())").unwrap()));
+}
@@ -0,0 +1,37 @@
+extern crate rlisp;
+
+use rlisp::gc::*;
+use rlisp::runtime::*;
+use rlisp::node::*;
+
+#[test]
+fn test_collect_unreachable_cells() {
+    let depth = rlisp_gc_enter();
+    rlisp_cons(fixnum(1), NIL);
+    let mut root = rlisp_cons(fixnum(2), rlisp_cons(fixnum(3), NIL));
+    rlisp_gc_root(&mut root);
+
+    collect();
+    assert_eq!(live_objects(), 2);
+    assert_eq!(to_node(root), rlist(rint(2), rint(3)));
+
+    rlisp_gc_leave(depth);
+    collect();
+    assert_eq!(live_objects(), 0);
+}
+
+#[test]
+fn test_stress_mode_keeps_reachable_cells() {
+    set_stress(true);
+    let depth = rlisp_gc_enter();
+    let mut a = rlisp_cons(fixnum(1), NIL);
+    rlisp_gc_root(&mut a);
+    let b = rlisp_cons(fixnum(2), a);
+    let c = rlisp_cons(b, NIL);
+
+    assert_eq!(live_objects(), 3);
+    assert_eq!(to_node(c), rcell(rcell(rint(2), rcell(rint(1), rnil())), rnil()));
+
+    rlisp_gc_leave(depth);
+    set_stress(false);
+}
@@ -1,3 +1,5 @@
+#![cfg(feature = "llvm")]
+
 extern crate rlisp;
 
 use rlisp::run;
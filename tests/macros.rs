@@ -0,0 +1,52 @@
+extern crate rlisp;
+
+mod common;
+
+use rlisp::node::*;
+use rlisp::parser::parse;
+use rlisp::error::EvalError;
+use common::{both, eval_err};
+
+#[test]
+fn test_quasiquote() {
+    assert_eq!(both("`(1 2)"), Ok(parse("(1 2)").unwrap()));
+    assert_eq!(both("(let ((x 2)) `(1 ,x ,(+ x 1)))"), Ok(parse("(1 2 3)").unwrap()));
+    assert_eq!(both("(let ((x '(2 3))) `(1 ,@x 4 ,@x))"), Ok(parse("(1 2 3 4 2 3)").unwrap()));
+    assert_eq!(both("`(1 ,@'())"), Ok(parse("(1)").unwrap()));
+    assert_eq!(both("((lambda (x) `(a (b ,x) 'c)) 1)"), Ok(parse("(a (b 1) 'c)").unwrap()));
+    // Only the outermost level is evaluated in nested templates.
+    assert_eq!(both("(let ((x 1)) `(a `(b ,(c ,x))))"),
+               Ok(parse("(a `(b ,(c 1)))").unwrap()));
+    assert_eq!(both("`(1 ,@2)"), eval_err(EvalError::WrongTypeArg));
+    assert_eq!(both("`,@'(1)"), eval_err(EvalError::WrongTypeArg));
+}
+
+#[test]
+fn test_defmacro() {
+    assert_eq!(both("(progn
+  (defmacro my-if (c a b) `(if ,c ,a ,b))
+  (my-if #f (car 1) 2))"),
+               Ok(rint(2)));
+    assert_eq!(both("(progn
+  (defmacro my-when (c &rest body) `(if ,c (progn ,@body) '()))
+  (let ((x 1))
+    (cons (my-when (= x 1) 10 20) (my-when (= x 2) (car 1)))))"),
+               Ok(rcell(rint(20), rnil())));
+    // Expansions are evaluated where the macro is called.
+    assert_eq!(both("(progn
+  (defmacro swap-args (f a b) `(,f ,b ,a))
+  ((lambda (x y) (swap-args - x y)) 1 10))"),
+               Ok(rint(9)));
+    assert_eq!(both("(progn (defmacro m () 1) m)"), eval_err(EvalError::MacroValue("m".to_string())));
+    assert_eq!(both("(defmacro if (x) x)"), eval_err(EvalError::SpecialFormRebind("if".to_string())));
+    assert_eq!(both("(progn (defmacro m (x) x) (m))"), eval_err(EvalError::InvalidArgNumber));
+}
+
+#[test]
+fn test_macro_defines_in_frame() {
+    // What the expansion defines is seen by the rest of the lambda.
+    assert_eq!(both("(progn
+  (defmacro def (name v) `(define ,name ,v))
+  ((lambda (x) (progn (def y (+ x 1)) (+ x y))) 1))"),
+               Ok(rint(3)));
+}
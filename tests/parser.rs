@@ -2,6 +2,7 @@ extern crate rlisp;
 
 use rlisp::parser::parse;
 use rlisp::node::*;
+use rlisp::error::ParseError;
 
 #[test]
 fn test_read_nil() {
@@ -13,6 +14,11 @@ fn test_read_int() {
     assert_eq!(parse("1"), Ok(rint(1)));
     assert_eq!(parse("100"), Ok(rint(100)));
     assert_eq!(parse("1000"), Ok(rint(1000)));
+    assert_eq!(parse("-6"), Ok(rint(-6)));
+    assert_eq!(parse("(- 6)"), Ok(rcell(rsym("-"), rcell(rint(6), rnil()))));
+    assert_eq!(parse("-2147483648"), Ok(rint(i32::min_value())));
+    assert_eq!(parse("2147483648"), Err(ParseError::OutOfRange(10)));
+    assert_eq!(parse("(+ 1 -99999999999)"), Err(ParseError::OutOfRange(17)));
 }
 
 #[test]
@@ -58,6 +64,7 @@ fn test_read_expr() {
 #[test]
 fn test_read_symbol() {
     assert_eq!(parse("(inc 1)"), Ok(rlist(rsym("inc"), rint(1))));
+    assert_eq!(parse("(null? x)"), Ok(rlist(rsym("null?"), rsym("x"))));
 }
 
 #[test]
@@ -107,3 +114,14 @@ fn test_read_let() {
                         rlist(rlist(rlist(rsym("a"), rint(10)), rlist(rsym("b"), rint(11))),
                               rcell(rsym("-"), rlist(rsym("a"), rsym("b")))))));
 }
+
+#[test]
+fn test_read_quasiquote() {
+    assert_eq!(parse("`a"), Ok(rlist(rsym("quasiquote"), rsym("a"))));
+    assert_eq!(parse("`(a ,b ,@c)"),
+               Ok(rlist(rsym("quasiquote"),
+                        rcell(rsym("a"),
+                              rlist(rlist(rsym("unquote"), rsym("b")),
+                                    rlist(rsym("unquote-splicing"), rsym("c")))))));
+    assert_eq!(parse("(&rest body)"), Ok(rlist(rsym("&rest"), rsym("body"))));
+}
@@ -0,0 +1,23 @@
+extern crate rlisp;
+
+use rlisp::node::*;
+use rlisp::parser::parse;
+use rlisp::printer::pretty;
+
+#[test]
+fn test_pretty() {
+    assert_eq!(pretty(&rint(-1)), "-1");
+    assert_eq!(pretty(&rnil()), "()");
+    assert_eq!(pretty(&rcell(rtrue(), rfalse())), "(#t . #f)");
+    assert_eq!(pretty(&parse("(a 'b `(c ,d ,@e) (1 2 . 3))").unwrap()),
+               "(a 'b `(c ,d ,@e) (1 2 . 3))");
+    // Lists longer than a line are broken one element per line.
+    let long = concat!("(define f (lambda (xxxxxxxxxx yyyyyyyyyy)",
+                       " (if (= xxxxxxxxxx yyyyyyyyyy) 'same 'different)))");
+    assert_eq!(pretty(&parse(long).unwrap()),
+               "(define
+  f
+  (lambda
+    (xxxxxxxxxx yyyyyyyyyy)
+    (if (= xxxxxxxxxx yyyyyyyyyy) 'same 'different)))");
+}
@@ -0,0 +1,71 @@
+extern crate rlisp;
+
+use rlisp::interpret;
+use rlisp::resolver::resolve;
+use rlisp::env::Env;
+use rlisp::node::*;
+use rlisp::parser::parse;
+use rlisp::error::{EvalError, RLispError};
+
+fn resolved(input: &str) -> Node {
+    resolve(&Env::new(), &parse(input).unwrap())
+}
+
+#[test]
+fn test_resolve_locals() {
+    let env: Env<Node> = Env::new();
+    // (lambda (x y) (+ x y))
+    let ast = resolve(&env, &parse("(lambda (x y) (+ x y))").unwrap());
+    assert_eq!(ast,
+               rcell(Node::Global(env.global_slot("lambda")),
+                     rlist(rlist(rsym("x"), rsym("y")),
+                           rcell(Node::Global(env.global_slot("+")),
+                                 rlist(Node::Local(0, 0), Node::Local(0, 1))))));
+
+    // Enclosing frames are counted outwards.
+    match resolved("(let ((a 1)) (lambda (b) a))") {
+        Node::Cell(_, ref rest) => {
+            let lambda = rcdar(rest).unwrap();
+            assert_eq!(rcddar(&lambda), Ok(Node::Local(1, 0)));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_resolve_leaves_quote_and_defines() {
+    match resolved("(lambda (x) '(x y))") {
+        Node::Cell(_, ref rest) => {
+            assert_eq!(rcdar(rest), Ok(rquote(rlist(rsym("x"), rsym("y")))));
+        }
+        _ => panic!(),
+    }
+    // `y` is looked up by name once a `define` has added it to the frame.
+    match resolved("(lambda (x) y (define y 1) y)") {
+        Node::Cell(_, ref rest) => {
+            let body = rcdr(rest).unwrap();
+            assert!(match rcar(&body) {
+                Ok(Node::Global(_)) => true,
+                _ => false,
+            });
+            assert_eq!(rcddar(&body), Ok(rsym("y")));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_resolved_programs_evaluate() {
+    assert_eq!(interpret("((lambda (x x) x) 1 2)"), Ok(rint(2)));
+    assert_eq!(interpret("((lambda (x) (progn (define x 5) x)) 1)"), Ok(rint(5)));
+    assert_eq!(interpret("(let ((x 1)) ((lambda (y) (progn (define x 2) (+ x y))) 10))"),
+               Ok(rint(12)));
+    assert_eq!(interpret("(let ((x 1)) (progn ((lambda () (define x 2))) x))"), Ok(rint(1)));
+    assert_eq!(interpret("((lambda (if) (if 1)) (lambda (x) (+ x 1)))"), Ok(rint(2)));
+    assert_eq!(interpret("((if #t if +) 1 2)"),
+               Err(RLispError::EvalError(EvalError::SpecialFormValue("if".to_string()))));
+    assert_eq!(interpret("(progn (define define 1) define)"),
+               Err(RLispError::EvalError(EvalError::SpecialFormRebind("define".to_string()))));
+    // Lambdas see globals defined after them.
+    assert_eq!(interpret("(progn (define f (lambda () x)) (define x 1) (f))"), Ok(rint(1)));
+}
@@ -0,0 +1,51 @@
+extern crate rlisp;
+
+use std::env;
+use std::process::Command;
+use rlisp::runtime::*;
+use rlisp::node::*;
+
+#[test]
+fn test_fixnum() {
+    assert_eq!(tag(fixnum(10)), TAG_FIXNUM);
+    assert_eq!(to_node(fixnum(10)), rint(10));
+    assert_eq!(to_node(fixnum(-10)), rint(-10));
+    assert_eq!(to_node(fixnum(i32::min_value())), rint(i32::min_value()));
+}
+
+#[test]
+fn test_immediates() {
+    assert_eq!(to_node(NIL), rnil());
+    assert_eq!(to_node(TRUE), rtrue());
+    assert_eq!(to_node(FALSE), rfalse());
+}
+
+#[test]
+fn test_cons_car_cdr() {
+    let v = rlisp_cons(fixnum(1), rlisp_cons(fixnum(2), NIL));
+    assert_eq!(tag(v), TAG_CELL);
+    assert_eq!(rlisp_car(v), fixnum(1));
+    assert_eq!(rlisp_car(rlisp_cdr(v)), fixnum(2));
+    assert_eq!(rlisp_is_null(rlisp_cdr(rlisp_cdr(v))), TRUE);
+    assert_eq!(rlisp_is_null(v), FALSE);
+    assert_eq!(to_node(v), rlist(rint(1), rint(2)));
+}
+
+// A type error aborts, so the test runs itself again in a child process to
+// see it happen.
+#[test]
+fn test_type_error() {
+    if env::var("RLISP_TEST_TYPE_ERROR").is_ok() {
+        rlisp_cdr(rlisp_car(rlisp_cons(fixnum(7), NIL)));
+        return;
+    }
+    let output = Command::new(env::current_exe().unwrap())
+        .args(&["--exact", "test_type_error", "--nocapture"])
+        .env("RLISP_TEST_TYPE_ERROR", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rlisp: wrong type argument to cdr: 7"), "{}", stderr);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("wrong type argument"));
+}
@@ -0,0 +1,24 @@
+extern crate rlisp;
+
+use rlisp::symbol::Symbol;
+use rlisp::node::*;
+use rlisp::parser::parse;
+
+#[test]
+fn test_intern_returns_same_symbol() {
+    let a = Symbol::intern("abc");
+    assert_eq!(a, Symbol::intern(&"abc".to_string()));
+    assert!(a != Symbol::intern("abd"));
+    assert_eq!(a.as_str(), "abc");
+    assert!(a == "abc");
+}
+
+#[test]
+fn test_symbol_nodes() {
+    assert_eq!(parse("foo").unwrap(), rsym("foo"));
+    assert_eq!(rsym("foo"), rsym("foo".to_string()));
+    assert_eq!(sym_to_str(&rsym("foo")), Ok("foo"));
+    // Printed the same as before symbols were interned.
+    assert_eq!(format!("{:?}", rsym("foo")), "Sym(\"foo\")");
+    assert_eq!(format!("{}", Symbol::intern("foo")), "foo");
+}